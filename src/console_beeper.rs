@@ -0,0 +1,174 @@
+// Linux console beeper backend (--output=console): the KIOCSOUND and
+// KDMKTONE ioctls on a virtual console (/dev/console or a /dev/ttyN),
+// which drive the PC speaker directly on machines without the pcspkr input
+// device. Like linux_pcspkr it plays the melody rendered by the mml module
+// through the tone module; only the way a tone is sounded differs.
+//
+// Both ioctls take a PIT divisor (PIT_FREQ / frequency) rather than a
+// frequency. KDMKTONE also takes a duration in milliseconds, after which
+// the kernel silences the speaker itself, so each note is sounded with it
+// for its own length plus HOLD_MARGIN: the next note or rest normally
+// replaces it first, and if spkrd dies mid-note the speaker still stops.
+// A note too long for KDMKTONE's 16-bit duration falls back to KIOCSOUND,
+// which sounds until told otherwise.
+//
+// The ioctls need the console open for writing and, on most systems,
+// CAP_SYS_TTY_CONFIG or the console being the caller's controlling
+// terminal; an EPERM is reported as a device error. The console is opened
+// with O_NOCTTY so a daemonised spkrd does not acquire it as controlling
+// terminal. Outside Linux every ioctl fails as unsupported.
+
+use crate::error::SpeakerError;
+use crate::queue::{PlayRequest, Ticket};
+use crate::tone::{self, Beeper};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::time::Duration;
+
+pub const DEFAULT_DEVICE: &str = "/dev/console";
+
+// <linux/kd.h>
+const KIOCSOUND: u64 = 0x4B2F;
+const KDMKTONE: u64 = 0x4B30;
+// Keyboard type query; answers on any virtual console and makes no sound.
+const KDGKBTYPE: u64 = 0x4B33;
+
+// The PC's programmable interval timer, as the kernel counts it.
+const PIT_FREQ: u32 = 1_193_182;
+
+const HOLD_MARGIN: Duration = Duration::from_millis(50);
+
+struct ConsoleBeeper {
+    file: File,
+}
+
+impl ConsoleBeeper {
+    fn open(device_path: &str) -> Result<Self, SpeakerError> {
+        let mut options = OpenOptions::new();
+        options.write(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.custom_flags(libc::O_NOCTTY);
+        }
+        Ok(Self { file: options.open(device_path)? })
+    }
+
+    fn ioctl(&self, request: u64, arg: u32) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+            // SAFETY: the console ioctls used here take their argument by
+            // value and write nothing back.
+            let rc = unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, arg as libc::c_ulong) };
+            if rc < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (request, arg);
+            Err(unsupported())
+        }
+    }
+
+    // KDGKBTYPE, which fails with ENOTTY unless the device is a console.
+    fn check_console(&self) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+            let mut kb_type: libc::c_char = 0;
+            // SAFETY: KDGKBTYPE writes a single char through the pointer.
+            let rc = unsafe { libc::ioctl(self.file.as_raw_fd(), KDGKBTYPE as _, &mut kb_type) };
+            if rc < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            Err(unsupported())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "console beeper ioctls need Linux")
+}
+
+impl Beeper for ConsoleBeeper {
+    fn set_tone(&mut self, freq_hz: u32, hold: Duration) -> io::Result<()> {
+        match kdmktone_arg(freq_hz, hold) {
+            Some(arg) => self.ioctl(KDMKTONE, arg),
+            None => self.ioctl(KIOCSOUND, pit_divisor(freq_hz)),
+        }
+    }
+}
+
+// The KIOCSOUND argument: 0 for silence, else the PIT divisor, kept to
+// the 16 bits the timer has.
+fn pit_divisor(freq_hz: u32) -> u32 {
+    match freq_hz {
+        0 => 0,
+        f => (PIT_FREQ / f).clamp(1, 0xFFFF),
+    }
+}
+
+// The KDMKTONE argument (duration in ms above the divisor), or None when
+// the tone is silence or too long to express.
+fn kdmktone_arg(freq_hz: u32, hold: Duration) -> Option<u32> {
+    if freq_hz == 0 {
+        return None;
+    }
+    let ms = u32::try_from((hold + HOLD_MARGIN).as_millis()).ok()?;
+    (ms <= 0xFFFF).then(|| ms << 16 | pit_divisor(freq_hz))
+}
+
+pub async fn play_melody(
+    req: &PlayRequest<'_>,
+    ticket: Ticket,
+    device_path: &str,
+) -> Result<u32, SpeakerError> {
+    let device_path = device_path.to_owned();
+    tone::play_melody(req, ticket, move || ConsoleBeeper::open(&device_path)).await
+}
+
+// Readiness probe: can the device be opened, and is it a console? Asks
+// for the keyboard type rather than sounding anything.
+pub fn probe(device_path: &str) -> Result<(), SpeakerError> {
+    match ConsoleBeeper::open(device_path) {
+        Ok(beeper) => Ok(beeper.check_console()?),
+        Err(SpeakerError::DeviceBusy) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tones_are_encoded_for_the_pit() {
+        assert_eq!(pit_divisor(0), 0);
+        assert_eq!(pit_divisor(440), 2711);
+        assert_eq!(pit_divisor(1), 0xFFFF);
+        assert_eq!(pit_divisor(u32::MAX), 1);
+
+        let arg = kdmktone_arg(440, Duration::from_millis(250)).unwrap();
+        assert_eq!(arg >> 16, 300);
+        assert_eq!(arg & 0xFFFF, 2711);
+        assert_eq!(kdmktone_arg(0, Duration::from_millis(250)), None);
+        assert_eq!(kdmktone_arg(440, Duration::from_secs(70)), None);
+    }
+
+    #[test]
+    fn a_regular_file_is_not_a_console() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap();
+        assert!(probe(path).is_err());
+        let mut beeper = ConsoleBeeper::open(path).unwrap();
+        assert!(beeper.set_tone(440, Duration::from_millis(10)).is_err());
+    }
+}
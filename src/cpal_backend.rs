@@ -1,16 +1,17 @@
 // CPAL audio output backend. Renders an MML melody to PCM via the mml module,
-// synthesises the chosen waveform at the device's configured sample rate, and
-// plays it through cpal's default (or selected) host/device. A global mutex
-// enforces one-melody-at-a-time semantics matching FreeBSD spkr.c's exclusive
-// sx lock; busy callers retry on the same schedule as the freebsd-speaker
-// backend until --retry-timeout elapses.
+// synthesises the chosen waveform (see the synth module) at the device's
+// configured sample rate, and plays it through cpal's default (or selected)
+// host/device. Requests take turns through the server's play queue, giving
+// one-melody-at-a-time semantics matching FreeBSD spkr.c's exclusive sx
+// lock; busy callers wait in FIFO order for up to --retry-timeout.
 //
-// The lock is held *inside* the spawn_blocking task that owns the live
+// The queue slot is held *inside* the spawn_blocking task that owns the live
 // cpal::Stream — not in the async parent — so that an HTTP-client disconnect
-// (which drops the parent future) cannot release the lock while audio is
-// still playing in CPAL's audio thread. An abort flag installed by the parent
-// is observed by the audio callback, mirroring FreeBSD spkr.c's PCATCH-aware
-// tsleep that lets a signal interrupt playback mid-string.
+// (which drops the parent future) cannot hand the device to the next request
+// while audio is still playing in CPAL's audio thread. An abort flag
+// installed by the parent (and raised either by its drop or by POST /stop)
+// is observed by the audio callback, mirroring FreeBSD spkr.c's
+// PCATCH-aware tsleep that lets a signal interrupt playback mid-string.
 //
 // PA-disconnect recovery: the cpal::Device and its underlying audio
 // host client outlive a single request, but the host's reactor can
@@ -21,9 +22,10 @@
 // build_device_state when build_output_stream / stream.play() / the
 // stream error callback report a disconnect-shaped ErrorKind
 // (StreamInvalidated, DeviceNotAvailable, HostUnavailable). Rebuild
-// attempts share the request's --retry-timeout window on the same
-// 1s cadence as the busy-device retry. The original CpalConfig is
-// retained so rebuild can re-run host/device selection identically.
+// attempts share the request's --retry-timeout window (which also
+// covered its wait in the play queue) on a 1s cadence. The original
+// CpalConfig is retained so rebuild can re-run host/device selection
+// identically.
 //
 // Stream error classification (classify_error): cpal's stream error
 // callback can fire for both fatal and non-fatal conditions. We
@@ -37,7 +39,7 @@
 //   * Disconnect — StreamInvalidated, DeviceNotAvailable,
 //     HostUnavailable. The PA host maps Disconnected/Io errors to
 //     StreamInvalidated; PipeWire maps host death likewise. Surfaces
-//     as SpeakerError::CpalDisconnect, which acquire_and_play
+//     as SpeakerError::CpalDisconnect, which play_with_reconnect
 //     retries via rebuild_device.
 //
 //   * Fatal — everything else (UnsupportedConfig, PermissionDenied,
@@ -51,11 +53,16 @@
 // condvar, which dropped the stream before audio finished playing.
 
 use crate::error::SpeakerError;
-use crate::mml::{self, Event};
+use crate::metrics::METRICS;
+use crate::mml::{Event, Timing};
+use crate::queue::{AbortOnDrop, PlayRequest, Ticket};
+use crate::speaker_profile::SpeakerProfile;
+use crate::synth::{mix, Envelope, Sound, Waveform, Wavetable};
+use crate::tuning::Tuning;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{BufferSize, ErrorKind, FromSample, SampleFormat, SizedSample, StreamConfig};
 use log::{debug, info, warn};
-use std::f32::consts::PI;
+use serde::Serialize;
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
@@ -63,68 +70,30 @@ use std::time::{Duration, Instant};
 
 const RETRY_INTERVAL: Duration = Duration::from_secs(1);
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Waveform {
-    Square,
-    SquareBandlimited,
-    Sine,
-    Triangle,
-    Sawtooth,
-    // Modern piezoelectric PC-speaker simulation: square wave generated at a
-    // PIT-quantised frequency, processed through a 3-stage biquad chain
-    // (HP/peak/LP) tuned to a small piezo disc, then soft-clipped via tanh.
-    // Filter state persists across the entire event sequence so rests ring
-    // out naturally instead of cutting off abruptly.
-    PcSpeaker,
-}
-
-impl std::str::FromStr for Waveform {
-    type Err = String;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_ascii_lowercase().as_str() {
-            "square" => Ok(Waveform::Square),
-            "square-bandlimited" | "squarebandlimited" | "bl-square" => {
-                Ok(Waveform::SquareBandlimited)
-            }
-            "sine" => Ok(Waveform::Sine),
-            "triangle" => Ok(Waveform::Triangle),
-            "sawtooth" | "saw" => Ok(Waveform::Sawtooth),
-            "pc-speaker" | "pcspeaker" | "pc" => Ok(Waveform::PcSpeaker),
-            other => Err(format!("unknown waveform: {}", other)),
-        }
-    }
-}
-
-// Attack/release envelope length applied to the non-PC-speaker waveforms to
-// suppress amplitude-step clicks at note boundaries. 5 ms is short enough to
-// be inaudible as a fade and long enough to push the boundary transient
-// below the audible click threshold.
-const ENVELOPE_MS: f32 = 5.0;
-
-// Intel 8254 PIT clock — used by real PC-speaker hardware. Note frequencies
-// in the simulation are quantised to PIT_FREQ / divisor for an integer
-// divisor, matching what the kernel driver actually programs.
-const PIT_FREQ: u32 = 1_193_182;
-
-// Modern piezo PC-speaker preset: steep HP to kill sub-bass the disc can't
-// move, peaking boost in the resonant midrange for the buzzy character, LP
-// for cone roll-off, and a tanh saturator for the driver-clip edge.
-const PIEZO_HP_HZ: f32 = 800.0;
-const PIEZO_HP_Q: f32 = 0.707;
-const PIEZO_PEAK_HZ: f32 = 3000.0;
-const PIEZO_PEAK_Q: f32 = 3.0;
-const PIEZO_PEAK_DB: f32 = 9.0;
-const PIEZO_LP_HZ: f32 = 6000.0;
-const PIEZO_LP_Q: f32 = 0.707;
-const PIEZO_DRIVE: f32 = 2.0;
-
-#[derive(Clone)]
+#[derive(Clone, Serialize)]
 pub struct CpalConfig {
     pub host: Option<String>,
     pub device: Option<String>,
     pub sample_rate: Option<u32>,
     pub volume: f32,
     pub waveform: Waveform,
+    pub envelope: Option<Envelope>,
+    pub wavetable: Option<Wavetable>,
+    pub speaker: SpeakerProfile,
+    pub tuning: Tuning,
+    pub timing: Timing,
+}
+
+impl CpalConfig {
+    pub fn sound(&self) -> Sound {
+        Sound {
+            waveform: self.waveform,
+            volume: self.volume,
+            envelope: self.envelope,
+            wavetable: self.wavetable.clone(),
+            speaker: self.speaker.clone(),
+        }
+    }
 }
 
 // Trio of state replaced together when the PA client dies and we need to
@@ -136,97 +105,168 @@ struct DeviceState {
     sample_format: SampleFormat,
 }
 
+// The live DeviceState as reported by GET /status.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceStatus {
+    pub device: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub sample_format: String,
+    pub buffer_size: String,
+}
+
+impl DeviceStatus {
+    fn of(state: &DeviceState) -> Self {
+        Self {
+            device: device_name(state),
+            sample_rate: state.config.sample_rate,
+            channels: state.config.channels,
+            sample_format: format!("{:?}", state.sample_format),
+            buffer_size: format!("{:?}", state.config.buffer_size),
+        }
+    }
+}
+
+// A melody synthesised at one sample rate, kept with what it was
+// synthesised from so a rebuild at another rate can redo it.
+struct Rendering {
+    voices: Vec<Vec<Event>>,
+    sound: Sound,
+    sample_rate: u32,
+    buffer: Vec<f32>,
+}
+
+impl Rendering {
+    fn new(voices: Vec<Vec<Event>>, sound: Sound, sample_rate: u32) -> Self {
+        let buffer = mix(&voices, sample_rate, &sound);
+        Self {
+            voices,
+            sound,
+            sample_rate,
+            buffer,
+        }
+    }
+}
+
 pub struct CpalBackend {
-    // Held by the spawn_blocking task that owns the live cpal::Stream, not by
-    // the async parent. See module-level comment on cancellation safety.
-    play_lock: Mutex<()>,
     // Rebuilt in-place by rebuild_device on PA-disconnect-shaped errors.
-    // Uncontended in practice: play_lock already serializes plays, and
-    // rebuild_device only runs from inside the same play_lock-guarded
-    // section that the prior failed play_buffer ran in.
+    // play_buffer holds this lock for the whole of a melody, so it is only
+    // ever taken on blocking threads, by whoever holds the play queue slot
+    // (or, for probe, keeps playback out): never by an async task, which
+    // would stall a runtime worker until the melody ends.
     state: Mutex<DeviceState>,
+    // What GET /status reports about `state`, kept under its own lock so a
+    // status request does not wait for the melody playing to end. Updated
+    // alongside `state` by new and rebuild_device.
+    status: Mutex<DeviceStatus>,
     // Retained so rebuild_device can re-run host/device selection with the
     // same user-supplied config. Immutable for the lifetime of the backend.
     cfg: CpalConfig,
 }
 
-// Sets the inner abort flag on drop. Installed in the async parent so that
-// when axum drops the request handler future (e.g. on client disconnect),
-// the in-progress audio playback is signalled to stop early.
-struct AbortOnDrop(Arc<AtomicBool>);
-
-impl Drop for AbortOnDrop {
-    fn drop(&mut self) {
-        self.0.store(true, Ordering::SeqCst);
-    }
-}
-
 impl CpalBackend {
     pub fn new(cfg: &CpalConfig) -> Result<Self, SpeakerError> {
         let state = build_device_state(cfg)?;
         log_device_state(&state, cfg);
         Ok(Self {
-            play_lock: Mutex::new(()),
+            status: Mutex::new(DeviceStatus::of(&state)),
             state: Mutex::new(state),
             cfg: cfg.clone(),
         })
     }
 
     // Replace the cached DeviceState with a freshly-constructed one. Called
-    // from acquire_and_play after build_output_stream returns a
+    // from play_with_reconnect after build_output_stream returns a
     // disconnect-shaped error. Each call goes through cpal::default_host()
     // / host_from_id, both of which construct a new Host (and therefore a
     // new pulseaudio::Client), so a stale PA reactor is replaced rather
     // than reused.
     fn rebuild_device(&self) -> Result<(), SpeakerError> {
-        let new_state = build_device_state(&self.cfg)?;
+        let new_state = build_device_state(&self.cfg).inspect_err(|_| METRICS.cpal_rebuild(false))?;
+        METRICS.cpal_rebuild(true);
         log_device_state(&new_state, &self.cfg);
+        let status = DeviceStatus::of(&new_state);
         *self.state.lock().unwrap() = new_state;
+        *self.status.lock().unwrap() = status;
         Ok(())
     }
 
+    pub fn config(&self) -> &CpalConfig {
+        &self.cfg
+    }
+
+    // Snapshot of the device currently in use, which may have been
+    // rebuilt since startup.
+    pub fn device_status(&self) -> DeviceStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    // Readiness probe: build (without playing) an output stream on the
+    // cached device. With `rebuild`, a failure triggers rebuild_device and
+    // one more attempt, so a dead PA client is replaced before the next
+    // request finds it. Blocking; the caller keeps playback out meanwhile.
+    pub fn probe(&self, rebuild: bool) -> Result<(), SpeakerError> {
+        let result = probe_stream(&self.state.lock().unwrap());
+        match result {
+            Err(e) if rebuild => {
+                warn!("CPAL readiness probe failed ({}); rebuilding device", e);
+                self.rebuild_device()?;
+                probe_stream(&self.state.lock().unwrap())
+            }
+            result => result,
+        }
+    }
+
     pub async fn play_melody(
         self: &Arc<Self>,
-        melody: &str,
-        client_addr: SocketAddr,
-        retry_timeout: Duration,
-        max_melody_length: usize,
-        debug: bool,
+        req: &PlayRequest<'_>,
+        ticket: Ticket,
     ) -> Result<u32, SpeakerError> {
-        validate_melody(melody, max_melody_length)?;
-        if debug {
-            log_request(client_addr, melody);
+        if req.debug {
+            log_request(req.client_addr, req.melody);
         }
+        let start = Instant::now();
 
-        // Synthesis is pure CPU work — render in the async parent. (Even a
-        // 1 MiB melody is well under a millisecond at typical sample rates.)
-        // The buffer is rendered at the *current* device sample rate; if a
-        // mid-request rebuild brings up a different rate (rare — same sink,
-        // fresh PA client), acquire_and_play re-renders.
-        let events = mml::render(melody);
-        let initial_sr = self.state.lock().unwrap().config.sample_rate;
-        let buffer = synth(&events, initial_sr, self.cfg.waveform, self.cfg.volume);
-
-        if buffer.is_empty() {
+        // Interpreting the melody is cheap and needs only the request, so it
+        // happens in the async parent. Synthesis needs the device's sample
+        // rate, which means the device lock, so it waits for the blocking
+        // task: the buffer is rendered at the rate current when our turn
+        // comes, and if a mid-request rebuild brings up a different rate
+        // (rare — same sink, fresh PA client), play_with_reconnect
+        // re-renders.
+        let voices = req.dialect.render_voices_with(req.melody, &self.cfg.tuning, self.cfg.timing);
+        let sound = req.sound.apply(self.cfg.sound());
+        if voices.iter().all(Vec::is_empty) {
             return Ok(0);
         }
 
         // Cancellation channel: setting `abort` makes the audio callback stop
         // emitting samples and signal end-of-stream. The guard installs a
         // Drop hook on the async parent — when axum drops this future on
-        // client disconnect, the flag is set, the cpal callback observes it,
-        // and the blocking task ends and releases play_lock.
+        // client disconnect (or a job is cancelled), the flag is set, the
+        // cpal callback observes it, and the blocking task ends and releases
+        // its queue slot.
         let abort = Arc::new(AtomicBool::new(false));
         let _abort_on_drop = AbortOnDrop(Arc::clone(&abort));
 
-        // Move the lock acquisition + audio playback into the blocking task.
-        // The lock is held by this task (synchronously) for the entire
-        // duration of the audio, so the next request cannot start a parallel
-        // stream even if our parent future is dropped before we return.
+        // Wait for our turn, then move the slot into the blocking task along
+        // with the audio playback. The slot is held by that task for the
+        // entire duration of the audio, so the next request cannot start a
+        // parallel stream even if our parent future is dropped before we
+        // return. Dropping the parent while still queued just leaves the
+        // queue.
+        let slot = ticket.wait(req, &abort).await?;
         let backend = Arc::clone(self);
         let task_abort = Arc::clone(&abort);
+        let retry_timeout = req.retry_timeout;
         let join = tokio::task::spawn_blocking(move || {
-            backend.acquire_and_play(events, buffer, initial_sr, retry_timeout, task_abort)
+            let _slot = slot;
+            let sample_rate = backend.state.lock().unwrap().config.sample_rate;
+            let rendering = Rendering::new(voices, sound, sample_rate);
+            if rendering.buffer.is_empty() {
+                return Ok(0);
+            }
+            backend.play_with_reconnect(rendering, start, retry_timeout, task_abort)
         });
 
         match join.await {
@@ -235,51 +275,33 @@ impl CpalBackend {
         }
     }
 
-    // Synchronous: acquire play_lock with retry-poll, then play. Runs on a
-    // tokio blocking thread. Holds the lock for the entire audio duration.
+    // Synchronous: play, retrying on disconnect. Runs on a tokio blocking
+    // thread whose caller holds the play queue slot for the entire audio
+    // duration.
     //
-    // After the lock is acquired, the play attempt itself is retried on
-    // disconnect-shaped CPAL errors: rebuild_device replaces the cached
-    // PulseAudio client (which can die on suspend/resume or pipewire-pulse
-    // restart) and we try again on the same 1s cadence. The total wait —
-    // lock-acquire + reconnect retries — is bounded by --retry-timeout.
-    fn acquire_and_play(
+    // The play attempt is retried on disconnect-shaped CPAL errors:
+    // rebuild_device replaces the cached PulseAudio client (which can die on
+    // suspend/resume or pipewire-pulse restart) and we try again on a 1s
+    // cadence. Other errors fail fast. The total wait — time spent in the
+    // queue (measured from `start`) plus reconnect retries — is bounded by
+    // --retry-timeout.
+    fn play_with_reconnect(
         &self,
-        events: Vec<Event>,
-        initial_buffer: Vec<f32>,
-        initial_sr: u32,
+        mut rendering: Rendering,
+        start: Instant,
         retry_timeout: Duration,
         abort: Arc<AtomicBool>,
     ) -> Result<u32, SpeakerError> {
-        let start = Instant::now();
         let mut retries: u32 = 0;
-        let _guard = loop {
-            // If the client already disconnected before we even got to
-            // acquire the lock, drop out cleanly without playing anything.
-            if abort.load(Ordering::SeqCst) {
-                return Ok(retries);
-            }
-            match self.play_lock.try_lock() {
-                Ok(g) => break g,
-                Err(_) => {
-                    if start.elapsed() >= retry_timeout {
-                        return Err(SpeakerError::Timeout);
-                    }
-                    retries += 1;
-                    std::thread::sleep(RETRY_INTERVAL);
-                }
-            }
-        };
-
-        // Lock held — play, retrying once-per-second on disconnect-shaped
-        // errors after rebuilding the cpal Device. Other errors fail fast.
-        let mut buffer = initial_buffer;
-        let mut buffer_sr = initial_sr;
         loop {
+            // The client may have disconnected (or POST /stop been called)
+            // while we were queued or between retries; drop out cleanly
+            // without playing anything.
             if abort.load(Ordering::SeqCst) {
-                return Ok(retries);
+                return Err(SpeakerError::Interrupted);
             }
-            match self.play_buffer(&buffer, Arc::clone(&abort)) {
+            match self.play_buffer(&rendering.buffer, Arc::clone(&abort)) {
+                Ok(()) if abort.load(Ordering::SeqCst) => return Err(SpeakerError::Interrupted),
                 Ok(()) => return Ok(retries),
                 Err(SpeakerError::CpalDisconnect(msg)) => {
                     if start.elapsed() >= retry_timeout {
@@ -294,18 +316,12 @@ impl CpalBackend {
                             // preserved. Same-sink reconnects normally
                             // keep the rate, so this is a rare path.
                             let new_sr = self.state.lock().unwrap().config.sample_rate;
-                            if new_sr != buffer_sr {
+                            if new_sr != rendering.sample_rate {
                                 warn!(
                                     "CPAL sample rate changed across rebuild ({} -> {}); re-rendering",
-                                    buffer_sr, new_sr
+                                    rendering.sample_rate, new_sr
                                 );
-                                buffer = synth(
-                                    &events,
-                                    new_sr,
-                                    self.cfg.waveform,
-                                    self.cfg.volume,
-                                );
-                                buffer_sr = new_sr;
+                                rendering = Rendering::new(rendering.voices, rendering.sound, new_sr);
                             }
                         }
                         Err(e) => warn!("CPAL device rebuild failed: {}; will retry", e),
@@ -401,6 +417,7 @@ impl CpalBackend {
                 },
                 move |err| {
                     let class = classify_error(&err);
+                    METRICS.cpal_stream_error(class.label());
                     match class {
                         // Documented as "playback continues" — log and let
                         // the data callback drive completion as usual.
@@ -546,14 +563,50 @@ fn build_device_state(cfg: &CpalConfig) -> Result<DeviceState, SpeakerError> {
     })
 }
 
+fn probe_stream(state: &DeviceState) -> Result<(), SpeakerError> {
+    match state.sample_format {
+        SampleFormat::F32 => probe_stream_as::<f32>(state),
+        SampleFormat::F64 => probe_stream_as::<f64>(state),
+        SampleFormat::I16 => probe_stream_as::<i16>(state),
+        SampleFormat::I32 => probe_stream_as::<i32>(state),
+        SampleFormat::U16 => probe_stream_as::<u16>(state),
+        SampleFormat::I8 => probe_stream_as::<i8>(state),
+        SampleFormat::U8 => probe_stream_as::<u8>(state),
+        other => Err(SpeakerError::CpalError(format!(
+            "unsupported sample format: {:?}",
+            other
+        ))),
+    }
+}
+
+fn probe_stream_as<T>(state: &DeviceState) -> Result<(), SpeakerError>
+where
+    T: SizedSample + Send + 'static,
+{
+    state
+        .device
+        .build_output_stream(
+            state.config,
+            |out: &mut [T], _info: &cpal::OutputCallbackInfo| out.fill(T::EQUILIBRIUM),
+            |err| warn!("cpal stream error during readiness probe: {}", err),
+            None,
+        )
+        .map(drop)
+        .map_err(|e| classify_to_speaker_error(&e, "build_output_stream"))
+}
+
+fn device_name(state: &DeviceState) -> String {
+    state
+        .device
+        .description()
+        .map(|d| d.name().to_owned())
+        .unwrap_or_else(|_| "<unknown>".into())
+}
+
 fn log_device_state(state: &DeviceState, cfg: &CpalConfig) {
     info!(
         "CPAL backend: device={:?}, sample_rate={}, channels={}, format={:?}, buffer_size={:?}, waveform={:?}, volume={}",
-        state
-            .device
-            .description()
-            .map(|d| d.name().to_owned())
-            .unwrap_or_else(|_| "<unknown>".into()),
+        device_name(state),
         state.config.sample_rate,
         state.config.channels,
         state.sample_format,
@@ -573,6 +626,17 @@ enum ErrorClass {
     Fatal,
 }
 
+impl ErrorClass {
+    // Label value for spkrd_cpal_stream_errors_total.
+    fn label(self) -> &'static str {
+        match self {
+            ErrorClass::Continues => "continues",
+            ErrorClass::Disconnect => "disconnect",
+            ErrorClass::Fatal => "fatal",
+        }
+    }
+}
+
 // Classify a cpal error by its ErrorKind. cpal 0.18+ exposes structured
 // kinds; matching on those is more robust than the prior
 // substring-on-message approach (which depended on the exact wording of
@@ -607,21 +671,14 @@ fn classify_error(err: &cpal::Error) -> ErrorClass {
 // "let it keep running" option.
 fn classify_to_speaker_error(err: &cpal::Error, ctx: &str) -> SpeakerError {
     let msg = format!("{}: {}", ctx, err);
-    match classify_error(err) {
+    let class = classify_error(err);
+    METRICS.cpal_stream_error(class.label());
+    match class {
         ErrorClass::Disconnect => SpeakerError::CpalDisconnect(msg),
         ErrorClass::Fatal | ErrorClass::Continues => SpeakerError::CpalError(msg),
     }
 }
 
-fn validate_melody(melody: &str, max_melody_length: usize) -> Result<(), SpeakerError> {
-    if melody.len() > max_melody_length {
-        return Err(SpeakerError::InvalidMelody(
-            format!("Melody exceeds {} bytes", max_melody_length),
-        ));
-    }
-    Ok(())
-}
-
 fn log_request(client_addr: SocketAddr, melody: &str) {
     let printable: String = melody
         .chars()
@@ -632,287 +689,10 @@ fn log_request(client_addr: SocketAddr, melody: &str) {
     debug!("Request from {}: melody={}", client_addr.ip(), printable);
 }
 
-// Synthesise the event sequence into a mono f32 PCM buffer. Dispatches to a
-// dedicated PC-speaker path (square + biquad chain + saturation) or to the
-// generic oscillator path (Square / SquareBandlimited / Sine / Triangle /
-// Sawtooth) which adds an AR envelope to suppress note-boundary clicks.
-fn synth(events: &[Event], sr: u32, wf: Waveform, volume: f32) -> Vec<f32> {
-    match wf {
-        Waveform::PcSpeaker => synth_pcspeaker(events, sr, volume),
-        _ => synth_generic(events, sr, wf, volume),
-    }
-}
-
-// Precompute the total sample count for buffer preallocation.
-fn total_samples(events: &[Event], sr: u32) -> usize {
-    let total_cs: u64 = events
-        .iter()
-        .map(|e| match *e {
-            Event::Tone { centisecs, .. } => centisecs as u64,
-            Event::Rest { centisecs } => centisecs as u64,
-        })
-        .sum();
-    (total_cs as usize) * (sr as usize) / 100
-}
-
-// Generic oscillator path. Behaviour is per-waveform:
-//
-//   * Waveform::Square is the kernel-faithful raw output: phase is reset to
-//     0 at every Tone event start (mirroring spkr.c's timer_spkr_setfreq()
-//     resetting the PIT counter), and no AR envelope is applied. This
-//     reproduces the FreeBSD driver's hard-edged amplitude-step transients
-//     at note boundaries — the "plink" the ear locks onto as articulation
-//     even when consecutive notes share a frequency. Boundary clicks are
-//     intentional here.
-//
-//   * The remaining software-only waveforms (SquareBandlimited, Sine,
-//     Triangle, Sawtooth) have no FreeBSD analog. They keep phase continuity
-//     across consecutive tones and apply a 5 ms linear AR envelope (capped
-//     at n/4 each side so attack+release can't exceed half a short staccato
-//     note) to suppress the amplitude-step clicks that would otherwise be
-//     audible at every Tone boundary.
-fn synth_generic(events: &[Event], sr: u32, wf: Waveform, volume: f32) -> Vec<f32> {
-    let sr_f = sr as f32;
-    let mut out: Vec<f32> = Vec::with_capacity(total_samples(events, sr));
-    let default_ramp = (sr_f * ENVELOPE_MS / 1000.0) as usize;
-
-    let kernel_faithful = matches!(wf, Waveform::Square);
-
-    let mut phase: f32 = 0.0;
-
-    for ev in events {
-        match *ev {
-            Event::Rest { centisecs } => {
-                let n = (centisecs as u64 * sr as u64 / 100) as usize;
-                out.extend(std::iter::repeat_n(0.0, n));
-                phase = 0.0;
-            }
-            Event::Tone { freq_hz, centisecs } => {
-                let n = (centisecs as u64 * sr as u64 / 100) as usize;
-                if n == 0 {
-                    continue;
-                }
-                if kernel_faithful {
-                    phase = 0.0;
-                }
-                let f = freq_hz as f32;
-                let dphase = f / sr_f;
-                let ramp = default_ramp.min(n / 4).max(1);
-                for i in 0..n {
-                    let s = match wf {
-                        Waveform::Square => {
-                            if phase < 0.5 { 1.0 } else { -1.0 }
-                        }
-                        Waveform::SquareBandlimited => {
-                            // PolyBLEP: sawtooth + shifted sawtooth.
-                            let saw1 = 2.0 * phase - 1.0;
-                            let phase2 = (phase + 0.5).fract();
-                            let saw2 = 2.0 * phase2 - 1.0;
-                            let sq = saw1 - saw2;
-                            sq - poly_blep(phase, dphase)
-                                + poly_blep(phase2, dphase)
-                        }
-                        Waveform::Sine => (2.0 * PI * phase).sin(),
-                        Waveform::Triangle => {
-                            if phase < 0.25 {
-                                4.0 * phase
-                            } else if phase < 0.75 {
-                                2.0 - 4.0 * phase
-                            } else {
-                                -4.0 + 4.0 * phase
-                            }
-                        }
-                        Waveform::Sawtooth => 2.0 * phase - 1.0,
-                        Waveform::PcSpeaker => unreachable!(),
-                    };
-                    let gain = if kernel_faithful {
-                        1.0
-                    } else if i < ramp {
-                        i as f32 / ramp as f32
-                    } else if i + ramp >= n {
-                        (n - 1 - i) as f32 / ramp as f32
-                    } else {
-                        1.0
-                    };
-                    out.push(s * gain * volume);
-                    phase += dphase;
-                    if phase >= 1.0 {
-                        phase -= phase.floor();
-                    }
-                }
-            }
-        }
-    }
-    out
-}
-
-// PC-speaker simulation path. The note frequency is rounded to the nearest
-// PIT-achievable value (PIT_FREQ / divisor) before sample generation —
-// matching what real hardware would actually play. A ±1 square at that
-// frequency is fed through HP -> peaking -> LP biquads (Modern piezo disc
-// preset) and through a tanh saturator. Filter state persists across all
-// events including rests, so the speaker "rings out" naturally on note-off.
-//
-// Phase is reset to 0 at every Tone event start to mirror the PIT counter
-// reset that timer_spkr_setfreq() performs in the FreeBSD kernel. The
-// resulting amplitude-step transient is shaped by the biquad chain into a
-// mechanical-style "plink" — what a real piezo would produce when the gate
-// reopens at a fresh PIT count, rather than the sharp DAC click you'd get
-// from feeding the same raw signal to a modern audio output.
-fn synth_pcspeaker(events: &[Event], sr: u32, volume: f32) -> Vec<f32> {
-    let sr_f = sr as f32;
-    let mut out: Vec<f32> = Vec::with_capacity(total_samples(events, sr));
-
-    let mut hp = Biquad::highpass(sr, PIEZO_HP_HZ, PIEZO_HP_Q);
-    let mut pk = Biquad::peak(sr, PIEZO_PEAK_HZ, PIEZO_PEAK_Q, PIEZO_PEAK_DB);
-    let mut lp = Biquad::lowpass(sr, PIEZO_LP_HZ, PIEZO_LP_Q);
-
-    for ev in events {
-        match *ev {
-            Event::Rest { centisecs } => {
-                let n = (centisecs as u64 * sr as u64 / 100) as usize;
-                for _ in 0..n {
-                    let y = lp.process(pk.process(hp.process(0.0)));
-                    out.push((PIEZO_DRIVE * y).tanh() * volume);
-                }
-            }
-            Event::Tone { freq_hz, centisecs } => {
-                let n = (centisecs as u64 * sr as u64 / 100) as usize;
-                if n == 0 {
-                    continue;
-                }
-                let q_freq = pit_quantize(freq_hz);
-                let dphase = q_freq as f32 / sr_f;
-                let mut phase: f32 = 0.0;
-                for _ in 0..n {
-                    let raw = if phase < 0.5 { 1.0 } else { -1.0 };
-                    let y = lp.process(pk.process(hp.process(raw)));
-                    out.push((PIEZO_DRIVE * y).tanh() * volume);
-                    phase += dphase;
-                    if phase >= 1.0 {
-                        phase -= phase.floor();
-                    }
-                }
-            }
-        }
-    }
-    out
-}
-
-// Round a desired frequency to the nearest frequency the PIT can actually
-// produce: divisor = round(PIT_FREQ / freq), achievable = PIT_FREQ / divisor.
-fn pit_quantize(freq_hz: u32) -> u32 {
-    if freq_hz == 0 {
-        return 0;
-    }
-    let divisor = ((PIT_FREQ + freq_hz / 2) / freq_hz).max(1);
-    PIT_FREQ / divisor
-}
-
-// Direct-form-2 transposed biquad. Coefficients are pre-normalised by a0 at
-// construction time so `process` is just five mul-adds.
-struct Biquad {
-    b0: f32,
-    b1: f32,
-    b2: f32,
-    a1: f32,
-    a2: f32,
-    s1: f32,
-    s2: f32,
-}
-
-impl Biquad {
-    fn process(&mut self, x: f32) -> f32 {
-        let y = self.b0 * x + self.s1;
-        self.s1 = self.b1 * x - self.a1 * y + self.s2;
-        self.s2 = self.b2 * x - self.a2 * y;
-        y
-    }
-
-    // RBJ audio cookbook: lowpass biquad.
-    fn lowpass(sr: u32, hz: f32, q: f32) -> Self {
-        let omega = 2.0 * PI * hz / sr as f32;
-        let alpha = omega.sin() / (2.0 * q);
-        let cos_w = omega.cos();
-        let b0 = (1.0 - cos_w) * 0.5;
-        let b1 = 1.0 - cos_w;
-        let b2 = (1.0 - cos_w) * 0.5;
-        let a0 = 1.0 + alpha;
-        let a1 = -2.0 * cos_w;
-        let a2 = 1.0 - alpha;
-        Self::normalise(a0, b0, b1, b2, a1, a2)
-    }
-
-    // RBJ audio cookbook: highpass biquad.
-    fn highpass(sr: u32, hz: f32, q: f32) -> Self {
-        let omega = 2.0 * PI * hz / sr as f32;
-        let alpha = omega.sin() / (2.0 * q);
-        let cos_w = omega.cos();
-        let b0 = (1.0 + cos_w) * 0.5;
-        let b1 = -(1.0 + cos_w);
-        let b2 = (1.0 + cos_w) * 0.5;
-        let a0 = 1.0 + alpha;
-        let a1 = -2.0 * cos_w;
-        let a2 = 1.0 - alpha;
-        Self::normalise(a0, b0, b1, b2, a1, a2)
-    }
-
-    // RBJ audio cookbook: peaking EQ biquad.
-    fn peak(sr: u32, hz: f32, q: f32, gain_db: f32) -> Self {
-        let a_amp = 10f32.powf(gain_db / 40.0);
-        let omega = 2.0 * PI * hz / sr as f32;
-        let alpha = omega.sin() / (2.0 * q);
-        let cos_w = omega.cos();
-        let b0 = 1.0 + alpha * a_amp;
-        let b1 = -2.0 * cos_w;
-        let b2 = 1.0 - alpha * a_amp;
-        let a0 = 1.0 + alpha / a_amp;
-        let a1 = -2.0 * cos_w;
-        let a2 = 1.0 - alpha / a_amp;
-        Self::normalise(a0, b0, b1, b2, a1, a2)
-    }
-
-    fn normalise(a0: f32, b0: f32, b1: f32, b2: f32, a1: f32, a2: f32) -> Self {
-        Self {
-            b0: b0 / a0,
-            b1: b1 / a0,
-            b2: b2 / a0,
-            a1: a1 / a0,
-            a2: a2 / a0,
-            s1: 0.0,
-            s2: 0.0,
-        }
-    }
-}
-
-// PolyBLEP correction for band-limited oscillators. `t` is phase in [0,1),
-// `dt` is per-sample phase increment.
-fn poly_blep(t: f32, dt: f32) -> f32 {
-    if t < dt {
-        let x = t / dt;
-        x + x - x * x - 1.0
-    } else if t > 1.0 - dt {
-        let x = (t - 1.0) / dt;
-        x * x + x + x + 1.0
-    } else {
-        0.0
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn pit_quantize_round_trip() {
-        // PIT_FREQ / 1140 = 1046.65... → rounds to divisor 1140 → 1046 Hz.
-        assert_eq!(pit_quantize(1047), 1193182 / 1140);
-        // 440 Hz: divisor = round(1193182/440) = 2712 → PIT/2712 = 439 Hz.
-        assert_eq!(pit_quantize(440), 1193182 / 2712);
-        // Zero-input guard.
-        assert_eq!(pit_quantize(0), 0);
-    }
-
     #[test]
     fn classify_error_buckets() {
         let mk = |k: ErrorKind| cpal::Error::new(k);
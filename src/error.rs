@@ -4,19 +4,30 @@
 // permission denied, generic backend error) — surfaced verbatim to the
 // HTTP client. CpalDisconnect is the "transient, retryable" subset
 // (host/device went away, stream invalidated) — the cpal backend's
-// acquire_and_play retry loop matches on this variant and rebuilds the
-// device on the same 1s cadence as the busy-device retry, sharing the
-// request's --retry-timeout window. Only after the timeout elapses does
-// a CpalDisconnect propagate up to the HTTP layer.
+// play_with_reconnect retry loop matches on this variant and rebuilds the
+// device on a 1s cadence, sharing the request's --retry-timeout window.
+// Only after the timeout elapses does a CpalDisconnect propagate up to the
+// HTTP layer.
+//
+// QueueFull vs Timeout: both mean "the device is busy" and map to 503.
+// QueueFull is returned immediately when --queue-depth requests are
+// already waiting; Timeout after a request waited its --retry-timeout in
+// the queue (or, for /dev/speaker, for another process to let go).
+//
+// Interrupted: playback was cut short by POST /stop. The backends return it
+// whenever they observe their abort flag, including after a client
+// disconnect, but only a /stop leaves anyone to see it.
 
 use std::fmt;
 
 #[derive(Debug)]
 pub enum SpeakerError {
     DeviceBusy,
+    QueueFull,
     DeviceError(std::io::Error),
     InvalidMelody(String),
     Timeout,
+    Interrupted,
     #[cfg(feature = "cpal")]
     CpalError(String),
     #[cfg(feature = "cpal")]
@@ -27,9 +38,11 @@ impl fmt::Display for SpeakerError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             SpeakerError::DeviceBusy => write!(f, "Speaker device is busy"),
+            SpeakerError::QueueFull => write!(f, "Playback queue is full"),
             SpeakerError::DeviceError(e) => write!(f, "Device error: {}", e),
             SpeakerError::InvalidMelody(msg) => write!(f, "Invalid melody: {}", msg),
             SpeakerError::Timeout => write!(f, "Operation timed out"),
+            SpeakerError::Interrupted => write!(f, "Playback interrupted"),
             #[cfg(feature = "cpal")]
             SpeakerError::CpalError(msg) => write!(f, "CPAL error: {}", msg),
             #[cfg(feature = "cpal")]
@@ -44,7 +57,7 @@ impl From<std::io::Error> for SpeakerError {
     fn from(err: std::io::Error) -> Self {
         use std::io::ErrorKind;
         match err.kind() {
-            ErrorKind::WouldBlock | ErrorKind::AddrInUse => SpeakerError::DeviceBusy,
+            ErrorKind::WouldBlock | ErrorKind::AddrInUse | ErrorKind::ResourceBusy => SpeakerError::DeviceBusy,
             _ => SpeakerError::DeviceError(err),
         }
     }
@@ -0,0 +1,310 @@
+// File and null output backends (--output=file, --output=null): the CPAL
+// backend's pipeline — mml::render_voices, then the synth module — with the
+// audio appended to a file, or discarded, instead of sent to a sound card.
+// They need no audio hardware and no `cpal` feature, so CI and headless
+// servers can exercise everything a real play does: parsing, synthesis, the
+// play queue, /stop and /status.
+//
+// The file is opened for each melody and appended to. As raw PCM (16-bit
+// little-endian mono; `aplay -f S16_LE -c 1 -r RATE`) it can also be a FIFO
+// read by a player. As WAV it stays one valid file across melodies and
+// server restarts: the samples are appended and the header's lengths
+// rewritten, which needs a regular file already holding a WAV at the same
+// rate (or an empty or missing one). A WAV file cannot pass 4 GiB, about
+// 6 hours at 192 kHz; a melody that would take it past is refused, and
+// the file has to be moved aside for playing to go on.
+//
+// Without --pace a melody is written, or dropped, as fast as it can be
+// synthesised. With it, the samples are written 10 ms at a time at the
+// rate they would play, so a request holds the queue for as long as it
+// would on a speaker. The abort flag is checked between chunks, as
+// freebsd_speaker checks it between notes.
+
+use crate::error::SpeakerError;
+use crate::mml::{Event, Timing};
+use crate::queue::{AbortOnDrop, PlayRequest, Ticket};
+use crate::speaker_profile::SpeakerProfile;
+use crate::synth::{mix, Envelope, Sound, Waveform, Wavetable};
+use crate::tuning::Tuning;
+use crate::wav;
+use log::debug;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Samples per write: 10 ms, the resolution of --pace.
+const CHUNKS_PER_SECOND: u32 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    Wav,
+    Pcm,
+}
+
+#[derive(Clone, Serialize)]
+pub struct FileConfig {
+    // Where the audio goes; None discards it (--output=null).
+    pub path: Option<String>,
+    pub format: FileFormat,
+    pub sample_rate: u32,
+    pub waveform: Waveform,
+    pub volume: f32,
+    pub envelope: Option<Envelope>,
+    pub wavetable: Option<Wavetable>,
+    pub speaker: SpeakerProfile,
+    pub tuning: Tuning,
+    pub timing: Timing,
+    pub pace: bool,
+}
+
+impl FileConfig {
+    pub fn sound(&self) -> Sound {
+        Sound {
+            waveform: self.waveform,
+            volume: self.volume,
+            envelope: self.envelope,
+            wavetable: self.wavetable.clone(),
+            speaker: self.speaker.clone(),
+        }
+    }
+}
+
+pub struct FileBackend {
+    cfg: FileConfig,
+}
+
+impl FileBackend {
+    pub fn new(cfg: FileConfig) -> Self {
+        Self { cfg }
+    }
+
+    pub fn config(&self) -> &FileConfig {
+        &self.cfg
+    }
+
+    pub async fn play_melody(
+        self: &Arc<Self>,
+        req: &PlayRequest<'_>,
+        ticket: Ticket,
+    ) -> Result<u32, SpeakerError> {
+        if req.debug {
+            debug!("Request from {}: {} bytes", req.client_addr.ip(), req.melody.len());
+        }
+        let abort = Arc::new(AtomicBool::new(false));
+        let _abort_on_drop = AbortOnDrop(Arc::clone(&abort));
+
+        let slot = ticket.wait(req, &abort).await?;
+        let voices = req.dialect.render_voices_with(req.melody, &self.cfg.tuning, self.cfg.timing);
+        let sound = req.sound.apply(self.cfg.sound());
+        let backend = Arc::clone(self);
+        let join = tokio::task::spawn_blocking(move || {
+            let _slot = slot;
+            backend.play(&voices, &sound, &abort)
+        });
+        match join.await {
+            Ok(result) => result.map(|()| 0),
+            Err(e) => Err(SpeakerError::DeviceError(io::Error::other(e))),
+        }
+    }
+
+    fn play(&self, voices: &[Vec<Event>], sound: &Sound, abort: &AtomicBool) -> Result<(), SpeakerError> {
+        let sr = self.cfg.sample_rate;
+        let samples = mix(voices, sr, sound);
+        let mut sink = match &self.cfg.path {
+            Some(path) => Some(Sink::open(path, self.cfg.format, sr)?),
+            None => None,
+        };
+        if let Some(sink) = &sink {
+            sink.check_room(samples.len(), self.cfg.path.as_deref().unwrap_or_default())?;
+        }
+
+        let start = Instant::now();
+        let chunk_len = (sr / CHUNKS_PER_SECOND).max(1) as usize;
+        let mut written = 0;
+        let mut result = Ok(());
+        for chunk in samples.chunks(chunk_len) {
+            if abort.load(Ordering::SeqCst) {
+                result = Err(SpeakerError::Interrupted);
+                break;
+            }
+            if let Some(sink) = &mut sink {
+                sink.write(chunk)?;
+            }
+            written += chunk.len();
+            if self.cfg.pace {
+                let due = start + Duration::from_secs_f64(written as f64 / f64::from(sr));
+                std::thread::sleep(due.saturating_duration_since(Instant::now()));
+            }
+        }
+        // An interrupted melody is kept up to where it stopped, as it would
+        // have been heard.
+        if let Some(sink) = sink {
+            sink.finish()?;
+        }
+        result
+    }
+
+    // Readiness probe: can the file be opened for appending? This creates
+    // it if missing, as the first melody would.
+    pub fn probe(&self) -> Result<(), SpeakerError> {
+        match &self.cfg.path {
+            Some(path) => {
+                OpenOptions::new().create(true).append(true).open(path)?;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+struct Sink {
+    file: File,
+    format: FileFormat,
+    sample_rate: u32,
+    // WAV only: data bytes in the file, before and after this melody.
+    data_len: u32,
+}
+
+impl Sink {
+    fn open(path: &str, format: FileFormat, sample_rate: u32) -> Result<Self, SpeakerError> {
+        let mut sink = match format {
+            FileFormat::Pcm => Sink {
+                file: OpenOptions::new().create(true).append(true).open(path)?,
+                format,
+                sample_rate,
+                data_len: 0,
+            },
+            FileFormat::Wav => {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .read(true)
+                    .write(true)
+                    .open(path)?;
+                let mut header = Vec::new();
+                (&mut file).take(wav::HEADER_LEN as u64).read_to_end(&mut header)?;
+                let data_len = match wav::parse_header(&header) {
+                    _ if header.is_empty() => 0,
+                    Some((rate, data_len)) if rate == sample_rate => data_len,
+                    _ => {
+                        return Err(SpeakerError::DeviceError(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{} is not a 16-bit mono {} Hz WAV file spkrd wrote", path, sample_rate),
+                        )))
+                    }
+                };
+                let mut sink = Sink {
+                    file,
+                    format,
+                    sample_rate,
+                    data_len,
+                };
+                sink.write_header()?;
+                sink.file.seek(SeekFrom::Start(wav::HEADER_LEN as u64 + u64::from(data_len)))?;
+                sink
+            }
+        };
+        sink.file.flush()?;
+        Ok(sink)
+    }
+
+    // Refuse a melody of `samples` samples that would take a WAV file past
+    // wav::MAX_DATA_LEN, before any of it is written.
+    fn check_room(&self, samples: usize, path: &str) -> Result<(), SpeakerError> {
+        let room = u64::from(wav::MAX_DATA_LEN - self.data_len);
+        if self.format == FileFormat::Wav && samples as u64 * 2 > room {
+            return Err(SpeakerError::DeviceError(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!("{} is full: a WAV file cannot hold more than 4 GiB", path),
+            )));
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        let bytes = wav::pcm(samples);
+        self.file.write_all(&bytes)?;
+        // Within wav::MAX_DATA_LEN: see check_room.
+        self.data_len += bytes.len() as u32;
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        if self.format == FileFormat::Wav {
+            self.write_header()?;
+        }
+        self.file.flush()
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&wav::header(self.sample_rate, self.data_len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mml;
+
+    fn backend(path: &std::path::Path, format: FileFormat, sample_rate: u32) -> FileBackend {
+        FileBackend::new(FileConfig {
+            path: Some(path.to_string_lossy().to_string()),
+            format,
+            sample_rate,
+            waveform: Waveform::Square,
+            volume: 0.5,
+            envelope: None,
+            wavetable: None,
+            speaker: SpeakerProfile::default(),
+            tuning: Tuning::default(),
+            timing: Timing::default(),
+            pace: false,
+        })
+    }
+
+    fn play(backend: &FileBackend, voices: &[Vec<Event>], abort: &AtomicBool) -> Result<(), SpeakerError> {
+        backend.play(voices, &backend.cfg.sound(), abort)
+    }
+
+    #[test]
+    fn wav_file_grows_across_melodies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.wav");
+        let abort = AtomicBool::new(false);
+        let events = mml::render_voices("t120 c4");
+
+        play(&backend(&path, FileFormat::Wav, 8000), &events, &abort).unwrap();
+        play(&backend(&path, FileFormat::Wav, 8000), &events, &abort).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), wav::HEADER_LEN + 2 * 8000);
+        assert_eq!(wav::parse_header(&bytes), Some((8000, 2 * 8000)));
+
+        // A different rate would make the file unplayable.
+        let err = play(&backend(&path, FileFormat::Wav, 16000), &events, &abort);
+        assert!(matches!(err, Err(SpeakerError::DeviceError(_))));
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+
+        // Nor may it grow past what its header can describe.
+        let mut full = bytes[..wav::HEADER_LEN].to_vec();
+        full[40..44].copy_from_slice(&(wav::MAX_DATA_LEN - 100).to_le_bytes());
+        full[4..8].copy_from_slice(&(wav::MAX_DATA_LEN - 64).to_le_bytes());
+        std::fs::write(&path, &full).unwrap();
+        let err = play(&backend(&path, FileFormat::Wav, 8000), &events, &abort);
+        assert!(matches!(err, Err(SpeakerError::DeviceError(e)) if e.kind() == io::ErrorKind::StorageFull));
+    }
+
+    #[test]
+    fn abort_stops_between_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.pcm");
+        let abort = AtomicBool::new(true);
+        let err = play(&backend(&path, FileFormat::Pcm, 8000), &mml::render_voices("c"), &abort);
+        assert!(matches!(err, Err(SpeakerError::Interrupted)));
+        assert_eq!(std::fs::read(&path).unwrap().len(), 0);
+    }
+}
@@ -1,68 +1,195 @@
 // FreeBSD /dev/speaker backend: writes the raw melody string to a character
-// device with retry-on-busy logic. Mirrors the original behaviour of this
-// program before the CPAL backend was added.
+// device. Mirrors the original behaviour of this program before the CPAL
+// backend was added. The kernel interprets the melody itself and skips
+// whatever it does not understand, so in strict mode the body is checked
+// against the mml module's parser before the request is queued.
+//
+// Requests from spkrd's own clients take turns through the shared play
+// queue, so they never contend for the device among themselves. The
+// retry-on-busy loop that remains only covers another process holding
+// /dev/speaker open (the kernel allows a single opener and returns EBUSY).
+//
+// A write to /dev/speaker blocks until the kernel has played it, so the
+// device is driven from a spawn_blocking task that holds the queue slot,
+// and the melody is written one command at a time (mml::split_commands;
+// with repeats, the expanded commands are written one by one). Between
+// writes the task checks an abort flag — raised by AbortOnDrop when the
+// request future is dropped (a client disconnect or a cancelled job), or by
+// POST /stop — so playback stops after the current note instead of running
+// to the end.
+//
+// The kernel cannot be told about ?transpose= or ?tempo=, so a melody
+// played with either is rewritten first (mml::Dialect::rewrite): notes
+// become explicit O and N commands in the new key and T commands are
+// scaled, and the kernel plays that instead. A factor that would scale a
+// T outside the kernel's range is refused up front, by
+// PlayRequest::validate.
+//
+// With --spkrtone the kernel's interpreter is bypassed: the melody is
+// rendered by the mml module, as for every other backend, and each tone or
+// rest is issued as a SPKRTONE ioctl (a tone_t of frequency and
+// centiseconds, frequency 0 resting), which returns once the kernel has
+// played it. The melody then means exactly what it means elsewhere, /stop
+// lands between notes even inside one long MML command, and the length
+// /status reports is the length actually played. The ioctl sits behind
+// the ToneDevice trait so the note loop can be tested without FreeBSD.
 
 use crate::error::SpeakerError;
-use std::fs::OpenOptions;
-use std::io::Write;
+use crate::mml::{self, Event};
+use crate::queue::{self, AbortOnDrop, PlayRequest, Ticket};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
 use std::net::SocketAddr;
-use std::time::{Duration, Instant};
-use tokio::time::sleep;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 use log::debug;
 
-const RETRY_INTERVAL: Duration = Duration::from_secs(1);
-
 pub async fn play_melody(
-    melody: &str,
-    client_addr: SocketAddr,
-    retry_timeout: Duration,
+    req: &PlayRequest<'_>,
+    ticket: Ticket,
     device_path: &str,
-    max_melody_length: usize,
-    debug: bool,
+    spkrtone: bool,
 ) -> Result<u32, SpeakerError> {
-    validate_melody(melody, max_melody_length)?;
-
-    if debug {
-        log_request(client_addr, melody);
+    if req.debug {
+        log_request(req.client_addr, req.melody);
     }
 
     let start_time = Instant::now();
-    let mut retries = 0;
-    
-    loop {
-        match try_play_melody(melody, device_path) {
-            Ok(()) => return Ok(retries),
-            Err(SpeakerError::DeviceBusy) => {
-                if start_time.elapsed() >= retry_timeout {
-                    return Err(SpeakerError::Timeout);
+    let abort = Arc::new(AtomicBool::new(false));
+    let _abort_on_drop = AbortOnDrop(Arc::clone(&abort));
+
+    let slot = ticket.wait(req, &abort).await?;
+    let melody = req.melody.to_owned();
+    let dialect = req.dialect;
+    let device_path = device_path.to_owned();
+    let retry_timeout = req.retry_timeout;
+    let join = tokio::task::spawn_blocking(move || {
+        let _slot = slot;
+        if spkrtone {
+            let events = dialect.render(&melody);
+            queue::retry_while_busy(start_time, retry_timeout, &abort, || {
+                let mut speaker = DevSpeaker::open(&device_path)?;
+                play_tones(&mut speaker, &events, &abort)
+            })
+        } else {
+            let (melody, dialect) = if dialect.transform.is_identity() {
+                (melody, dialect)
+            } else {
+                // Checked by PlayRequest::validate.
+                match dialect.rewrite(&melody) {
+                    Ok(melody) => (melody, mml::Dialect::default()),
+                    Err(e) => return Err(SpeakerError::InvalidMelody(e)),
                 }
-                retries += 1;
-                sleep(RETRY_INTERVAL).await;
-                continue;
-            }
-            Err(e) => return Err(e),
+            };
+            queue::retry_while_busy(start_time, retry_timeout, &abort, || {
+                try_play_melody(&melody, dialect, &device_path, &abort)
+            })
         }
-    }
-}
+    });
 
-fn validate_melody(melody: &str, max_melody_length: usize) -> Result<(), SpeakerError> {
-    if melody.len() > max_melody_length {
-        return Err(SpeakerError::InvalidMelody(
-            format!("Melody exceeds {} bytes", max_melody_length),
-        ));
+    match join.await {
+        Ok(result) => result,
+        Err(e) => Err(SpeakerError::DeviceError(std::io::Error::other(e))),
     }
-    Ok(())
 }
 
-fn try_play_melody(melody: &str, device_path: &str) -> Result<(), SpeakerError> {
+fn try_play_melody(
+    melody: &str,
+    dialect: mml::Dialect,
+    device_path: &str,
+    abort: &AtomicBool,
+) -> Result<(), SpeakerError> {
     let mut file = OpenOptions::new()
         .write(true)
         .open(device_path)?;
-    
-    file.write_all(melody.as_bytes())?;
+
+    for piece in dialect.split_commands(melody) {
+        if abort.load(Ordering::SeqCst) {
+            return Err(SpeakerError::Interrupted);
+        }
+        file.write_all(piece.as_bytes())?;
+    }
+    Ok(())
+}
+
+pub trait ToneDevice {
+    // Sound `freq_hz` (0 for a rest) for `centisecs`, returning when done.
+    fn tone(&mut self, freq_hz: u32, centisecs: u32) -> io::Result<()>;
+}
+
+// <machine/speaker.h>
+#[cfg(target_os = "freebsd")]
+#[repr(C)]
+struct ToneT {
+    frequency: libc::c_int,
+    duration: libc::c_int,
+}
+
+// _IOW('S', 1, tone_t)
+#[cfg(target_os = "freebsd")]
+const SPKRTONE: libc::c_ulong = 0x8008_5301;
+
+struct DevSpeaker {
+    file: File,
+}
+
+impl DevSpeaker {
+    fn open(device_path: &str) -> Result<Self, SpeakerError> {
+        let file = OpenOptions::new().write(true).open(device_path)?;
+        Ok(Self { file })
+    }
+}
+
+impl ToneDevice for DevSpeaker {
+    fn tone(&mut self, freq_hz: u32, centisecs: u32) -> io::Result<()> {
+        #[cfg(target_os = "freebsd")]
+        {
+            use std::os::fd::AsRawFd;
+            let tone = ToneT {
+                frequency: libc::c_int::try_from(freq_hz).unwrap_or(libc::c_int::MAX),
+                duration: libc::c_int::try_from(centisecs).unwrap_or(libc::c_int::MAX),
+            };
+            // SAFETY: SPKRTONE reads one tone_t through the pointer.
+            let rc = unsafe { libc::ioctl(self.file.as_raw_fd(), SPKRTONE, &tone) };
+            if rc < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        #[cfg(not(target_os = "freebsd"))]
+        {
+            let _ = (&self.file, freq_hz, centisecs);
+            Err(io::Error::new(io::ErrorKind::Unsupported, "SPKRTONE needs FreeBSD"))
+        }
+    }
+}
+
+fn play_tones(device: &mut dyn ToneDevice, events: &[Event], abort: &AtomicBool) -> Result<(), SpeakerError> {
+    for event in events {
+        if abort.load(Ordering::SeqCst) {
+            return Err(SpeakerError::Interrupted);
+        }
+        match *event {
+            Event::Tone { freq_hz, .. } => device.tone(freq_hz, event.centisecs())?,
+            Event::Rest { .. } => device.tone(0, event.centisecs())?,
+        }
+    }
     Ok(())
 }
 
+// Readiness probe: can the device be opened for writing? Another process
+// holding it (EBUSY) counts as usable. Nothing is written.
+pub fn probe(device_path: &str) -> Result<(), SpeakerError> {
+    match OpenOptions::new().write(true).open(device_path) {
+        Ok(_) => Ok(()),
+        Err(e) => match SpeakerError::from(e) {
+            SpeakerError::DeviceBusy => Ok(()),
+            e => Err(e),
+        },
+    }
+}
+
 fn log_request(client_addr: SocketAddr, melody: &str) {
     let printable_melody: String = melody
         .chars()
@@ -70,4 +197,57 @@ fn log_request(client_addr: SocketAddr, melody: &str) {
         .collect();
     
     debug!("Request from {}: melody={}", client_addr.ip(), printable_melody);
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct MockSpeaker {
+        tones: Vec<(u32, u32)>,
+        // Raised after this many tones, as /stop would be mid-melody.
+        abort_after: Option<(usize, Arc<AtomicBool>)>,
+    }
+
+    impl ToneDevice for MockSpeaker {
+        fn tone(&mut self, freq_hz: u32, centisecs: u32) -> io::Result<()> {
+            self.tones.push((freq_hz, centisecs));
+            if let Some((n, abort)) = &self.abort_after {
+                if self.tones.len() == *n {
+                    abort.store(true, Ordering::SeqCst);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn events_become_spkrtone_calls() {
+        let events = mml::render("t120 l8 c p g");
+        let mut speaker = MockSpeaker::default();
+        play_tones(&mut speaker, &events, &AtomicBool::new(false)).unwrap();
+        let expected: Vec<(u32, u32)> = events
+            .iter()
+            .map(|e| match *e {
+                Event::Tone { freq_hz, micros, .. } => (freq_hz, micros / 10_000),
+                Event::Rest { micros } => (0, micros / 10_000),
+            })
+            .collect();
+        assert_eq!(speaker.tones, expected);
+        assert!(speaker.tones.iter().any(|&(f, _)| f == 0));
+    }
+
+    #[test]
+    fn abort_stops_between_tones() {
+        let events = mml::render("cdefgab");
+        let abort = Arc::new(AtomicBool::new(false));
+        let mut speaker = MockSpeaker {
+            abort_after: Some((2, Arc::clone(&abort))),
+            ..MockSpeaker::default()
+        };
+        let result = play_tones(&mut speaker, &events, &abort);
+        assert!(matches!(result, Err(SpeakerError::Interrupted)));
+        assert_eq!(speaker.tones.len(), 2);
+    }
+}
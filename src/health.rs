@@ -0,0 +1,175 @@
+// Liveness and readiness. GET /healthz only says the process is serving
+// HTTP. GET /readyz says whether a melody could be played right now by
+// exercising the backend: /dev/speaker (or the pcspkr input device) must
+// open for writing, the console must open and answer a console ioctl, the
+// file backend's file must open for appending, and the cpal backend must
+// still be able to build an output stream on its cached device (optionally
+// rebuilding it first if not; --ready-rebuild).
+//
+// Probing is not free — an open() of the speaker, or a round trip to the
+// sound server — so the result is cached for --ready-interval and
+// concurrent callers share one probe. A probe needs the device idle: it
+// takes it through PlayQueue::try_reserve, and while a melody is playing
+// it is skipped and the last result stands. Playback in progress is
+// itself evidence that the device works, and a probe must never delay or
+// disturb it.
+
+use crate::console_beeper;
+use crate::freebsd_speaker;
+use crate::linux_pcspkr;
+use crate::queue::PlayQueue;
+use crate::server::Backend;
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    // Why the backend is not ready; None when it is.
+    pub error: Option<String>,
+    // When the backend was last probed; None until the first probe.
+    pub checked_at: Option<DateTime<Utc>>,
+}
+
+pub struct ReadinessCheck {
+    interval: Duration,
+    rebuild: bool,
+    last: Mutex<Option<(Instant, Readiness)>>,
+}
+
+impl ReadinessCheck {
+    pub fn new(interval: Duration, rebuild: bool) -> Self {
+        Self {
+            interval,
+            rebuild,
+            last: Mutex::new(None),
+        }
+    }
+
+    pub async fn check(&self, backend: &Backend, queue: &PlayQueue) -> Readiness {
+        let mut last = self.last.lock().await;
+        if let Some((at, readiness)) = &*last {
+            if at.elapsed() < self.interval {
+                return readiness.clone();
+            }
+        }
+
+        let Some(reservation) = queue.try_reserve() else {
+            return match &*last {
+                Some((_, readiness)) => readiness.clone(),
+                None => Readiness {
+                    ready: true,
+                    error: None,
+                    checked_at: None,
+                },
+            };
+        };
+
+        let backend = backend.clone();
+        let rebuild = self.rebuild;
+        let result = tokio::task::spawn_blocking(move || {
+            let _reservation = reservation;
+            #[cfg(not(feature = "cpal"))]
+            let _ = rebuild;
+            match &backend {
+                Backend::FreebsdSpeaker { device_path } | Backend::FreebsdSpkrtone { device_path } => {
+                    freebsd_speaker::probe(device_path)
+                }
+                Backend::LinuxPcspkr { device_path } => linux_pcspkr::probe(device_path),
+                Backend::Console { device_path } => console_beeper::probe(device_path),
+                #[cfg(feature = "cpal")]
+                Backend::Cpal(b) => b.probe(rebuild),
+                Backend::File(b) => b.probe(),
+            }
+        })
+        .await;
+
+        let error = match result {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(e) => Some(format!("probe failed: {}", e)),
+        };
+        if let Some(e) = &error {
+            warn!("Readiness probe failed: {}", e);
+        }
+        let readiness = Readiness {
+            ready: error.is_none(),
+            error,
+            checked_at: Some(Utc::now()),
+        };
+        *last = Some((Instant::now(), readiness.clone()));
+        readiness
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mml;
+    use crate::queue::PlayRequest;
+    use std::net::SocketAddr;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    fn speaker(path: &std::path::Path) -> Backend {
+        Backend::FreebsdSpeaker {
+            device_path: path.to_string_lossy().to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn result_is_cached_for_the_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let device = dir.path().join("speaker");
+        let queue = PlayQueue::new(1);
+        let check = ReadinessCheck::new(Duration::from_secs(60), false);
+
+        let readiness = check.check(&speaker(&device), &queue).await;
+        assert!(!readiness.ready);
+        assert!(readiness.error.is_some());
+
+        // Fixed, but the failure is remembered until the interval expires.
+        std::fs::write(&device, "").unwrap();
+        assert!(!check.check(&speaker(&device), &queue).await.ready);
+        let fresh = ReadinessCheck::new(Duration::ZERO, false);
+        assert!(fresh.check(&speaker(&device), &queue).await.ready);
+    }
+
+    #[tokio::test]
+    async fn playback_is_not_disturbed() {
+        let dir = tempfile::tempdir().unwrap();
+        let device = dir.path().join("speaker");
+        let queue = PlayQueue::new(1);
+        let check = ReadinessCheck::new(Duration::ZERO, false);
+
+        let req = PlayRequest {
+            melody: "c",
+            client_addr: SocketAddr::from(([127, 0, 0, 1], 1111)),
+            retry_timeout: Duration::from_secs(1),
+            max_melody_length: 1000,
+            strict: false,
+            dialect: mml::Dialect::default(),
+            max_expanded_duration: Duration::from_secs(600),
+            timing: mml::Timing::default(),
+            sound: Default::default(),
+            max_volume: 1.0,
+            wavetable: false,
+            polyphonic: true,
+            extended_range: true,
+            debug: false,
+        };
+        let abort = Arc::new(AtomicBool::new(false));
+        let slot = queue.join().unwrap().wait(&req, &abort).await.unwrap();
+
+        // The device is missing, but it is not probed while in use.
+        let readiness = check.check(&speaker(&device), &queue).await;
+        assert!(readiness.ready);
+        assert!(readiness.checked_at.is_none());
+
+        drop(slot);
+        assert!(!check.check(&speaker(&device), &queue).await.ready);
+    }
+}
@@ -0,0 +1,245 @@
+// Asynchronous playback jobs. POST /jobs (or PUT /play?async=1) admits a
+// melody to the play queue exactly as a blocking /play would — the same
+// 400 and 503 answers — but then answers 202 at once and plays it from a
+// spawned task. The job's progress is recorded here for GET /jobs/{id}.
+//
+// A job moves queued -> playing -> done | failed, or to cancelled from
+// either of the first two. Cancelling aborts the spawned task: a job still
+// waiting drops its queue ticket and leaves the line; a job already
+// playing drops its AbortOnDrop guard, which stops the backend's playback
+// task at its next check (the next note for /dev/speaker, the next audio
+// callback for cpal). In both cases the state is already final, so the
+// task's own outcome is never recorded over it.
+//
+// Finished jobs are kept for GET until MAX_FINISHED newer ones have
+// finished, oldest first; queued and playing jobs are bounded by the play
+// queue itself.
+
+use crate::error::SpeakerError;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::task::AbortHandle;
+
+const MAX_FINISHED: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Playing,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    pub fn is_finished(self) -> bool {
+        matches!(self, JobState::Done | JobState::Failed | JobState::Cancelled)
+    }
+}
+
+// What GET /jobs/{id} reports. `retries` is known once the job is done;
+// `error` is the SpeakerError a failed job ended with.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub id: u64,
+    pub state: JobState,
+    pub client: IpAddr,
+    pub melody_bytes: usize,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub retries: Option<u32>,
+    pub error: Option<String>,
+}
+
+pub enum Cancel {
+    Cancelled(JobStatus),
+    AlreadyFinished(JobStatus),
+}
+
+struct Job {
+    status: JobStatus,
+    task: Option<AbortHandle>,
+}
+
+#[derive(Default)]
+struct Table {
+    jobs: HashMap<u64, Job>,
+    finished: VecDeque<u64>,
+}
+
+impl Table {
+    // Move a job to a final state and forget the oldest finished jobs
+    // beyond MAX_FINISHED.
+    fn finish(&mut self, id: u64, state: JobState) -> Option<&mut Job> {
+        let job = self.jobs.get_mut(&id)?;
+        if job.status.state.is_finished() {
+            return None;
+        }
+        job.status.state = state;
+        job.status.finished_at = Some(Utc::now());
+        job.task = None;
+        self.finished.push_back(id);
+        while self.finished.len() > MAX_FINISHED {
+            if let Some(old) = self.finished.pop_front() {
+                self.jobs.remove(&old);
+            }
+        }
+        self.jobs.get_mut(&id)
+    }
+}
+
+#[derive(Default)]
+pub struct Jobs {
+    next_id: AtomicU64,
+    table: Mutex<Table>,
+}
+
+impl Jobs {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    // Record a new queued job.
+    pub fn create(&self, client: IpAddr, melody_bytes: usize) -> JobStatus {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let status = JobStatus {
+            id,
+            state: JobState::Queued,
+            client,
+            melody_bytes,
+            created_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+            retries: None,
+            error: None,
+        };
+        self.table.lock().unwrap().jobs.insert(
+            id,
+            Job {
+                status: status.clone(),
+                task: None,
+            },
+        );
+        status
+    }
+
+    // Attach the task playing the job, so it can be cancelled. A task that
+    // has already finished (or been cancelled) is left alone.
+    pub fn attach(&self, id: u64, task: AbortHandle) {
+        let mut table = self.table.lock().unwrap();
+        match table.jobs.get_mut(&id) {
+            Some(job) if !job.status.state.is_finished() => job.task = Some(task),
+            _ => task.abort(),
+        }
+    }
+
+    // The job's turn at the device has come.
+    pub fn started(&self, id: u64) {
+        let mut table = self.table.lock().unwrap();
+        if let Some(job) = table.jobs.get_mut(&id) {
+            if job.status.state == JobState::Queued {
+                job.status.state = JobState::Playing;
+                job.status.started_at = Some(Utc::now());
+            }
+        }
+    }
+
+    // Record the outcome of the job's playback.
+    pub fn finish(&self, id: u64, result: &Result<u32, SpeakerError>) {
+        let mut table = self.table.lock().unwrap();
+        let state = match result {
+            Ok(_) => JobState::Done,
+            Err(_) => JobState::Failed,
+        };
+        if let Some(job) = table.finish(id, state) {
+            match result {
+                Ok(retries) => job.status.retries = Some(*retries),
+                Err(e) => job.status.error = Some(e.to_string()),
+            }
+        }
+    }
+
+    // Cancel a queued or playing job. None if the id is unknown (or
+    // finished long enough ago to have been forgotten).
+    pub fn cancel(&self, id: u64) -> Option<Cancel> {
+        let mut table = self.table.lock().unwrap();
+        let task = table.jobs.get_mut(&id)?.task.take();
+        match table.finish(id, JobState::Cancelled) {
+            Some(job) => {
+                if let Some(task) = task {
+                    task.abort();
+                }
+                Some(Cancel::Cancelled(job.status.clone()))
+            }
+            None => Some(Cancel::AlreadyFinished(table.jobs[&id].status.clone())),
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<JobStatus> {
+        let table = self.table.lock().unwrap();
+        table.jobs.get(&id).map(|job| job.status.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn lifecycle() {
+        let jobs = Jobs::new();
+        let id = jobs.create(CLIENT, 3).id;
+        assert_eq!(jobs.get(id).unwrap().state, JobState::Queued);
+
+        jobs.started(id);
+        let status = jobs.get(id).unwrap();
+        assert_eq!(status.state, JobState::Playing);
+        assert!(status.started_at.is_some());
+
+        jobs.finish(id, &Ok(2));
+        let status = jobs.get(id).unwrap();
+        assert_eq!(status.state, JobState::Done);
+        assert_eq!(status.retries, Some(2));
+        assert!(status.finished_at.is_some());
+
+        let failed = jobs.create(CLIENT, 3).id;
+        jobs.finish(failed, &Err(SpeakerError::Timeout));
+        let status = jobs.get(failed).unwrap();
+        assert_eq!(status.state, JobState::Failed);
+        assert_eq!(status.error.as_deref(), Some("Operation timed out"));
+    }
+
+    #[test]
+    fn cancelled_job_keeps_its_state() {
+        let jobs = Jobs::new();
+        let id = jobs.create(CLIENT, 3).id;
+        assert!(matches!(jobs.cancel(id), Some(Cancel::Cancelled(_))));
+        // The aborted task's own outcome does not overwrite the cancellation.
+        jobs.finish(id, &Ok(0));
+        assert_eq!(jobs.get(id).unwrap().state, JobState::Cancelled);
+        assert!(matches!(jobs.cancel(id), Some(Cancel::AlreadyFinished(_))));
+        assert!(jobs.cancel(id + 1).is_none());
+    }
+
+    #[test]
+    fn old_finished_jobs_are_forgotten() {
+        let jobs = Jobs::new();
+        let first = jobs.create(CLIENT, 1).id;
+        jobs.finish(first, &Ok(0));
+        for _ in 0..MAX_FINISHED {
+            let id = jobs.create(CLIENT, 1).id;
+            jobs.finish(id, &Ok(0));
+        }
+        assert!(jobs.get(first).is_none());
+        assert!(jobs.get(first + 1).is_some());
+    }
+}
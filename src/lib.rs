@@ -1,15 +1,41 @@
-// Library interface for spkrd. Up to two output backends are exposed:
-// freebsd_speaker (writes the raw melody string to /dev/speaker) is always
-// compiled; cpal_backend (parses MML via the mml module and synthesises a
-// waveform through the host's audio output) is gated behind the `cpal`
-// Cargo feature, which is enabled by default. server::run dispatches to
-// whichever backend has been selected at startup, listening on the
-// addresses parsed by the bind module from the --bind flag.
+// Library interface for spkrd. Output backends: freebsd_speaker (writes the
+// raw melody string to /dev/speaker), linux_pcspkr (drives the Linux pcspkr
+// input device note by note through the tone module) and console_beeper
+// (the same, through the console's KDMKTONE ioctl) are always compiled;
+// cpal_backend (parses MML via the mml module and synthesises a waveform
+// through the host's audio output) is gated behind the `cpal` Cargo
+// feature, which is enabled by default. server::run dispatches to whichever
+// backend has been selected at startup, listening on the addresses parsed
+// by the bind module from the --bind flag. Requests take turns at the
+// device through the queue module's bounded FIFO; the jobs module tracks
+// requests submitted for asynchronous playback, and the metrics module
+// counts what happens for GET /metrics. The health module backs the
+// /healthz and /readyz probes. Waveform synthesis lives in the synth
+// module, independent of the `cpal` feature, so melodies can also be
+// rendered to WAV files (wav module) on machines with no audio device, and
+// played into a file or into nothing by file_backend. The speaker the
+// pc-speaker waveform emulates is described by the speaker_profile module,
+// and the tunings the synthesisers can render melodies in by the tuning
+// module. The tunes module holds the --tunes-dir library of named
+// melodies.
 
 pub mod bind;
+pub mod console_beeper;
 pub mod error;
 pub mod server;
+pub mod speaker_profile;
+pub mod file_backend;
 pub mod freebsd_speaker;
+pub mod health;
+pub mod jobs;
+pub mod linux_pcspkr;
+pub mod metrics;
 pub mod mml;
+pub mod queue;
+pub mod synth;
+pub mod tone;
+pub mod tunes;
+pub mod tuning;
+pub mod wav;
 #[cfg(feature = "cpal")]
 pub mod cpal_backend;
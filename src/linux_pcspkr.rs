@@ -0,0 +1,104 @@
+// Linux PC-speaker backend (--output=linux-pcspkr). Linux has no
+// /dev/speaker; its pcspkr driver registers an input device instead
+// (normally /dev/input/by-path/platform-pcspkr-event-spkr) that sounds a
+// tone when an EV_SND/SND_TONE event carrying the frequency is written to
+// it, and falls silent on a frequency of 0. That is what beep(1) does.
+//
+// The kernel does no timing, so the melody is rendered by the mml module
+// and played through the tone module, which holds each note and checks
+// the abort flag while it waits. Opening the device goes through the same
+// EBUSY retry as /dev/speaker.
+//
+// Events are plain `struct input_event` records, written one per write()
+// as evdev requires. Nothing here needs an ioctl, so the module builds on
+// every platform and the records can be checked by pointing the backend
+// at a regular file or a FIFO.
+
+use crate::error::SpeakerError;
+use crate::queue::{PlayRequest, Ticket};
+use crate::tone::{self, Beeper};
+use std::ffi::c_long;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::time::Duration;
+
+pub const DEFAULT_DEVICE: &str = "/dev/input/by-path/platform-pcspkr-event-spkr";
+
+// <linux/input-event-codes.h>
+pub const EV_SND: u16 = 0x12;
+pub const SND_TONE: u16 = 0x02;
+
+// sizeof(struct input_event): a struct timeval, which the kernel ignores
+// on write, then type, code and value.
+pub const EVENT_LEN: usize = 2 * std::mem::size_of::<c_long>() + 8;
+
+struct EvdevBeeper {
+    file: File,
+}
+
+impl EvdevBeeper {
+    fn open(device_path: &str) -> Result<Self, SpeakerError> {
+        let file = OpenOptions::new().write(true).open(device_path)?;
+        Ok(Self { file })
+    }
+}
+
+impl Beeper for EvdevBeeper {
+    fn set_tone(&mut self, freq_hz: u32, _hold: Duration) -> io::Result<()> {
+        let value = i32::try_from(freq_hz).unwrap_or(i32::MAX);
+        self.file.write_all(&input_event(EV_SND, SND_TONE, value))
+    }
+}
+
+fn input_event(kind: u16, code: u16, value: i32) -> [u8; EVENT_LEN] {
+    let mut event = [0; EVENT_LEN];
+    let (_time, rest) = event.split_at_mut(EVENT_LEN - 8);
+    rest[0..2].copy_from_slice(&kind.to_ne_bytes());
+    rest[2..4].copy_from_slice(&code.to_ne_bytes());
+    rest[4..8].copy_from_slice(&value.to_ne_bytes());
+    event
+}
+
+pub async fn play_melody(
+    req: &PlayRequest<'_>,
+    ticket: Ticket,
+    device_path: &str,
+) -> Result<u32, SpeakerError> {
+    let device_path = device_path.to_owned();
+    tone::play_melody(req, ticket, move || EvdevBeeper::open(&device_path)).await
+}
+
+// Readiness probe: can the device be opened for writing? Nothing is
+// written, so the speaker stays silent.
+pub fn probe(device_path: &str) -> Result<(), SpeakerError> {
+    match EvdevBeeper::open(device_path) {
+        Ok(_) | Err(SpeakerError::DeviceBusy) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tones_are_written_as_input_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("event-spkr");
+        std::fs::write(&path, "").unwrap();
+
+        let mut beeper = EvdevBeeper::open(path.to_str().unwrap()).unwrap();
+        beeper.set_tone(440, Duration::from_millis(100)).unwrap();
+        beeper.set_tone(0, Duration::ZERO).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 2 * EVENT_LEN);
+        let record = &bytes[..EVENT_LEN];
+        assert!(record[..EVENT_LEN - 8].iter().all(|&b| b == 0));
+        let tail = &record[EVENT_LEN - 8..];
+        assert_eq!(u16::from_ne_bytes([tail[0], tail[1]]), EV_SND);
+        assert_eq!(u16::from_ne_bytes([tail[2], tail[3]]), SND_TONE);
+        assert_eq!(i32::from_ne_bytes(tail[4..8].try_into().unwrap()), 440);
+        assert_eq!(&bytes[EVENT_LEN..], &input_event(EV_SND, SND_TONE, 0));
+    }
+}
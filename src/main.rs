@@ -1,40 +1,72 @@
 // FreeBSD speaker device network server. CLI entry point that selects an
 // output backend, initialises logging (stderr or syslog), optionally
-// daemonises, and starts the HTTP server. Two backends exist: the FreeBSD
-// /dev/speaker writer (always compiled), and a CPAL audio renderer (gated
-// behind the `cpal` Cargo feature, on by default). The --output flag chooses
-// the backend; in `auto` mode the device path is probed and CPAL is used as
-// fallback when available. Without the `cpal` feature, `auto` falls through
-// to freebsd-speaker and fails at startup if the device path is missing.
+// daemonises, and starts the HTTP server. Backends: the FreeBSD
+// /dev/speaker writer and the Linux pcspkr input-device beeper (always
+// compiled), and a CPAL audio renderer (gated behind the `cpal` Cargo
+// feature, on by default). The --output flag chooses the backend; in
+// `auto` mode --device is probed, then --pcspkr-device, and CPAL is used
+// as fallback when available. Without the `cpal` feature, `auto` falls
+// through to freebsd-speaker and fails at startup if neither device path
+// exists. --output=file and --output=null synthesise like CPAL but write
+// the audio to a file or nowhere, for hosts without sound hardware, and
+// --output=console sounds the Linux console beeper by ioctl; auto picks
+// none of these.
 // Backend-specific flags from the unselected backend are warned about, not
 // rejected. The --bind flag (parsed by the bind module) lists the listen
 // addresses; --port supplies the default port for entries that omit one.
+// The `render` subcommand writes a melody to a WAV file through the same
+// synthesiser as the CPAL backend and exits without serving anything.
 
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use daemonize::Daemonize;
-use log::{error, info};
-#[cfg(feature = "cpal")]
-use log::warn;
+use log::{error, info, warn};
+use std::io::{Read, Write};
 use std::process;
-#[cfg(feature = "cpal")]
 use std::sync::Arc;
 use std::time::Duration;
 use syslog::{BasicLogger, Facility, Formatter3164};
 
 #[cfg(feature = "cpal")]
-use spkrd::cpal_backend::{CpalBackend, CpalConfig, Waveform};
+use spkrd::cpal_backend::{CpalBackend, CpalConfig};
 use spkrd::bind;
-use spkrd::server::{self, Backend};
+use spkrd::console_beeper;
+use spkrd::file_backend::{FileBackend, FileConfig, FileFormat};
+use spkrd::linux_pcspkr;
+use spkrd::mml;
+use spkrd::server::{self, Backend, Config};
+use spkrd::speaker_profile::{SpeakerProfile, DEFAULT_PROFILE};
+use spkrd::synth::{Envelope, Sound, Waveform, Wavetable, DEFAULT_VOLUME};
+use spkrd::tunes::Tunes;
+use spkrd::tuning::{Tuning, DEFAULT_REFERENCE_HZ, DEFAULT_TUNING};
+use spkrd::wav;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
 enum OutputMode {
     Auto,
     FreebsdSpeaker,
+    LinuxPcspkr,
+    Console,
     #[cfg(feature = "cpal")]
     Cpal,
+    File,
+    Null,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum FileFormatArg {
+    Wav,
+    Pcm,
+}
+
+impl From<FileFormatArg> for FileFormat {
+    fn from(f: FileFormatArg) -> Self {
+        match f {
+            FileFormatArg::Wav => FileFormat::Wav,
+            FileFormatArg::Pcm => FileFormat::Pcm,
+        }
+    }
 }
 
-#[cfg(feature = "cpal")]
 #[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
 enum WaveformArg {
     Square,
@@ -42,10 +74,17 @@ enum WaveformArg {
     Sine,
     Triangle,
     Sawtooth,
+    #[value(name = "pulse-12")]
+    Pulse12,
+    #[value(name = "pulse-25")]
+    Pulse25,
+    Noise,
+    PeriodicNoise,
+    Fm,
+    Wavetable,
     PcSpeaker,
 }
 
-#[cfg(feature = "cpal")]
 impl From<WaveformArg> for Waveform {
     fn from(w: WaveformArg) -> Self {
         match w {
@@ -54,6 +93,12 @@ impl From<WaveformArg> for Waveform {
             WaveformArg::Sine => Waveform::Sine,
             WaveformArg::Triangle => Waveform::Triangle,
             WaveformArg::Sawtooth => Waveform::Sawtooth,
+            WaveformArg::Pulse12 => Waveform::Pulse12,
+            WaveformArg::Pulse25 => Waveform::Pulse25,
+            WaveformArg::Noise => Waveform::Noise,
+            WaveformArg::PeriodicNoise => Waveform::PeriodicNoise,
+            WaveformArg::Fm => Waveform::Fm,
+            WaveformArg::Wavetable => Waveform::Wavetable,
             WaveformArg::PcSpeaker => Waveform::PcSpeaker,
         }
     }
@@ -61,14 +106,17 @@ impl From<WaveformArg> for Waveform {
 
 #[cfg(feature = "cpal")]
 const OUTPUT_HELP: &str =
-    "Output backend: auto picks freebsd-speaker if --device exists, else cpal";
+    "Output backend: auto picks freebsd-speaker if --device exists, else linux-pcspkr if --pcspkr-device exists, else cpal";
 #[cfg(not(feature = "cpal"))]
 const OUTPUT_HELP: &str =
-    "Output backend: auto requires --device to exist (CPAL fallback not compiled in)";
+    "Output backend: auto requires --device or --pcspkr-device to exist (CPAL fallback not compiled in)";
 
 #[derive(Parser)]
 #[command(author, version, about = "FreeBSD speaker device network server", long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(short, long, default_value = "1111", help = "Default port for --bind entries that omit one")]
     port: u16,
 
@@ -92,6 +140,63 @@ struct Args {
     )]
     max_melody_length: usize,
 
+    #[arg(
+        long,
+        help = "Reject melodies that do not parse cleanly as MML (unrecognised characters, \
+                out-of-range arguments) with 400 instead of playing them"
+    )]
+    mml_strict: bool,
+
+    #[arg(
+        long,
+        help = "Accept the MML repeat extension ([...]n, |: and :|) in every melody, \
+                not only in requests with ?repeat=1"
+    )]
+    mml_repeat: bool,
+
+    #[arg(
+        long,
+        help = "Read every melody with the MML extended range (O0-O9 as standard octaves, \
+                N as MIDI notes, K transposition), not only requests with ?extended=1"
+    )]
+    mml_extended: bool,
+
+    #[arg(
+        long,
+        default_value_t = 600,
+        help = "Longest a melody may play once its repeats are expanded, in seconds"
+    )]
+    max_expanded_duration: u64,
+
+    #[arg(
+        long,
+        default_value_t = 16,
+        help = "Maximum number of requests waiting for the device behind the one playing; \
+                further requests get 503 immediately"
+    )]
+    queue_depth: usize,
+
+    #[arg(
+        long,
+        help = "File holding the bearer token POST /stop requires; without it anyone may stop playback"
+    )]
+    admin_token_file: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Directory of NAME.mml tunes to serve as PUT /play/NAME and list at GET /tunes; \
+                reread on SIGHUP and when its files change"
+    )]
+    tunes_dir: Option<String>,
+
+    #[arg(
+        long,
+        default_value_t = 10,
+        help = "Seconds a /readyz device probe result is reused before probing again"
+    )]
+    ready_interval: u64,
+
     #[arg(
         long,
         value_enum,
@@ -108,6 +213,26 @@ struct Args {
     )]
     device: String,
 
+    #[arg(
+        long,
+        help = "[freebsd-speaker] Play with per-note SPKRTONE ioctls from spkrd's MML interpreter instead of writing the melody to the device"
+    )]
+    spkrtone: bool,
+
+    #[arg(
+        long,
+        default_value = linux_pcspkr::DEFAULT_DEVICE,
+        help = "Path to the pcspkr input device (used by linux-pcspkr backend)"
+    )]
+    pcspkr_device: String,
+
+    #[arg(
+        long,
+        default_value = console_beeper::DEFAULT_DEVICE,
+        help = "Console whose beeper the console backend sounds"
+    )]
+    console_device: String,
+
     #[arg(long, help = "Run as daemon in background")]
     daemon: bool,
 
@@ -121,24 +246,96 @@ struct Args {
     #[arg(short = 'D', long, help = "Enable debug logging including client request details")]
     debug: bool,
 
-    // CPAL-only options (only present when built with the `cpal` feature).
-    #[cfg(feature = "cpal")]
+    // Synthesis options, shared by the backends that synthesise.
     #[arg(
         long,
         value_enum,
         default_value_t = WaveformArg::PcSpeaker,
-        help = "[cpal] waveform"
+        help = "[cpal, file] waveform"
     )]
     waveform: WaveformArg,
 
-    #[cfg(feature = "cpal")]
-    #[arg(long, default_value_t = 0.25, help = "[cpal] output volume in [0.0, 1.0]")]
+    #[arg(long, default_value_t = DEFAULT_VOLUME, help = "[cpal, file] output volume in [0.0, 1.0]")]
     volume: f32,
 
-    #[cfg(feature = "cpal")]
-    #[arg(long, help = "[cpal] sample rate in Hz; falls back to device default")]
+    #[arg(
+        long,
+        value_name = "A,D,S,R",
+        help = "[cpal, file] ADSR envelope: attack, decay and release in ms, sustain level in \
+                [0.0, 1.0] (e.g. 10,50,0.7,100); default a 5 ms click-free fade, none for square"
+    )]
+    envelope: Option<Envelope>,
+
+    #[arg(
+        long,
+        help = "[cpal, file] loudest volume a request may ask for with ?volume= \
+                (default: --volume)"
+    )]
+    max_volume: Option<f32>,
+
+    #[arg(
+        long,
+        help = "[cpal, file] single-cycle wavetable for --waveform wavetable and \
+                ?waveform=wavetable: a 16-bit mono WAV file, or numbers in [-1, 1]"
+    )]
+    wavetable: Option<String>,
+
+    #[arg(
+        long,
+        default_value = DEFAULT_PROFILE,
+        help = "[cpal, file] speaker the pc-speaker waveform emulates: piezo, cone (1980s \
+                PC case speaker), laptop, or a TOML file describing its filters"
+    )]
+    speaker_profile: String,
+
+    #[arg(
+        long,
+        default_value = DEFAULT_TUNING,
+        help = "[cpal, file] tuning: spkr (spkr.c's A440 table, rounded to whole Hz), equal, \
+                just, pythagorean, or a Scala .scl file"
+    )]
+    tuning: String,
+
+    #[arg(
+        long,
+        value_name = "HZ",
+        default_value_t = DEFAULT_REFERENCE_HZ,
+        help = "[cpal, file] frequency of A4 in Hz (220..=880), e.g. 432 or 415"
+    )]
+    reference_pitch: f64,
+
+    #[arg(
+        long,
+        help = "[cpal, file] time notes to the microsecond instead of spkr.c's whole centiseconds"
+    )]
+    precise_timing: bool,
+
+    #[arg(
+        long,
+        help = "[cpal, file] sample rate in Hz; cpal falls back to the device default, file to 48000"
+    )]
     sample_rate: Option<u32>,
 
+    // File/null-only options.
+    #[arg(long, help = "[file] file to append the audio to")]
+    output_file: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = FileFormatArg::Wav,
+        help = "[file] wav (one growing WAV file) or pcm (raw 16-bit little-endian mono, e.g. for a FIFO)"
+    )]
+    file_format: FileFormatArg,
+
+    #[arg(
+        long,
+        help = "[file, null] write the audio at the rate it would play, so melodies take as long as on a speaker"
+    )]
+    pace: bool,
+
+    // CPAL-only options (only present when built with the `cpal` feature).
+
     #[cfg(feature = "cpal")]
     #[arg(
         long,
@@ -152,6 +349,134 @@ struct Args {
     #[cfg(feature = "cpal")]
     #[arg(long, help = "[cpal] output device name; defaults to system default")]
     cpal_device: Option<String>,
+
+    #[cfg(feature = "cpal")]
+    #[arg(
+        long,
+        help = "[cpal] when a /readyz probe cannot open a stream, rebuild the device and probe again"
+    )]
+    ready_rebuild: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Render a melody to a WAV file instead of serving requests
+    Render(RenderArgs),
+}
+
+#[derive(clap::Args)]
+struct RenderArgs {
+    #[arg(help = "MML file to render; standard input if omitted or -")]
+    input: Option<String>,
+
+    #[arg(short, long, help = "WAV file to write, or - for standard output")]
+    out: String,
+
+    #[arg(long, value_enum, default_value_t = WaveformArg::PcSpeaker, help = "Waveform")]
+    waveform: WaveformArg,
+
+    #[arg(
+        long,
+        default_value_t = 48000,
+        help = "Sample rate in Hz (8000..=192000)"
+    )]
+    rate: u32,
+
+    #[arg(long, default_value_t = DEFAULT_VOLUME, help = "Volume in [0.0, 1.0]")]
+    volume: f32,
+
+    #[arg(long, value_name = "A,D,S,R", help = "ADSR envelope, as for the server's --envelope")]
+    envelope: Option<Envelope>,
+
+    #[arg(long, help = "Wavetable for --waveform wavetable, as for the server's --wavetable")]
+    wavetable: Option<String>,
+
+    #[arg(
+        long,
+        default_value = DEFAULT_PROFILE,
+        help = "Speaker for --waveform pc-speaker, as for the server's --speaker-profile"
+    )]
+    speaker_profile: String,
+
+    #[arg(long, default_value = DEFAULT_TUNING, help = "Tuning, as for the server's --tuning")]
+    tuning: String,
+
+    #[arg(
+        long,
+        value_name = "HZ",
+        default_value_t = DEFAULT_REFERENCE_HZ,
+        help = "Frequency of A4 in Hz, as for the server's --reference-pitch"
+    )]
+    reference_pitch: f64,
+
+    #[arg(long, help = "Time notes to the microsecond, as for the server's --precise-timing")]
+    precise_timing: bool,
+
+    #[arg(long, help = "Expand the MML repeat extension ([...]n, |: and :|)")]
+    repeat: bool,
+
+    #[arg(long, help = "Read the melody with the MML extended range, as for the server's --mml-extended")]
+    extended: bool,
+}
+
+// Read --wavetable, which --waveform wavetable cannot do without.
+fn load_wavetable(path: Option<&str>, waveform: WaveformArg) -> Result<Option<Wavetable>, String> {
+    match path {
+        Some(path) => Wavetable::load(path).map(Some),
+        None if waveform == WaveformArg::Wavetable => {
+            Err("--waveform wavetable requires --wavetable".to_string())
+        }
+        None => Ok(None),
+    }
+}
+
+// `spkrd render`: parse, synthesise and write one melody. Problems a
+// --mml-strict server would reject are reported on stderr, but the melody
+// is rendered the way it would play.
+fn render(args: &RenderArgs) -> Result<(), Box<dyn std::error::Error>> {
+    if !(wav::MIN_RATE..=wav::MAX_RATE).contains(&args.rate) {
+        return Err(format!(
+            "--rate {} is outside {}..={}",
+            args.rate,
+            wav::MIN_RATE,
+            wav::MAX_RATE
+        )
+        .into());
+    }
+
+    let input = args.input.as_deref().unwrap_or("-");
+    let mut melody = String::new();
+    if input == "-" {
+        std::io::stdin().read_to_string(&mut melody)?;
+    } else {
+        melody = std::fs::read_to_string(input).map_err(|e| format!("{}: {}", input, e))?;
+    }
+
+    let dialect = mml::Dialect {
+        repeats: args.repeat,
+        extended: args.extended,
+        ..Default::default()
+    };
+    let score = dialect.parse(&melody);
+    for d in &score.diagnostics {
+        eprintln!("spkrd: {}: {}", input, d);
+    }
+    let sound = Sound {
+        waveform: args.waveform.into(),
+        volume: args.volume.clamp(0.0, 1.0),
+        envelope: args.envelope,
+        wavetable: load_wavetable(args.wavetable.as_deref(), args.waveform)?,
+        speaker: SpeakerProfile::load(&args.speaker_profile)?,
+    };
+    let tuning = Tuning::load(&args.tuning, args.reference_pitch)?;
+    let voices = score.render_voices_with(&tuning, timing(args.precise_timing));
+    let bytes = wav::render(&voices, args.rate, &sound);
+    if args.out == "-" {
+        std::io::stdout().write_all(&bytes)?;
+    } else {
+        std::fs::write(&args.out, &bytes).map_err(|e| format!("{}: {}", args.out, e))?;
+    }
+    Ok(())
 }
 
 fn init_logging(daemon: bool, debug: bool) {
@@ -193,25 +518,25 @@ fn init_logging(daemon: bool, debug: bool) {
     }
 }
 
-// Resolve `auto` to a concrete mode by checking whether `device` exists.
-// With the `cpal` feature compiled in, a missing device falls back to CPAL.
-// Without it, `auto` always resolves to FreebsdSpeaker; main() then verifies
-// the device exists and fails at startup if it does not.
-fn resolve_output(mode: OutputMode, device: &str) -> OutputMode {
+// Resolve `auto` to a concrete mode by checking whether `device`, then
+// `pcspkr_device`, exists. With the `cpal` feature compiled in, when
+// neither does, it falls back to CPAL. Without it, it resolves to
+// FreebsdSpeaker; main() then fails at startup, as neither device exists.
+fn resolve_output(mode: OutputMode, device: &str, pcspkr_device: &str) -> OutputMode {
     match mode {
         OutputMode::Auto => {
-            let exists = std::fs::metadata(device).is_ok();
+            if std::fs::metadata(device).is_ok() {
+                return OutputMode::FreebsdSpeaker;
+            }
+            if std::fs::metadata(pcspkr_device).is_ok() {
+                return OutputMode::LinuxPcspkr;
+            }
             #[cfg(feature = "cpal")]
             {
-                if exists {
-                    OutputMode::FreebsdSpeaker
-                } else {
-                    OutputMode::Cpal
-                }
+                OutputMode::Cpal
             }
             #[cfg(not(feature = "cpal"))]
             {
-                let _ = exists;
                 OutputMode::FreebsdSpeaker
             }
         }
@@ -220,39 +545,107 @@ fn resolve_output(mode: OutputMode, device: &str) -> OutputMode {
 }
 
 fn warn_unused_flags(args: &Args, resolved: OutputMode, user_specified_output: bool) {
+    let synth_set = args.waveform != WaveformArg::PcSpeaker
+        || args.volume != DEFAULT_VOLUME
+        || args.envelope.is_some()
+        || args.max_volume.is_some()
+        || args.wavetable.is_some()
+        || args.speaker_profile != DEFAULT_PROFILE
+        || args.tuning != DEFAULT_TUNING
+        || args.reference_pitch != DEFAULT_REFERENCE_HZ
+        || args.precise_timing
+        || args.sample_rate.is_some();
+    #[cfg(feature = "cpal")]
+    let cpal_specific_set = args.cpal_host.is_some() || args.cpal_device.is_some() || args.ready_rebuild;
+    #[cfg(not(feature = "cpal"))]
+    let cpal_specific_set = false;
+    let file_specific_set = args.output_file.is_some() || args.file_format != FileFormatArg::Wav;
+
+    let mode = resolved
+        .to_possible_value()
+        .map_or(String::new(), |v| v.get_name().to_string());
+    let ignored = |flags: &str| warn!("{} are ignored under --output={}", flags, mode);
+    let synthesising = matches!(resolved, OutputMode::File | OutputMode::Null);
     #[cfg(feature = "cpal")]
-    let cpal_specific_set = args.waveform != WaveformArg::PcSpeaker
-        || args.volume != 0.25
-        || args.sample_rate.is_some()
-        || args.cpal_host.is_some()
-        || args.cpal_device.is_some();
-    let device_specific_set = args.device != "/dev/speaker";
+    let synthesising = synthesising || resolved == OutputMode::Cpal;
 
+    if synth_set && !synthesising {
+        ignored("Synthesis flags (--waveform/--volume/--envelope/--max-volume/--wavetable/--speaker-profile/--tuning/--reference-pitch/--precise-timing/--sample-rate)");
+    }
+    #[cfg(feature = "cpal")]
+    if cpal_specific_set && resolved != OutputMode::Cpal {
+        ignored("CPAL-specific flags (--cpal-host/--cpal-device/--ready-rebuild)");
+    }
+    #[cfg(not(feature = "cpal"))]
+    let _ = cpal_specific_set;
     match resolved {
-        OutputMode::FreebsdSpeaker => {
-            #[cfg(feature = "cpal")]
-            if cpal_specific_set {
-                warn!(
-                    "CPAL-specific flags (--waveform/--volume/--sample-rate/--cpal-host/--cpal-device) are ignored under --output=freebsd-speaker"
-                );
-            }
+        OutputMode::File => {}
+        OutputMode::Null if file_specific_set => ignored("--output-file/--file-format"),
+        OutputMode::Null => {}
+        _ if file_specific_set || args.pace => {
+            ignored("File-specific flags (--output-file/--file-format/--pace)")
         }
-        #[cfg(feature = "cpal")]
-        OutputMode::Cpal => {
-            if device_specific_set && user_specified_output {
-                warn!("--device is ignored under --output=cpal");
-            }
+        _ => {}
+    }
+
+    if args.spkrtone && resolved != OutputMode::FreebsdSpeaker {
+        warn!("--spkrtone is ignored under --output={}", mode);
+    }
+    if args.mml_extended && resolved == OutputMode::FreebsdSpeaker && !args.spkrtone {
+        warn!("--mml-extended refuses every melody under --output=freebsd-speaker without --spkrtone");
+    }
+
+    // Under auto, a device path that was set but not used is simply one
+    // that does not exist on this host, and not worth a warning.
+    let device_flags = [
+        (OutputMode::FreebsdSpeaker, "--device", args.device != "/dev/speaker"),
+        (
+            OutputMode::LinuxPcspkr,
+            "--pcspkr-device",
+            args.pcspkr_device != linux_pcspkr::DEFAULT_DEVICE,
+        ),
+        (
+            OutputMode::Console,
+            "--console-device",
+            args.console_device != console_beeper::DEFAULT_DEVICE,
+        ),
+    ];
+    for (owner, flag, set) in device_flags {
+        if set && owner != resolved && user_specified_output {
+            warn!("{} is ignored under --output={}", flag, mode);
         }
-        OutputMode::Auto => {}
     }
-    let _ = (user_specified_output, device_specific_set);
 }
 
-fn build_backend(args: &Args, resolved: OutputMode) -> Result<Backend, Box<dyn std::error::Error>> {
+// The timing --precise-timing asks for.
+fn timing(precise: bool) -> mml::Timing {
+    if precise {
+        mml::Timing::Precise
+    } else {
+        mml::Timing::Centisecs
+    }
+}
+
+fn build_backend(
+    args: &Args,
+    resolved: OutputMode,
+    wavetable: Option<Wavetable>,
+    speaker: SpeakerProfile,
+    tuning: Tuning,
+) -> Result<Backend, Box<dyn std::error::Error>> {
     match resolved {
+        OutputMode::FreebsdSpeaker if args.spkrtone => Ok(Backend::FreebsdSpkrtone {
+            device_path: args.device.clone(),
+        }),
         OutputMode::FreebsdSpeaker => Ok(Backend::FreebsdSpeaker {
             device_path: args.device.clone(),
         }),
+        OutputMode::LinuxPcspkr => Ok(Backend::LinuxPcspkr {
+            device_path: args.pcspkr_device.clone(),
+        }),
+        OutputMode::Console => Ok(Backend::Console {
+            device_path: args.console_device.clone(),
+        }),
         #[cfg(feature = "cpal")]
         OutputMode::Cpal => {
             let cfg = CpalConfig {
@@ -261,10 +654,36 @@ fn build_backend(args: &Args, resolved: OutputMode) -> Result<Backend, Box<dyn s
                 sample_rate: args.sample_rate,
                 volume: args.volume.clamp(0.0, 1.0),
                 waveform: args.waveform.into(),
+                envelope: args.envelope,
+                wavetable,
+                speaker,
+                tuning,
+                timing: timing(args.precise_timing),
             };
             let backend = CpalBackend::new(&cfg)?;
             Ok(Backend::Cpal(Arc::new(backend)))
         }
+        OutputMode::File | OutputMode::Null => {
+            // Both checked in main().
+            let path = match resolved {
+                OutputMode::File => args.output_file.clone(),
+                _ => None,
+            };
+            let cfg = FileConfig {
+                path,
+                format: args.file_format.into(),
+                sample_rate: args.sample_rate.unwrap_or(FILE_SAMPLE_RATE),
+                waveform: args.waveform.into(),
+                volume: args.volume.clamp(0.0, 1.0),
+                envelope: args.envelope,
+                wavetable,
+                speaker,
+                tuning,
+                timing: timing(args.precise_timing),
+                pace: args.pace,
+            };
+            Ok(Backend::File(Arc::new(FileBackend::new(cfg))))
+        }
         OutputMode::Auto => unreachable!("auto should be resolved before build_backend"),
     }
 }
@@ -274,9 +693,34 @@ fn build_backend(args: &Args, resolved: OutputMode) -> Result<Backend, Box<dyn s
 // to avoid plausible-misconfiguration OOMs.
 const MAX_MELODY_LENGTH_CEILING: usize = 1024 * 1024;
 
+// --output=file/null sample rate when --sample-rate is not given.
+const FILE_SAMPLE_RATE: u32 = 48000;
+
+// --output-file made absolute. The file itself need not exist yet, so its
+// directory is what gets canonicalised; a symlink or FIFO at the path is
+// kept as it is.
+fn absolute_output_file(path: &str) -> std::io::Result<String> {
+    let path = std::path::Path::new(path);
+    let name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a file name"))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    Ok(std::fs::canonicalize(dir)?.join(name).to_string_lossy().into_owned())
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
-    let retry_timeout = Duration::from_secs(args.retry_timeout);
+    let mut args = Args::parse();
+
+    if let Some(Command::Render(render_args)) = &args.command {
+        if let Err(e) = render(render_args) {
+            eprintln!("spkrd: render: {}", e);
+            process::exit(1);
+        }
+        return Ok(());
+    }
 
     if args.max_melody_length == 0 {
         eprintln!("spkrd: --max-melody-length must be at least 1");
@@ -298,40 +742,122 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
+    let wavetable = match load_wavetable(args.wavetable.as_deref(), args.waveform) {
+        Ok(wavetable) => wavetable,
+        Err(e) => {
+            eprintln!("spkrd: {}", e);
+            process::exit(1);
+        }
+    };
+    let speaker = match SpeakerProfile::load(&args.speaker_profile) {
+        Ok(speaker) => speaker,
+        Err(e) => {
+            eprintln!("spkrd: --speaker-profile {}", e);
+            process::exit(1);
+        }
+    };
+    let tuning = match Tuning::load(&args.tuning, args.reference_pitch) {
+        Ok(tuning) => tuning,
+        Err(e) => {
+            eprintln!("spkrd: --tuning {}", e);
+            process::exit(1);
+        }
+    };
+
+    if matches!(args.output, OutputMode::File | OutputMode::Null) {
+        if args.output == OutputMode::File && args.output_file.is_none() {
+            eprintln!("spkrd: --output=file requires --output-file");
+            process::exit(1);
+        }
+        // The file is reopened for every melody, after daemonising has
+        // moved to /, so a relative path is resolved now.
+        if let Some(path) = &args.output_file {
+            match absolute_output_file(path) {
+                Ok(absolute) => args.output_file = Some(absolute),
+                Err(e) => {
+                    eprintln!("spkrd: --output-file {}: {}", path, e);
+                    process::exit(1);
+                }
+            }
+        }
+        let sample_rate = args.sample_rate.unwrap_or(FILE_SAMPLE_RATE);
+        if !(wav::MIN_RATE..=wav::MAX_RATE).contains(&sample_rate) {
+            eprintln!(
+                "spkrd: --sample-rate {} is outside {}..={}",
+                sample_rate,
+                wav::MIN_RATE,
+                wav::MAX_RATE
+            );
+            process::exit(1);
+        }
+    }
+
+    // Read from a file rather than taken on the command line, where any
+    // local user could see it in ps(1).
+    let admin_token = match &args.admin_token_file {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(token) if !token.trim().is_empty() => Some(token.trim().to_string()),
+            Ok(_) => {
+                eprintln!("spkrd: --admin-token-file {} is empty", path);
+                process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("spkrd: cannot read --admin-token-file {}: {}", path, e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+
     init_logging(args.daemon, args.debug);
 
     // Track whether user explicitly chose --output (vs Auto default) for the
     // purposes of "ignored flag" warnings.
     let user_specified_output = args.output != OutputMode::Auto;
 
-    // Without the `cpal` feature there is no fallback backend, so a missing
-    // device path under --output=auto is a startup error rather than the
+    // Without the `cpal` feature there is no fallback backend, so missing
+    // device paths under --output=auto are a startup error rather than the
     // silent fall-through that the freebsd-speaker backend would otherwise
     // produce on each request.
     #[cfg(not(feature = "cpal"))]
     {
         if matches!(args.output, OutputMode::Auto)
             && std::fs::metadata(&args.device).is_err()
+            && std::fs::metadata(&args.pcspkr_device).is_err()
         {
             eprintln!(
-                "spkrd: --output=auto: device {:?} not found and the CPAL fallback is not compiled in. \
+                "spkrd: --output=auto: neither device {:?} nor {:?} found and the CPAL fallback is not compiled in. \
                  Rebuild with default features to enable CPAL, or pass --output=freebsd-speaker --device <path>.",
-                args.device
+                args.device, args.pcspkr_device
             );
             process::exit(1);
         }
     }
 
-    let resolved = resolve_output(args.output, &args.device);
+    let resolved = resolve_output(args.output, &args.device, &args.pcspkr_device);
+    // A request may not be louder than the server is configured to be
+    // unless --max-volume says so.
+    let max_volume = args.max_volume.unwrap_or(args.volume).clamp(0.0, 1.0);
 
     info!(
-        "Starting spkrd: bind={:?}, retry_timeout={}s, max_melody_length={}, output={:?} (resolved={:?}), device={}, daemon={}, pidfile={}, debug={}",
+        "Starting spkrd: bind={:?}, retry_timeout={}s, max_melody_length={}, mml_strict={}, mml_repeat={}, mml_extended={}, max_expanded_duration={}s, max_volume={}, queue_depth={}, admin_token={}, tunes_dir={}, output={:?} (resolved={:?}), device={}, spkrtone={}, pcspkr_device={}, console_device={}, daemon={}, pidfile={}, debug={}",
         bind_addrs,
         args.retry_timeout,
         args.max_melody_length,
+        args.mml_strict,
+        args.mml_repeat,
+        args.mml_extended,
+        args.max_expanded_duration,
+        max_volume,
+        args.queue_depth,
+        if admin_token.is_some() { "set" } else { "none" },
+        args.tunes_dir.as_deref().unwrap_or("none"),
         args.output,
         resolved,
         args.device,
+        args.spkrtone,
+        args.pcspkr_device,
+        args.console_device,
         args.daemon,
         args.pidfile,
         args.debug
@@ -339,7 +865,44 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     warn_unused_flags(&args, resolved, user_specified_output);
 
-    let backend = build_backend(&args, resolved)?;
+    let backend = build_backend(&args, resolved, wavetable, speaker, tuning)?;
+
+    // Loaded before daemonising, which moves to /, so a relative path
+    // still means what it did on the command line. Tunes are timed as the
+    // backend plays them, in the dialect every request gets.
+    let tunes_dialect = mml::Dialect {
+        repeats: args.mml_repeat,
+        extended: args.mml_extended,
+        ..mml::Dialect::default()
+    };
+    let tunes = match &args.tunes_dir {
+        Some(dir) => match Tunes::load(dir, args.max_melody_length, tunes_dialect, backend.timing()) {
+            Ok(tunes) => Some(Arc::new(tunes)),
+            Err(e) => {
+                eprintln!("spkrd: --tunes-dir {}", e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let config = Config {
+        retry_timeout: Duration::from_secs(args.retry_timeout),
+        max_melody_length: args.max_melody_length,
+        mml_strict: args.mml_strict,
+        mml_repeat: args.mml_repeat,
+        mml_extended: args.mml_extended,
+        max_expanded_duration: Duration::from_secs(args.max_expanded_duration),
+        max_volume,
+        queue_depth: args.queue_depth,
+        admin_token,
+        ready_interval: Duration::from_secs(args.ready_interval),
+        #[cfg(feature = "cpal")]
+        ready_rebuild: args.ready_rebuild,
+        #[cfg(not(feature = "cpal"))]
+        ready_rebuild: false,
+        tunes,
+        debug: args.debug,
+    };
 
     if args.daemon {
         let daemonize = Daemonize::new()
@@ -360,15 +923,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .map_err(|e| format!("Failed to create Tokio runtime: {}", e))?;
 
     runtime.block_on(async move {
-        match server::run(
-            bind_addrs,
-            retry_timeout,
-            backend,
-            args.max_melody_length,
-            args.debug,
-        )
-        .await
-        {
+        match server::run(bind_addrs, backend, config).await {
             Ok(_) => {
                 info!("Server shutdown completed");
                 Ok(())
@@ -0,0 +1,260 @@
+// Prometheus metrics, served as text by GET /metrics on the same listeners
+// as everything else. The set is small and fixed, so it is kept in atomics
+// and rendered by hand in the text exposition format rather than pulling
+// in a metrics framework.
+//
+// METRICS is a process-wide static because the events it counts happen
+// far apart — request outcomes in the server, queue waits and playback
+// times in the queue, device rebuilds and stream errors deep inside the
+// cpal backend's audio callbacks — and threading a handle through all of
+// them would buy nothing: there is one server per process.
+//
+// Metrics:
+//   spkrd_requests_total{outcome}        every /play and job, by result:
+//                                        "ok" or the SpeakerError variant
+//   spkrd_retries_total                  retries reported by successful plays
+//   spkrd_queue_wait_seconds             histogram: join to turn
+//   spkrd_playback_duration_seconds      histogram: turn to release
+//   spkrd_cpal_device_rebuilds_total{result}
+//   spkrd_cpal_stream_errors_total{class}  classify_error's buckets
+//   spkrd_queue_length                   gauge: playing plus waiting
+
+use crate::error::SpeakerError;
+use crate::queue::PlayQueue;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+pub static METRICS: Metrics = Metrics::new();
+
+// Label values of spkrd_requests_total, in SpeakerError declaration order.
+// The cpal ones are listed in every build so the series set does not
+// depend on features.
+const OUTCOMES: [&str; 9] = [
+    "ok",
+    "device_busy",
+    "queue_full",
+    "device_error",
+    "invalid_melody",
+    "timeout",
+    "interrupted",
+    "cpal_error",
+    "cpal_disconnect",
+];
+
+const STREAM_ERROR_CLASSES: [&str; 3] = ["continues", "disconnect", "fatal"];
+
+// Upper bounds in seconds. Queue waits are bounded by --retry-timeout
+// (30 s by default); melodies can run for minutes.
+const BUCKETS: [f64; 12] = [
+    0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+
+pub struct Histogram {
+    // Per-bucket counts (not cumulative); the last slot is +Inf.
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len() + 1],
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, d: Duration) {
+        let secs = d.as_secs_f64();
+        let i = BUCKETS.iter().position(|&b| secs <= b).unwrap_or(BUCKETS.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(d.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let le = BUCKETS.get(i).map_or("+Inf".to_string(), |b| b.to_string());
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, cumulative);
+    }
+}
+
+pub struct Metrics {
+    requests: [AtomicU64; OUTCOMES.len()],
+    retries: AtomicU64,
+    pub queue_wait: Histogram,
+    pub playback_duration: Histogram,
+    cpal_rebuilds_ok: AtomicU64,
+    cpal_rebuilds_failed: AtomicU64,
+    cpal_stream_errors: [AtomicU64; STREAM_ERROR_CLASSES.len()],
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Self {
+            requests: [const { AtomicU64::new(0) }; OUTCOMES.len()],
+            retries: AtomicU64::new(0),
+            queue_wait: Histogram::new(),
+            playback_duration: Histogram::new(),
+            cpal_rebuilds_ok: AtomicU64::new(0),
+            cpal_rebuilds_failed: AtomicU64::new(0),
+            cpal_stream_errors: [const { AtomicU64::new(0) }; STREAM_ERROR_CLASSES.len()],
+        }
+    }
+
+    // Count a finished request: its outcome and, on success, its retries.
+    pub fn request(&self, result: &Result<u32, SpeakerError>) {
+        match result {
+            Ok(retries) => {
+                self.retries.fetch_add(u64::from(*retries), Ordering::Relaxed);
+                self.count_outcome("ok");
+            }
+            Err(e) => self.request_failed(e),
+        }
+    }
+
+    pub fn request_failed(&self, err: &SpeakerError) {
+        self.count_outcome(match err {
+            SpeakerError::DeviceBusy => "device_busy",
+            SpeakerError::QueueFull => "queue_full",
+            SpeakerError::DeviceError(_) => "device_error",
+            SpeakerError::InvalidMelody(_) => "invalid_melody",
+            SpeakerError::Timeout => "timeout",
+            SpeakerError::Interrupted => "interrupted",
+            #[cfg(feature = "cpal")]
+            SpeakerError::CpalError(_) => "cpal_error",
+            #[cfg(feature = "cpal")]
+            SpeakerError::CpalDisconnect(_) => "cpal_disconnect",
+        });
+    }
+
+    fn count_outcome(&self, outcome: &str) {
+        let i = OUTCOMES.iter().position(|&o| o == outcome).unwrap();
+        self.requests[i].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cpal_rebuild(&self, ok: bool) {
+        let counter = if ok {
+            &self.cpal_rebuilds_ok
+        } else {
+            &self.cpal_rebuilds_failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // `class` is one of STREAM_ERROR_CLASSES.
+    pub fn cpal_stream_error(&self, class: &str) {
+        if let Some(i) = STREAM_ERROR_CLASSES.iter().position(|&c| c == class) {
+            self.cpal_stream_errors[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn render(&self, queue: &PlayQueue) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# HELP spkrd_requests_total Play requests and jobs by outcome.");
+        let _ = writeln!(out, "# TYPE spkrd_requests_total counter");
+        for (outcome, count) in OUTCOMES.iter().zip(&self.requests) {
+            let _ = writeln!(
+                out,
+                "spkrd_requests_total{{outcome=\"{}\"}} {}",
+                outcome,
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(out, "# HELP spkrd_retries_total Device-busy retries of successful plays.");
+        let _ = writeln!(out, "# TYPE spkrd_retries_total counter");
+        let _ = writeln!(out, "spkrd_retries_total {}", self.retries.load(Ordering::Relaxed));
+
+        self.queue_wait.render(
+            &mut out,
+            "spkrd_queue_wait_seconds",
+            "Time from joining the play queue to getting the device.",
+        );
+        self.playback_duration.render(
+            &mut out,
+            "spkrd_playback_duration_seconds",
+            "Time the device was held by one request.",
+        );
+
+        let _ = writeln!(out, "# HELP spkrd_cpal_device_rebuilds_total CPAL device rebuilds after a disconnect.");
+        let _ = writeln!(out, "# TYPE spkrd_cpal_device_rebuilds_total counter");
+        let _ = writeln!(
+            out,
+            "spkrd_cpal_device_rebuilds_total{{result=\"ok\"}} {}",
+            self.cpal_rebuilds_ok.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "spkrd_cpal_device_rebuilds_total{{result=\"error\"}} {}",
+            self.cpal_rebuilds_failed.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(out, "# HELP spkrd_cpal_stream_errors_total CPAL stream errors by classification.");
+        let _ = writeln!(out, "# TYPE spkrd_cpal_stream_errors_total counter");
+        for (class, count) in STREAM_ERROR_CLASSES.iter().zip(&self.cpal_stream_errors) {
+            let _ = writeln!(
+                out,
+                "spkrd_cpal_stream_errors_total{{class=\"{}\"}} {}",
+                class,
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(out, "# HELP spkrd_queue_length Requests playing or waiting to play.");
+        let _ = writeln!(out, "# TYPE spkrd_queue_length gauge");
+        let _ = writeln!(out, "spkrd_queue_length {}", queue.len());
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_counted_by_outcome() {
+        let metrics = Metrics::new();
+        metrics.request(&Ok(3));
+        metrics.request(&Ok(0));
+        metrics.request(&Err(SpeakerError::QueueFull));
+        let text = metrics.render(&PlayQueue::new(1));
+        assert!(text.contains("spkrd_requests_total{outcome=\"ok\"} 2\n"));
+        assert!(text.contains("spkrd_requests_total{outcome=\"queue_full\"} 1\n"));
+        assert!(text.contains("spkrd_requests_total{outcome=\"timeout\"} 0\n"));
+        assert!(text.contains("spkrd_retries_total 3\n"));
+        assert!(text.contains("spkrd_queue_length 0\n"));
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let metrics = Metrics::new();
+        metrics.queue_wait.observe(Duration::from_millis(5));
+        metrics.queue_wait.observe(Duration::from_millis(700));
+        metrics.queue_wait.observe(Duration::from_secs(600));
+        let text = metrics.render(&PlayQueue::new(1));
+        assert!(text.contains("spkrd_queue_wait_seconds_bucket{le=\"0.01\"} 1\n"));
+        assert!(text.contains("spkrd_queue_wait_seconds_bucket{le=\"0.5\"} 1\n"));
+        assert!(text.contains("spkrd_queue_wait_seconds_bucket{le=\"1\"} 2\n"));
+        assert!(text.contains("spkrd_queue_wait_seconds_bucket{le=\"300\"} 2\n"));
+        assert!(text.contains("spkrd_queue_wait_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("spkrd_queue_wait_seconds_sum 600.705\n"));
+        assert!(text.contains("spkrd_queue_wait_seconds_count 3\n"));
+    }
+}
@@ -13,12 +13,151 @@
 // same handling of accidentals, dotted notes, slur `_`, octave tracking
 // (OL/ON/O<n>/>/</), numeric notes (N<n>), rests (P/~), tempo (T),
 // length (L), and articulation (M[NLS]). Output is a sequence of
-// Tone/Rest events with frequencies in Hz and durations in centiseconds.
+// Tone/Rest events with frequencies in Hz and durations in microseconds,
+// whole centiseconds unless rendered with Timing::Precise. Tones also carry
+// the volume they are to be synthesised at.
+//
+// The pitch table is spkr.c's unless the caller renders through another
+// tuning::Tuning (the *_with methods), which the synthesisers do for
+// --tuning and --reference-pitch. Tones carry their frequency to the
+// millihertz for those, and rounded to whole hertz for the beepers.
+//
+// Timing is spkr.c's too by default: every length is worked out in whole
+// centiseconds with the kernel's integer divisions, so each note is cut a
+// little short and a fast or dotted passage drifts behind a metronome.
+// Timing::Precise, which the synthesisers use for --precise-timing, works
+// each length out as a fraction instead, keeps the voice's position to the
+// picosecond, and rounds only the event boundaries to the microsecond, so
+// the error never exceeds half a microsecond however long the melody. It
+// also gives a dotted note exactly its written length: spkr.c takes the
+// articulation gap off the undotted note but adds the dotted one, which
+// makes dotted notes that much longer.
+//
+// Parsing and rendering are split: parse() turns the melody into a typed
+// command list (Score) plus diagnostics for everything spkr.c would
+// silently skip or clamp — unrecognised bytes, out-of-range O/T/L/N
+// arguments, notes outside the pitch table — each located by byte span.
+// render() is built on the same tokenizer and interpreter and ignores the
+// diagnostics, so its output is unchanged by their existence. timeline()
+// is render() with each event tagged by the span of the command that
+// produced it.
+//
+// Polyphony is an extension spkr.c does not have (it skips `;` as junk):
+// `;` ends one voice and starts the next, as the channel strings of MSX
+// PLAY do. Each voice is interpreted on its own, from the default octave,
+// tempo, length and articulation, and render_voices() returns one event
+// list per voice for the synthesisers to mix. render() returns the first
+// voice only, for callers that can sound one tone at a time; the beeper
+// backends refuse a polyphonic melody before it gets that far.
+//
+// Volume is another extension, from MSX MML: `V<n>` sets it from 0
+// (silent) to 15 (the default, full --volume), and `)` and `(` step it one
+// level up or down, or n levels with `)n` and `(n`, for crescendos and
+// diminuendos written note by note. Each Tone carries the volume it was
+// played at; the synthesisers scale it, the PC speaker, which has one
+// loudness, ignores it, and split_commands() leaves the volume commands
+// out of what is written to /dev/speaker.
+//
+// Repeats are an opt-in extension (Dialect::repeats), off by default
+// because spkr.c skips `[`, `]`, `|` and `:` as junk and a melody that
+// happens to contain them must keep playing as the kernel plays it. With
+// it on, `[...]n` plays its contents n times in all (twice when n is
+// omitted) and nests; `:|` plays everything since the last `|:`, or since
+// the start of the voice or enclosing bracket, once more. Repeats are
+// expanded on the command list before interpretation, so octave, tempo
+// and length carry from one pass into the next exactly as if the section
+// had been written out, and every expanded command keeps the span it was
+// written at. Expansion is bounded: brackets nest at most MAX_REPEAT_DEPTH
+// deep, and a melody stops expanding at MAX_EXPANDED_COMMANDS commands,
+// which check_expansion() reports so a request can be refused rather than
+// played cut short.
+//
+// The extended range is another opt-in extension (Dialect::extended), for
+// backends that render melodies themselves and so are not held to the
+// pitch table. With it on, O numbers the standard octaves from O0 (C0,
+// 16 Hz) to O9, with O6, the kernel's O4, as the default, so a melody
+// without O commands sounds the same; N<n> is MIDI note n (N60 is middle
+// C, N0 still a rest); and `K<n>`, `K-<n>` transpose every later note by
+// n semitones until the next K. Pitches outside the table come from the
+// tuning's formula. Off, all of this is spkr.c's: seven octaves, N1 to N84
+// and K skipped as junk.
+//
+// A Transform (Dialect::transform) plays a melody in another key or at
+// another speed without touching its text: the interpreter moves every
+// note, N included, by its semitones, after octave tracking as K does,
+// and scales every tempo, the implicit T120 included. A scaled tempo may
+// leave T32..T255: only the kernel is limited to that. rewrite() does the
+// same for the kernel, which cannot be told about it: it writes the
+// melody out again with the notes as explicit O and N commands and the T
+// commands scaled, and refuses a melody a scaled T would take out of the
+// kernel's range rather than play it at another speed than was asked.
+
+use crate::tuning::Tuning;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Range;
+use std::time::Duration;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Event {
-    Tone { freq_hz: u32, centisecs: u32 },
-    Rest { centisecs: u32 },
+    // freq_mhz is the frequency in millihertz, freq_hz the same rounded to
+    // whole hertz; under the default tuning both are spkr.c's. micros is
+    // the duration, a multiple of MICROS_PER_CENTISEC under the default
+    // timing.
+    Tone { freq_hz: u32, freq_mhz: u32, micros: u32, volume: u8 },
+    Rest { micros: u32 },
+}
+
+impl Event {
+    pub fn tone(freq_mhz: u32, micros: u32, volume: u8) -> Self {
+        Event::Tone {
+            freq_hz: freq_mhz.saturating_add(500) / 1000,
+            freq_mhz,
+            micros,
+            volume,
+        }
+    }
+
+    pub fn micros(&self) -> u32 {
+        match *self {
+            Event::Tone { micros, .. } | Event::Rest { micros } => micros,
+        }
+    }
+
+    // The duration to the nearest centisecond, as /dev/speaker's ioctl
+    // takes it; exact under the default timing.
+    pub fn centisecs(&self) -> u32 {
+        (self.micros() + MICROS_PER_CENTISEC / 2) / MICROS_PER_CENTISEC
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_micros(u64::from(self.micros()))
+    }
+}
+
+// How an interpreter works out lengths: spkr.c's whole centiseconds, the
+// default, or exact fractions (see the module comment).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Timing {
+    #[default]
+    Centisecs,
+    Precise,
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timing::Centisecs => write!(f, "centisecs"),
+            Timing::Precise => write!(f, "precise"),
+        }
+    }
+}
+
+// Total playing time of an event sequence.
+pub fn duration(events: &[Event]) -> Duration {
+    events.iter().map(Event::duration).sum()
 }
 
 // FreeBSD spkr.c constants
@@ -38,12 +177,47 @@ const NUM_MULT: i32 = 3;
 const DENOM_MULT: i32 = 2;
 const OCTAVE_NOTES: i32 = 12;
 
+pub const MICROS_PER_CENTISEC: u32 = 10_000;
+
+// Precise timing (extension). A whole note lasts WHOLE_NOTE beats of
+// SECS_PER_MIN / tempo seconds, here in picoseconds. Past
+// MAX_PRECISE_DOTS dots, where spkr.c's lengths have long overflowed, a
+// note is already longer than an Event can hold.
+const PICOS_PER_MICRO: u128 = 1_000_000;
+const WHOLE_NOTE_PICOS: u128 = (SECS_PER_MIN * WHOLE_NOTE) as u128 * 1_000_000_000_000;
+const MAX_PRECISE_DOTS: i32 = 32;
+
+// V range (extension): MSX MML's, with the loudest as the default so
+// melodies without V play at full --volume.
+pub const MAX_VOLUME: u8 = 15;
+
+// Repeat limits (extension; see the module comment).
+pub const MAX_REPEAT_DEPTH: usize = 8;
+pub const MAX_REPEAT_COUNT: i32 = 255;
+pub const MAX_EXPANDED_COMMANDS: usize = 10_000;
+const DFLT_REPEAT_COUNT: i32 = 2;
+
+// Extended range (extension; see the module comment). Pitches are counted
+// from the table's first entry, C2, as they are in spkr.c, so the
+// extended range starts two octaves below it.
+const EXT_OCTAVES: i32 = 10;
+const EXT_DFLT_OCTAVE: i32 = 6;
+const EXT_OCTAVE_SHIFT: i32 = 2;
+const EXT_PITCHES: Range<i32> = -24..96;
+const MIDI_C2: i32 = 36;
+pub const MAX_MIDI_NOTE: i32 = 127;
+pub const MAX_TRANSPOSE: i32 = 48;
+
+// Transform::tempo_permille bounds: a quarter to four times the speed.
+pub const MIN_TEMPO_PERMILLE: u32 = 250;
+pub const MAX_TEMPO_PERMILLE: u32 = 4000;
+
 // Letter to half-tone offset:  A   B  C  D  E  F  G
 const NOTETAB: [i32; 7] = [9, 11, 0, 2, 4, 5, 7];
 
 // A440 equal-tempered, rounded to nearest integer; spkr.c's pitchtab.
-// Octave 0 here is standard octave 2.
-const PITCHTAB: [u32; 84] = [
+// Octave 0 here is standard octave 2. tuning::Tuning's default.
+pub(crate) const PITCHTAB: [u32; 84] = [
     65, 69, 73, 78, 82, 87, 93, 98, 103, 110, 117, 123,
     131, 139, 147, 156, 165, 175, 185, 196, 208, 220, 233, 247,
     262, 277, 294, 311, 330, 349, 370, 392, 415, 440, 466, 494,
@@ -54,256 +228,1226 @@ const PITCHTAB: [u32; 84] = [
 ];
 const PITCHTAB_OCTAVES: i32 = (PITCHTAB.len() as i32) / OCTAVE_NOTES; // 7
 
-struct State {
-    octave: i32,
-    whole: i32,
-    value: i32,
-    fill: i32,
-    octtrack: bool,
-    octprefix: bool,
-    events: Vec<Event>,
+// Severity of a parse diagnostic. Errors are input bytes the interpreter
+// does not understand at all and skips; warnings are recognised commands
+// whose argument was out of range and replaced by a default, or which had
+// no effect. Either way the melody plays differently from what was written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
 }
 
-impl State {
-    fn new() -> Self {
-        Self {
-            octave: DFLT_OCTAVE,
-            whole: (100 * SECS_PER_MIN * WHOLE_NOTE) / DFLT_TEMPO,
-            value: DFLT_VALUE,
-            fill: NORMAL,
-            octtrack: false,
-            octprefix: true,
-            events: Vec::new(),
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
         }
     }
+}
 
-    // Mirrors playtone() in spkr.c.
-    fn playtone(&mut self, pitch: i32, value: i32, sustain: i32) {
-        let mut snum: i32 = 1;
-        let mut sdenom: i32 = 1;
-        for _ in 0..sustain {
-            snum *= NUM_MULT;
-            sdenom *= DENOM_MULT;
-        }
+// A problem found while parsing, located by the byte span of the offending
+// command in the source melody (span.start is its byte offset).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub span: Range<usize>,
+    pub severity: Severity,
+    pub message: String,
+}
 
-        if value == 0 || sdenom == 0 {
-            return;
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "byte {}: {}: {}", self.span.start, self.severity, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Articulation {
+    Normal,
+    Legato,
+    Staccato,
+}
+
+impl Articulation {
+    fn fill(self) -> i32 {
+        match self {
+            Articulation::Normal => NORMAL,
+            Articulation::Legato => LEGATO,
+            Articulation::Staccato => STACCATO,
         }
+    }
+}
 
-        let whole = self.whole;
-        let fill = self.fill;
+// One MML command. Numeric arguments are kept as written (None when the
+// digits were omitted); range checks and defaulting happen at render time,
+// exactly where spkr.c applies them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    // A..G. `letter` is upper-case; `accidental` is -1 (`-`), 0 or +1
+    // (`#`/`+`); `dots` counts trailing `.`; `slur` is a trailing `_`.
+    Note {
+        letter: char,
+        accidental: i32,
+        length: Option<i32>,
+        dots: u32,
+        slur: bool,
+    },
+    // N<n>: absolute pitch, 1-based into the pitch table; N0 is a rest.
+    NoteNumber { number: i32, dots: u32, slur: bool },
+    // P<n> / ~<n>.
+    Rest { length: Option<i32>, dots: u32 },
+    // O<n>.
+    Octave(Option<i32>),
+    // OL (true) / ON (false).
+    OctaveTracking(bool),
+    OctaveUp,
+    OctaveDown,
+    // L<n>.
+    Length(Option<i32>),
+    // T<n>.
+    Tempo(Option<i32>),
+    // MN / ML / MS.
+    Articulation(Articulation),
+    // `;`: the end of one voice and the start of the next (extension).
+    Voice,
+    // V<n> (extension).
+    Volume(Option<i32>),
+    // `)<n>` / `(<n>`: n volume steps up (positive) or down (negative);
+    // one when the digits are omitted (extension).
+    VolumeStep(i32),
+    // `[` and `]<n>`: a section played n times in all (extension).
+    RepeatStart,
+    RepeatEnd(Option<i32>),
+    // `|:` and `:|`: repeat barlines (extension).
+    SectionStart,
+    SectionEnd,
+    // K<n> / K-<n>: transpose by n semitones; bare K is K0 (extension).
+    Transpose(i32),
+}
 
-        if pitch == -1 {
-            let cs = whole * snum / (value * sdenom);
-            if cs > 0 {
-                self.events.push(Event::Rest { centisecs: cs as u32 });
-            }
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub span: Range<usize>,
+    pub command: Command,
+}
+
+// Result of parse(): the command list, with any repeats expanded, plus
+// every diagnostic, sorted by source offset, and the dialect it is to be
+// interpreted in. Rendering ignores the diagnostics and reproduces
+// exactly what the kernel would play for the same input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Score {
+    pub nodes: Vec<Node>,
+    pub diagnostics: Vec<Diagnostic>,
+    pub dialect: Dialect,
+}
+
+impl Score {
+    // The first voice.
+    pub fn render(&self) -> Vec<Event> {
+        self.interpreter(&Tuning::default(), Timing::default())
+            .run(voices(&self.nodes)[0])
+    }
+
+    pub fn render_voices(&self) -> Vec<Vec<Event>> {
+        self.render_voices_with(&Tuning::default(), Timing::default())
+    }
+
+    // render_voices(), with the pitches `tuning` gives them and the
+    // lengths `timing` works out.
+    pub fn render_voices_with(&self, tuning: &Tuning, timing: Timing) -> Vec<Vec<Event>> {
+        voices(&self.nodes)
+            .into_iter()
+            .map(|voice| self.interpreter(tuning, timing).run(voice))
+            .collect()
+    }
+
+    // Every voice's events, voice by voice.
+    pub fn timeline(&self) -> Vec<SourcedEvent> {
+        self.timeline_with(&Tuning::default(), Timing::default())
+    }
+
+    pub fn timeline_with(&self, tuning: &Tuning, timing: Timing) -> Vec<SourcedEvent> {
+        voices(&self.nodes)
+            .into_iter()
+            .enumerate()
+            .flat_map(|(voice, nodes)| self.interpreter(tuning, timing).run_sourced(voice, nodes))
+            .collect()
+    }
+
+    fn interpreter<'a>(&self, tuning: &'a Tuning, timing: Timing) -> Interpreter<'a> {
+        Interpreter::new(self.dialect, None, tuning, timing)
+    }
+}
+
+// An event and the source bytes of the command that produced it. A note
+// yields a Tone and usually a Rest (its articulation gap), both with the
+// note's span. `voice` counts from 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourcedEvent {
+    pub voice: usize,
+    pub span: Range<usize>,
+    pub event: Event,
+}
+
+// The MML dialect a melody is read in: spkr.c's, plus polyphony, plus
+// whichever opt-in extensions are set, and the transform it is played
+// with. The module-level functions below use the default, which has no
+// extensions and the identity transform.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Dialect {
+    pub repeats: bool,
+    pub extended: bool,
+    pub transform: Transform,
+}
+
+// Semitones to move every note by, and the factor to scale every tempo
+// by, in thousandths (see the module comment).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transform {
+    pub transpose: i32,
+    pub tempo_permille: u32,
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform {
+        transpose: 0,
+        tempo_permille: 1000,
+    };
+
+    pub fn is_identity(self) -> bool {
+        self == Self::IDENTITY
+    }
+
+    // The tempo T<tempo> plays at, which may be outside T32..T255.
+    fn tempo(self, tempo: i32) -> i32 {
+        let scaled = (i64::from(tempo) * i64::from(self.tempo_permille) + 500) / 1000;
+        scaled.clamp(1, i64::from(i32::MAX)) as i32
+    }
+
+    // The tempo T<tempo> plays at, as the kernel can be told it.
+    fn kernel_tempo(self, tempo: i32) -> Result<i32, String> {
+        let scaled = self.tempo(tempo);
+        if (MIN_TEMPO..=MAX_TEMPO).contains(&scaled) {
+            Ok(scaled)
         } else {
-            let sound = (whole * snum) / (value * sdenom)
-                - (whole * (FILLTIME - fill)) / (value * FILLTIME);
-            let silence =
-                whole * (FILLTIME - fill) * snum / (FILLTIME * value * sdenom);
-            let freq = PITCHTAB[pitch as usize];
-            if sound > 0 {
-                self.events.push(Event::Tone {
-                    freq_hz: freq,
-                    centisecs: sound as u32,
-                });
+            Err(format!(
+                "Tempo {} makes T{} T{}, outside the kernel's {}..={}",
+                f64::from(self.tempo_permille) / 1000.0,
+                tempo,
+                scaled,
+                MIN_TEMPO,
+                MAX_TEMPO
+            ))
+        }
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl Dialect {
+    // Parse an MML melody into commands and diagnostics. Bytes spkr.c
+    // would skip become Error diagnostics (whitespace is skipped
+    // silently); out-of-range arguments and no-op commands become Warning
+    // diagnostics. A command inside a repeat is interpreted once per pass,
+    // but a diagnostic repeated word for word is reported once.
+    pub fn parse(self, melody: &str) -> Score {
+        let Scan { nodes, mut diagnostics, .. } = self.scan(melody);
+        let tuning = Tuning::default();
+        for voice in voices(&nodes) {
+            Interpreter::new(self, Some(&mut diagnostics), &tuning, Timing::default()).run(voice);
+        }
+        diagnostics.sort_by(|a, b| {
+            (a.span.start, a.span.end, &a.message).cmp(&(b.span.start, b.span.end, &b.message))
+        });
+        diagnostics.dedup();
+        Score {
+            nodes,
+            diagnostics,
+            dialect: self,
+        }
+    }
+
+    // Strict-mode validation: reject a melody carrying any diagnostic,
+    // warnings included, since both kinds make it play something other
+    // than what was written. The error lists every diagnostic, one per
+    // line, first offending byte first.
+    pub fn check_strict(self, melody: &str) -> Result<(), String> {
+        let score = self.parse(melody);
+        if score.diagnostics.is_empty() {
+            return Ok(());
+        }
+        Err(score
+            .diagnostics
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    // Fail if repeats expand the melody past MAX_EXPANDED_COMMANDS, in
+    // which case everything below would play it cut short.
+    pub fn check_expansion(self, melody: &str) -> Result<(), String> {
+        if self.scan(melody).overflow {
+            return Err(format!(
+                "Repeats expand the melody past {} commands",
+                MAX_EXPANDED_COMMANDS
+            ));
+        }
+        Ok(())
+    }
+
+    // Render an MML melody to events. Mirrors playstring() in spkr.c.
+    // Only the first voice of a polyphonic melody is rendered.
+    pub fn render(self, melody: &str) -> Vec<Event> {
+        self.score(melody).render()
+    }
+
+    // Render each voice of an MML melody to its own events.
+    pub fn render_voices(self, melody: &str) -> Vec<Vec<Event>> {
+        self.score(melody).render_voices()
+    }
+
+    pub fn render_voices_with(self, melody: &str, tuning: &Tuning, timing: Timing) -> Vec<Vec<Event>> {
+        self.score(melody).render_voices_with(tuning, timing)
+    }
+
+    // The number of voices: one more than the `;` separators.
+    pub fn voice_count(self, melody: &str) -> usize {
+        voices(&self.scan(melody).nodes).len()
+    }
+
+    // How long a melody plays: the length of its longest voice.
+    pub fn playing_time(self, melody: &str) -> Duration {
+        self.playing_time_with(melody, Timing::default())
+    }
+
+    // playing_time() with `timing`. The tuning only moves pitches, so the
+    // default one is as good as any.
+    pub fn playing_time_with(self, melody: &str, timing: Timing) -> Duration {
+        self.render_voices_with(melody, &Tuning::default(), timing)
+            .iter()
+            .map(|voice| duration(voice))
+            .max()
+            .unwrap_or_default()
+    }
+
+    // render_voices(), with each event's voice and source span.
+    pub fn timeline(self, melody: &str) -> Vec<SourcedEvent> {
+        self.score(melody).timeline()
+    }
+
+    // Cut the melody into pieces, one per command, that play the same
+    // written to /dev/speaker one after another as the whole does in a
+    // single write: spkr.c keeps octave, tempo, length and articulation
+    // across writes. Bytes that are not a command stay with the piece
+    // before them. Volume commands, which the kernel does not know, are
+    // left out. The exception is octave tracking, whose reference pitch is
+    // local to each write, so a melody using OL is returned as a single
+    // piece. Once repeats are expanded the pieces are no longer
+    // consecutive slices of the melody: each is the source of one expanded
+    // command, in playing order, and the bytes between commands, which the
+    // kernel would skip anyway, are dropped.
+    pub fn split_commands(self, melody: &str) -> Vec<Cow<'_, str>> {
+        let nodes = self.scan(melody).nodes;
+        if nodes.is_empty() {
+            return vec![Cow::Borrowed(melody)];
+        }
+        let mut pieces = Vec::new();
+        for (i, node) in nodes.iter().enumerate() {
+            if matches!(node.command, Command::Volume(_) | Command::VolumeStep(_)) {
+                continue;
             }
-            if fill != LEGATO && silence > 0 {
-                self.events.push(Event::Rest {
-                    centisecs: silence as u32,
-                });
+            let range = if self.repeats {
+                node.span.clone()
+            } else {
+                let start = if i == 0 { 0 } else { node.span.start };
+                start..nodes.get(i + 1).map_or(melody.len(), |n| n.span.start)
+            };
+            pieces.push(&melody[range]);
+        }
+        if nodes
+            .iter()
+            .any(|n| n.command == Command::OctaveTracking(true))
+        {
+            return vec![Cow::Owned(pieces.concat())];
+        }
+        pieces.into_iter().map(Cow::Borrowed).collect()
+    }
+
+    // The melody, transform applied, as spkr.c's MML, for the kernel to
+    // play (see the module comment). Only the first voice is written, with
+    // its repeats expanded, and only in the kernel's range; everything else
+    // is kept as written, bar the octave commands, which the explicit
+    // octaves make redundant, and whatever the kernel would skip. Fails if
+    // a scaled tempo is one the kernel cannot play.
+    pub fn rewrite(self, melody: &str) -> Result<String, String> {
+        const NOTES: [&str; 12] = ["c", "c#", "d", "d#", "e", "f", "f#", "g", "g#", "a", "a#", "b"];
+        let table = 0..PITCHTAB.len() as i32;
+        let nodes = self.scan(melody).nodes;
+        let tuning = Tuning::default();
+        let plain = Dialect {
+            transform: Transform::IDENTITY,
+            ..self
+        };
+        let mut interpreter = Interpreter::new(plain, None, &tuning, Timing::default());
+        let mut out = String::new();
+        if self.transform.tempo_permille != Transform::IDENTITY.tempo_permille {
+            out.push_str(&format!("t{}", self.transform.kernel_tempo(DFLT_TEMPO)?));
+        }
+        let mut octave = None;
+        let tail = |dots: u32, slur: bool| format!("{}{}", ".".repeat(dots as usize), if slur { "_" } else { "" });
+        for node in voices(&nodes)[0] {
+            interpreter.step(node);
+            match node.command {
+                Command::Note { length, dots, slur, .. } => {
+                    let pitch = interpreter.lastpitch + self.transform.transpose;
+                    if !table.contains(&pitch) {
+                        continue;
+                    }
+                    if octave != Some(pitch / OCTAVE_NOTES) {
+                        octave = Some(pitch / OCTAVE_NOTES);
+                        out.push_str(&format!("o{}", pitch / OCTAVE_NOTES));
+                    }
+                    out.push_str(NOTES[(pitch % OCTAVE_NOTES) as usize]);
+                    if let Some(length) = length {
+                        out.push_str(&length.to_string());
+                    }
+                    out.push_str(&tail(dots, slur));
+                }
+                Command::NoteNumber { number: 0, dots, slur } => {
+                    out.push_str(&format!("n0{}", tail(dots, slur)));
+                }
+                // Out-of-range numbers, up to i32::MAX, are dropped before
+                // any arithmetic.
+                Command::NoteNumber { number, dots, slur } if number <= PITCHTAB.len() as i32 => {
+                    let pitch = number - 1 + self.transform.transpose;
+                    if table.contains(&pitch) {
+                        out.push_str(&format!("n{}{}", pitch + 1, tail(dots, slur)));
+                    }
+                }
+                Command::NoteNumber { .. } => {}
+                Command::Tempo(_) => {
+                    out.push_str(&format!("t{}", self.transform.kernel_tempo(interpreter.tempo)?));
+                }
+                Command::Octave(_)
+                | Command::OctaveTracking(_)
+                | Command::OctaveUp
+                | Command::OctaveDown => {}
+                _ => out.push_str(&melody[node.span.clone()]),
             }
         }
+        Ok(out)
     }
+
+    fn score(self, melody: &str) -> Score {
+        Score {
+            nodes: self.scan(melody).nodes,
+            diagnostics: Vec::new(),
+            dialect: self,
+        }
+    }
+
+    fn scan(self, melody: &str) -> Scan {
+        let (nodes, mut diagnostics) = tokenize(melody, self);
+        if !self.repeats {
+            return Scan { nodes, diagnostics, overflow: false };
+        }
+        let (nodes, overflow) = expand(nodes, &mut diagnostics);
+        Scan { nodes, diagnostics, overflow }
+    }
+}
+
+// The tokenizer's output with repeats expanded. `overflow` is set when
+// the expansion was cut short at MAX_EXPANDED_COMMANDS.
+struct Scan {
+    nodes: Vec<Node>,
+    diagnostics: Vec<Diagnostic>,
+    overflow: bool,
+}
+
+// Dialect::parse() in the default dialect.
+pub fn parse(melody: &str) -> Score {
+    Dialect::default().parse(melody)
+}
+
+pub fn check_strict(melody: &str) -> Result<(), String> {
+    Dialect::default().check_strict(melody)
 }
 
-// Render an MML melody to events. Mirrors playstring() in spkr.c.
 pub fn render(melody: &str) -> Vec<Event> {
+    Dialect::default().render(melody)
+}
+
+pub fn render_voices(melody: &str) -> Vec<Vec<Event>> {
+    Dialect::default().render_voices(melody)
+}
+
+pub fn voice_count(melody: &str) -> usize {
+    Dialect::default().voice_count(melody)
+}
+
+pub fn playing_time(melody: &str) -> Duration {
+    Dialect::default().playing_time(melody)
+}
+
+pub fn timeline(melody: &str) -> Vec<SourcedEvent> {
+    Dialect::default().timeline(melody)
+}
+
+// The command list cut at each `;`. Always at least one voice, possibly
+// empty.
+fn voices(nodes: &[Node]) -> Vec<&[Node]> {
+    nodes.split(|n| n.command == Command::Voice).collect()
+}
+
+pub fn split_commands(melody: &str) -> Vec<Cow<'_, str>> {
+    Dialect::default().split_commands(melody)
+}
+
+// Split the melody into commands. Mirrors the byte-at-a-time scan of
+// playstring() in spkr.c, including its quirks: an `M` not followed by
+// N/L/S consumes nothing (so "MB" plays a B), and GETNUM only looks at the
+// bytes immediately following the command letter. Repeat commands are
+// only recognised in a dialect with repeats, and K in one with the
+// extended range; otherwise they are junk.
+fn tokenize(melody: &str, dialect: Dialect) -> (Vec<Node>, Vec<Diagnostic>) {
     let bytes = melody.as_bytes();
-    let mut st = State::new();
+    let mut nodes = Vec::new();
+    let mut diags = Vec::new();
     let mut i: usize = 0;
-    let mut lastpitch: i32 = OCTAVE_NOTES * DFLT_OCTAVE;
+    // Start of the current run of unrecognised characters, reported as a
+    // single diagnostic when the run ends.
+    let mut junk: Option<usize> = None;
 
     // Helper: ascii-uppercase a single byte (matches toupper on ascii).
     fn up(b: u8) -> u8 {
         b.to_ascii_uppercase()
     }
-    // GETNUM: while next byte is ascii digit, consume it into v.
-    fn getnum(bytes: &[u8], i: &mut usize) -> i32 {
-        let mut v: i32 = 0;
+    // GETNUM: while next byte is ascii digit, consume it into v. None when
+    // there are no digits (spkr.c then sees 0).
+    fn getnum(bytes: &[u8], i: &mut usize) -> Option<i32> {
+        let mut v: Option<i32> = None;
         while *i + 1 < bytes.len() && (bytes[*i + 1] as char).is_ascii_digit() {
             *i += 1;
-            v = v.saturating_mul(10).saturating_add((bytes[*i] - b'0') as i32);
+            let d = (bytes[*i] - b'0') as i32;
+            v = Some(v.unwrap_or(0).saturating_mul(10).saturating_add(d));
         }
         v
     }
+    fn dots(bytes: &[u8], i: &mut usize) -> u32 {
+        let mut n = 0;
+        while *i + 1 < bytes.len() && bytes[*i + 1] == b'.' {
+            *i += 1;
+            n += 1;
+        }
+        n
+    }
+    fn slur(bytes: &[u8], i: &mut usize) -> bool {
+        if *i + 1 < bytes.len() && bytes[*i + 1] == b'_' {
+            *i += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn flush_junk(melody: &str, junk: &mut Option<usize>, end: usize, diags: &mut Vec<Diagnostic>) {
+        if let Some(start) = junk.take() {
+            diags.push(Diagnostic {
+                span: start..end,
+                severity: Severity::Error,
+                message: format!("unrecognised input {:?} skipped", &melody[start..end]),
+            });
+        }
+    }
 
     while i < bytes.len() {
+        let start = i;
         let c = up(bytes[i]);
-        match c {
+        let command = match c {
             b'A'..=b'G' => {
-                let mut pitch =
-                    NOTETAB[(c - b'A') as usize] + st.octave * OCTAVE_NOTES;
-
+                let mut accidental = 0;
                 if i + 1 < bytes.len() {
                     let n = bytes[i + 1];
                     if n == b'#' || n == b'+' {
-                        pitch += 1;
+                        accidental = 1;
                         i += 1;
                     } else if n == b'-' {
-                        pitch -= 1;
+                        accidental = -1;
                         i += 1;
                     }
                 }
+                let length = getnum(bytes, &mut i);
+                let dots = dots(bytes, &mut i);
+                let slur = slur(bytes, &mut i);
+                Some(Command::Note {
+                    letter: c as char,
+                    accidental,
+                    length,
+                    dots,
+                    slur,
+                })
+            }
+            b'O' => {
+                let n = bytes.get(i + 1).map(|&b| up(b));
+                if n == Some(b'N') {
+                    i += 1;
+                    Some(Command::OctaveTracking(false))
+                } else if n == Some(b'L') {
+                    i += 1;
+                    Some(Command::OctaveTracking(true))
+                } else {
+                    Some(Command::Octave(getnum(bytes, &mut i)))
+                }
+            }
+            b'>' => Some(Command::OctaveUp),
+            b'<' => Some(Command::OctaveDown),
+            b'N' => {
+                let number = getnum(bytes, &mut i).unwrap_or(0);
+                let dots = dots(bytes, &mut i);
+                let slur = slur(bytes, &mut i);
+                Some(Command::NoteNumber { number, dots, slur })
+            }
+            b'L' => Some(Command::Length(getnum(bytes, &mut i))),
+            b'P' | b'~' => {
+                let length = getnum(bytes, &mut i);
+                let dots = dots(bytes, &mut i);
+                Some(Command::Rest { length, dots })
+            }
+            b'T' => Some(Command::Tempo(getnum(bytes, &mut i))),
+            b';' => Some(Command::Voice),
+            b'V' => Some(Command::Volume(getnum(bytes, &mut i))),
+            b')' => Some(Command::VolumeStep(getnum(bytes, &mut i).unwrap_or(1))),
+            b'(' => Some(Command::VolumeStep(-getnum(bytes, &mut i).unwrap_or(1))),
+            b'[' if dialect.repeats => Some(Command::RepeatStart),
+            b']' if dialect.repeats => Some(Command::RepeatEnd(getnum(bytes, &mut i))),
+            b'|' if dialect.repeats && bytes.get(i + 1) == Some(&b':') => {
+                i += 1;
+                Some(Command::SectionStart)
+            }
+            b':' if dialect.repeats && bytes.get(i + 1) == Some(&b'|') => {
+                i += 1;
+                Some(Command::SectionEnd)
+            }
+            b'K' if dialect.extended => {
+                let sign = match bytes.get(i + 1) {
+                    Some(b'-') => -1,
+                    _ => 1,
+                };
+                if matches!(bytes.get(i + 1), Some(b'-' | b'+')) {
+                    i += 1;
+                }
+                Some(Command::Transpose(sign * getnum(bytes, &mut i).unwrap_or(0)))
+            }
+            b'M' => {
+                let articulation = match bytes.get(i + 1).map(|&b| up(b)) {
+                    Some(b'N') => Some(Articulation::Normal),
+                    Some(b'L') => Some(Articulation::Legato),
+                    Some(b'S') => Some(Articulation::Staccato),
+                    _ => None,
+                };
+                match articulation {
+                    Some(a) => {
+                        i += 1;
+                        Some(Command::Articulation(a))
+                    }
+                    None => {
+                        flush_junk(melody, &mut junk, start, &mut diags);
+                        diags.push(Diagnostic {
+                            span: start..start + 1,
+                            severity: Severity::Warning,
+                            message: "M not followed by N, L or S; ignored".to_string(),
+                        });
+                        i += 1;
+                        continue;
+                    }
+                }
+            }
+            _ => None,
+        };
 
-                if st.octtrack && !st.octprefix {
-                    if (pitch - lastpitch).abs()
-                        > (pitch + OCTAVE_NOTES - lastpitch).abs()
+        match command {
+            Some(command) => {
+                flush_junk(melody, &mut junk, start, &mut diags);
+                nodes.push(Node {
+                    span: start..i + 1,
+                    command,
+                });
+                i += 1;
+            }
+            None => {
+                // Skip a whole character so multi-byte UTF-8 sequences are
+                // reported (and sliced) intact. Skipping is all spkr.c does
+                // with them too.
+                let ch = melody[i..].chars().next().unwrap_or(' ');
+                if ch.is_whitespace() {
+                    flush_junk(melody, &mut junk, start, &mut diags);
+                } else if junk.is_none() {
+                    junk = Some(start);
+                }
+                i += ch.len_utf8();
+            }
+        }
+    }
+    flush_junk(melody, &mut junk, bytes.len(), &mut diags);
+
+    (nodes, diags)
+}
+
+// Expand the repeat commands out of a token list. A `]` with no `[`, and
+// a `[` nested past MAX_REPEAT_DEPTH, are errors and ignored; a bracket
+// still open at a `;` or at the end is an error and played once, as if
+// closed there. Returns the expanded list and whether it was cut short at
+// MAX_EXPANDED_COMMANDS, which is also reported as an error.
+fn expand(nodes: Vec<Node>, diags: &mut Vec<Diagnostic>) -> (Vec<Node>, bool) {
+    // An open bracket (the outermost is the voice itself): where it was
+    // opened, what it holds so far, and where its current `|:` section
+    // starts.
+    struct Frame {
+        open: Range<usize>,
+        nodes: Vec<Node>,
+        section: usize,
+    }
+    fn error(diags: &mut Vec<Diagnostic>, span: &Range<usize>, message: String) {
+        diags.push(Diagnostic {
+            span: span.clone(),
+            severity: Severity::Error,
+            message,
+        });
+    }
+    // Expansion so far: whether it has hit the limit, and the diagnostics.
+    struct Out<'a> {
+        overflow: bool,
+        diags: &'a mut Vec<Diagnostic>,
+    }
+    // Append `times` copies of `nodes` to `to`, stopping at the limit, which
+    // is reported the first time it is hit, at `span`. Every frame is
+    // capped, so memory stays bounded however deep the repeats nest.
+    fn append(out: &mut Out, to: &mut Vec<Node>, nodes: &[Node], times: usize, span: &Range<usize>) {
+        for _ in 0..times {
+            let room = MAX_EXPANDED_COMMANDS - to.len();
+            if nodes.len() > room {
+                to.extend_from_slice(&nodes[..room]);
+                if !out.overflow {
+                    out.overflow = true;
+                    error(
+                        out.diags,
+                        span,
+                        format!("repeats expand past {} commands; cut short", MAX_EXPANDED_COMMANDS),
+                    );
+                }
+                return;
+            }
+            to.extend_from_slice(nodes);
+        }
+    }
+    fn close_all(out: &mut Out, stack: &mut Vec<Frame>) {
+        while stack.len() > 1 {
+            let frame = stack.pop().unwrap();
+            error(out.diags, &frame.open, "`[` without `]`; played once".to_string());
+            append(out, &mut stack.last_mut().unwrap().nodes, &frame.nodes, 1, &frame.open);
+        }
+    }
+
+    let mut stack = vec![Frame {
+        open: 0..0,
+        nodes: Vec::new(),
+        section: 0,
+    }];
+    let mut out = Out { overflow: false, diags };
+    for node in nodes {
+        match node.command {
+            Command::RepeatStart if stack.len() > MAX_REPEAT_DEPTH => error(
+                out.diags,
+                &node.span,
+                format!("repeats nested more than {} deep; `[` ignored", MAX_REPEAT_DEPTH),
+            ),
+            Command::RepeatStart => stack.push(Frame {
+                open: node.span,
+                nodes: Vec::new(),
+                section: 0,
+            }),
+            Command::RepeatEnd(_) if stack.len() == 1 => {
+                error(out.diags, &node.span, "`]` without `[`; ignored".to_string())
+            }
+            Command::RepeatEnd(count) => {
+                let times = match count {
+                    Some(n) if !(1..=MAX_REPEAT_COUNT).contains(&n) => {
+                        out.diags.push(Diagnostic {
+                            span: node.span.clone(),
+                            severity: Severity::Warning,
+                            message: format!(
+                                "repeat count {} out of range 1..={}, using {}",
+                                n, MAX_REPEAT_COUNT, DFLT_REPEAT_COUNT
+                            ),
+                        });
+                        DFLT_REPEAT_COUNT
+                    }
+                    Some(n) => n,
+                    None => DFLT_REPEAT_COUNT,
+                };
+                let frame = stack.pop().unwrap();
+                let parent = stack.last_mut().unwrap();
+                append(&mut out, &mut parent.nodes, &frame.nodes, times as usize, &node.span);
+            }
+            Command::SectionStart => {
+                let frame = stack.last_mut().unwrap();
+                frame.section = frame.nodes.len();
+            }
+            Command::SectionEnd => {
+                let frame = stack.last_mut().unwrap();
+                let section = frame.nodes[frame.section..].to_vec();
+                append(&mut out, &mut frame.nodes, &section, 1, &node.span);
+                frame.section = frame.nodes.len();
+            }
+            Command::Voice => {
+                close_all(&mut out, &mut stack);
+                let frame = stack.last_mut().unwrap();
+                append(&mut out, &mut frame.nodes, std::slice::from_ref(&node), 1, &node.span);
+                frame.section = frame.nodes.len();
+            }
+            _ => append(
+                &mut out,
+                &mut stack.last_mut().unwrap().nodes,
+                std::slice::from_ref(&node),
+                1,
+                &node.span,
+            ),
+        }
+    }
+    close_all(&mut out, &mut stack);
+    (stack.pop().unwrap().nodes, out.overflow)
+}
+
+// Interpreter state. Mirrors the file-scope statics of spkr.c.
+struct Interpreter<'a> {
+    octave: i32,
+    whole: i32,
+    tempo: i32,
+    value: i32,
+    fill: i32,
+    octtrack: bool,
+    octprefix: bool,
+    lastpitch: i32,
+    volume: i32,
+    // Semitones added to every note (extension).
+    transpose: i32,
+    transform: Transform,
+    events: Vec<Event>,
+    // When set, range fallbacks and dropped notes are reported here.
+    diags: Option<&'a mut Vec<Diagnostic>>,
+    tuning: &'a Tuning,
+    extended: bool,
+    timing: Timing,
+    // Under Timing::Precise, how far into the voice the events pushed so
+    // far reach, exactly, in picoseconds.
+    clock: u128,
+}
+
+impl<'a> Interpreter<'a> {
+    fn new(
+        dialect: Dialect,
+        diags: Option<&'a mut Vec<Diagnostic>>,
+        tuning: &'a Tuning,
+        timing: Timing,
+    ) -> Self {
+        let tempo = dialect.transform.tempo(DFLT_TEMPO);
+        Self {
+            octave: if dialect.extended { EXT_DFLT_OCTAVE } else { DFLT_OCTAVE },
+            whole: (100 * SECS_PER_MIN * WHOLE_NOTE) / tempo,
+            tempo,
+            value: DFLT_VALUE,
+            fill: NORMAL,
+            octtrack: false,
+            octprefix: true,
+            lastpitch: OCTAVE_NOTES * DFLT_OCTAVE,
+            volume: i32::from(MAX_VOLUME),
+            transpose: 0,
+            transform: dialect.transform,
+            events: Vec::new(),
+            diags,
+            tuning,
+            extended: dialect.extended,
+            timing,
+            clock: 0,
+        }
+    }
+
+    // The O range and default, how far O0 is below the table's octave 0,
+    // and the pitches that can be played: the table's, or the extended
+    // range's.
+    fn octaves(&self) -> (i32, i32, i32) {
+        if self.extended {
+            (EXT_OCTAVES, EXT_DFLT_OCTAVE, EXT_OCTAVE_SHIFT)
+        } else {
+            (PITCHTAB_OCTAVES, DFLT_OCTAVE, 0)
+        }
+    }
+
+    fn playable(&self, pitch: i32) -> bool {
+        if self.extended {
+            EXT_PITCHES.contains(&pitch)
+        } else {
+            (0..PITCHTAB.len() as i32).contains(&pitch)
+        }
+    }
+
+    fn warn(&mut self, span: &Range<usize>, message: String) {
+        if let Some(diags) = self.diags.as_deref_mut() {
+            diags.push(Diagnostic {
+                span: span.clone(),
+                severity: Severity::Warning,
+                message,
+            });
+        }
+    }
+
+    // Resolve an explicit note/rest length, falling back to the current L
+    // value as spkr.c does for 0 or anything past MIN_VALUE.
+    fn timeval(&mut self, span: &Range<usize>, length: Option<i32>) -> i32 {
+        match length {
+            Some(v) if v <= 0 || v > MIN_VALUE => {
+                let value = self.value;
+                self.warn(
+                    span,
+                    format!(
+                        "length {} out of range 1..={}, using {}",
+                        v, MIN_VALUE, value
+                    ),
+                );
+                value
+            }
+            Some(v) => v,
+            None => self.value,
+        }
+    }
+
+    fn run(mut self, nodes: &[Node]) -> Vec<Event> {
+        for node in nodes {
+            self.step(node);
+        }
+        self.events
+    }
+
+    fn run_sourced(mut self, voice: usize, nodes: &[Node]) -> Vec<SourcedEvent> {
+        let mut spans = Vec::new();
+        for node in nodes {
+            self.step(node);
+            spans.resize(self.events.len(), node.span.clone());
+        }
+        spans
+            .into_iter()
+            .zip(self.events)
+            .map(|(span, event)| SourcedEvent { voice, span, event })
+            .collect()
+    }
+
+    fn step(&mut self, node: &Node) {
+        let span = &node.span;
+        match node.command {
+            Command::Note {
+                letter,
+                accidental,
+                length,
+                dots,
+                slur,
+            } => {
+                let (_, _, shift) = self.octaves();
+                let mut pitch = NOTETAB[(letter as u8 - b'A') as usize]
+                    + (self.octave - shift) * OCTAVE_NOTES
+                    + accidental;
+
+                if self.octtrack && !self.octprefix {
+                    if (pitch - self.lastpitch).abs()
+                        > (pitch + OCTAVE_NOTES - self.lastpitch).abs()
                     {
-                        st.octave += 1;
+                        self.octave += 1;
                         pitch += OCTAVE_NOTES;
                     }
-                    if (pitch - lastpitch).abs()
-                        > ((pitch - OCTAVE_NOTES) - lastpitch).abs()
+                    if (pitch - self.lastpitch).abs()
+                        > ((pitch - OCTAVE_NOTES) - self.lastpitch).abs()
                     {
-                        st.octave -= 1;
+                        self.octave -= 1;
                         pitch -= OCTAVE_NOTES;
                     }
                 }
-                st.octprefix = false;
-                lastpitch = pitch;
+                self.octprefix = false;
+                self.lastpitch = pitch;
 
-                let mut timeval = getnum(bytes, &mut i);
-                if timeval <= 0 || timeval > MIN_VALUE {
-                    timeval = st.value;
-                }
+                let timeval = self.timeval(span, length);
 
-                let mut sustain = 0;
-                while i + 1 < bytes.len() && bytes[i + 1] == b'.' {
-                    i += 1;
-                    sustain += 1;
-                }
-
-                let oldfill = st.fill;
-                if i + 1 < bytes.len() && bytes[i + 1] == b'_' {
-                    st.fill = LEGATO;
-                    i += 1;
+                let oldfill = self.fill;
+                if slur {
+                    self.fill = LEGATO;
                 }
 
                 // Bounds-check pitch against pitchtab length, matching the
                 // implicit array access in the C code (which would index out
                 // of bounds for very high notes); we clamp to avoid panics.
-                if (0..PITCHTAB.len() as i32).contains(&pitch) {
-                    st.playtone(pitch, timeval, sustain);
+                // Octave tracking follows the notes as written, before any
+                // transposition.
+                let pitch = pitch + self.transpose + self.transform.transpose;
+                if self.playable(pitch) {
+                    self.playtone(Some(pitch), timeval, dots as i32);
+                } else {
+                    self.warn(span, "note outside the playable range; skipped".to_string());
                 }
 
-                st.fill = oldfill;
+                self.fill = oldfill;
             }
-            b'O' => {
-                if i + 1 < bytes.len() {
-                    let n = up(bytes[i + 1]);
-                    if n == b'N' {
-                        st.octprefix = false;
-                        st.octtrack = false;
-                        i += 1;
-                    } else if n == b'L' {
-                        st.octtrack = true;
-                        i += 1;
-                    } else {
-                        let v = getnum(bytes, &mut i);
-                        st.octave = if v >= PITCHTAB_OCTAVES {
-                            DFLT_OCTAVE
-                        } else {
-                            v
-                        };
-                        st.octprefix = true;
-                    }
+            Command::OctaveTracking(on) => {
+                if on {
+                    self.octtrack = true;
                 } else {
-                    let v = getnum(bytes, &mut i);
-                    st.octave = if v >= PITCHTAB_OCTAVES {
-                        DFLT_OCTAVE
-                    } else {
-                        v
-                    };
-                    st.octprefix = true;
+                    self.octprefix = false;
+                    self.octtrack = false;
                 }
             }
-            b'>' => {
-                if st.octave < PITCHTAB_OCTAVES - 1 {
-                    st.octave += 1;
-                }
-                st.octprefix = true;
+            Command::Octave(v) => {
+                let v = v.unwrap_or(0);
+                let (octaves, default, _) = self.octaves();
+                self.octave = if v >= octaves {
+                    self.warn(
+                        span,
+                        format!("O{} out of range 0..={}, using {}", v, octaves - 1, default),
+                    );
+                    default
+                } else {
+                    v
+                };
+                self.octprefix = true;
             }
-            b'<' => {
-                if st.octave > 0 {
-                    st.octave -= 1;
+            Command::OctaveUp => {
+                if self.octave < self.octaves().0 - 1 {
+                    self.octave += 1;
+                } else {
+                    self.warn(span, "already at the highest octave; ignored".to_string());
                 }
-                st.octprefix = true;
+                self.octprefix = true;
             }
-            b'N' => {
-                let pitch = getnum(bytes, &mut i);
-                let mut sustain = 0;
-                while i + 1 < bytes.len() && bytes[i + 1] == b'.' {
-                    i += 1;
-                    sustain += 1;
+            Command::OctaveDown => {
+                if self.octave > 0 {
+                    self.octave -= 1;
+                } else {
+                    self.warn(span, "already at the lowest octave; ignored".to_string());
                 }
-                let oldfill = st.fill;
-                if i + 1 < bytes.len() && bytes[i + 1] == b'_' {
-                    st.fill = LEGATO;
-                    i += 1;
+                self.octprefix = true;
+            }
+            Command::NoteNumber { number, dots, slur } => {
+                let oldfill = self.fill;
+                if slur {
+                    self.fill = LEGATO;
                 }
-                let p = pitch - 1;
-                if p == -1 || (0..PITCHTAB.len() as i32).contains(&p) {
-                    st.playtone(p, st.value, sustain);
+                // N0 rests. Otherwise the table is indexed from N1, and
+                // the extended range by MIDI note number. The number is
+                // checked before any arithmetic: getnum saturates, so it
+                // may be as large as i32::MAX.
+                let max = if self.extended { MAX_MIDI_NOTE } else { PITCHTAB.len() as i32 };
+                if number == 0 {
+                    self.playtone(None, self.value, dots as i32);
+                } else if number > max {
+                    self.warn(span, format!("N{} out of range 0..={}; skipped", number, max));
+                } else {
+                    let pitch = if self.extended {
+                        number - MIDI_C2 + self.transpose
+                    } else {
+                        number - 1
+                    } + self.transform.transpose;
+                    if self.playable(pitch) {
+                        self.playtone(Some(pitch), self.value, dots as i32);
+                    } else {
+                        self.warn(span, "note outside the playable range; skipped".to_string());
+                    }
                 }
-                st.fill = oldfill;
+                self.fill = oldfill;
             }
-            b'L' => {
-                let v = getnum(bytes, &mut i);
-                st.value = if v <= 0 || v > MIN_VALUE { DFLT_VALUE } else { v };
+            Command::Length(v) => {
+                self.value = match v {
+                    Some(v) if v <= 0 || v > MIN_VALUE => {
+                        self.warn(
+                            span,
+                            format!(
+                                "L{} out of range 1..={}, using {}",
+                                v, MIN_VALUE, DFLT_VALUE
+                            ),
+                        );
+                        DFLT_VALUE
+                    }
+                    Some(v) => v,
+                    None => DFLT_VALUE,
+                };
             }
-            b'P' | b'~' => {
-                let mut timeval = getnum(bytes, &mut i);
-                if timeval <= 0 || timeval > MIN_VALUE {
-                    timeval = st.value;
-                }
-                let mut sustain = 0;
-                while i + 1 < bytes.len() && bytes[i + 1] == b'.' {
-                    i += 1;
-                    sustain += 1;
+            Command::Rest { length, dots } => {
+                let timeval = self.timeval(span, length);
+                self.playtone(None, timeval, dots as i32);
+            }
+            Command::Tempo(v) => {
+                let tempo = match v {
+                    Some(v) if !(MIN_TEMPO..=MAX_TEMPO).contains(&v) => {
+                        self.warn(
+                            span,
+                            format!(
+                                "T{} out of range {}..={}, using {}",
+                                v, MIN_TEMPO, MAX_TEMPO, DFLT_TEMPO
+                            ),
+                        );
+                        DFLT_TEMPO
+                    }
+                    Some(v) => v,
+                    None => DFLT_TEMPO,
+                };
+                let tempo = self.transform.tempo(tempo);
+                self.whole = (100 * SECS_PER_MIN * WHOLE_NOTE) / tempo;
+                self.tempo = tempo;
+            }
+            Command::Articulation(a) => {
+                self.fill = a.fill();
+            }
+            Command::Volume(v) => {
+                let max = i32::from(MAX_VOLUME);
+                self.volume = match v {
+                    Some(v) if v > max => {
+                        self.warn(span, format!("V{} out of range 0..={}, using {}", v, max, max));
+                        max
+                    }
+                    Some(v) => v,
+                    None => max,
+                };
+            }
+            Command::VolumeStep(steps) => {
+                let max = i32::from(MAX_VOLUME);
+                let volume = self.volume.saturating_add(steps);
+                if !(0..=max).contains(&volume) {
+                    self.warn(span, format!("volume stepped outside 0..={}, kept at the limit", max));
                 }
-                st.playtone(-1, timeval, sustain);
+                self.volume = volume.clamp(0, max);
             }
-            b'T' => {
-                let v = getnum(bytes, &mut i);
-                let tempo = if !(MIN_TEMPO..=MAX_TEMPO).contains(&v) {
-                    DFLT_TEMPO
+            Command::Transpose(semitones) => {
+                self.transpose = if semitones.abs() > MAX_TRANSPOSE {
+                    self.warn(
+                        span,
+                        format!(
+                            "K{} out of range {}..={}, using 0",
+                            semitones, -MAX_TRANSPOSE, MAX_TRANSPOSE
+                        ),
+                    );
+                    0
                 } else {
-                    v
+                    semitones
                 };
-                st.whole = (100 * SECS_PER_MIN * WHOLE_NOTE) / tempo;
             }
-            b'M' if i + 1 < bytes.len() => {
-                let n = up(bytes[i + 1]);
-                if n == b'N' {
-                    st.fill = NORMAL;
-                    i += 1;
-                } else if n == b'L' {
-                    st.fill = LEGATO;
-                    i += 1;
-                } else if n == b'S' {
-                    st.fill = STACCATO;
-                    i += 1;
+            // Voices are cut apart, and repeats expanded, before they are
+            // interpreted.
+            Command::Voice
+            | Command::RepeatStart
+            | Command::RepeatEnd(_)
+            | Command::SectionStart
+            | Command::SectionEnd => {}
+        }
+    }
+
+    // Mirrors playtone() in spkr.c, where a pitch of -1 is a rest; here it
+    // is None, as the extended range has pitches below 0.
+    fn playtone(&mut self, pitch: Option<i32>, value: i32, sustain: i32) {
+        if self.timing == Timing::Precise && value > 0 {
+            self.playtone_precise(pitch, value, sustain);
+            return;
+        }
+
+        let mut snum: i32 = 1;
+        let mut sdenom: i32 = 1;
+        for _ in 0..sustain {
+            snum *= NUM_MULT;
+            sdenom *= DENOM_MULT;
+        }
+
+        if value == 0 || sdenom == 0 {
+            return;
+        }
+
+        let whole = self.whole;
+        let fill = self.fill;
+        let micros = |cs: i32| cs as u32 * MICROS_PER_CENTISEC;
+
+        match pitch {
+            None => {
+                let cs = whole * snum / (value * sdenom);
+                if cs > 0 {
+                    self.events.push(Event::Rest { micros: micros(cs) });
+                }
+            }
+            Some(pitch) => {
+                let sound = (whole * snum) / (value * sdenom)
+                    - (whole * (FILLTIME - fill)) / (value * FILLTIME);
+                let silence =
+                    whole * (FILLTIME - fill) * snum / (FILLTIME * value * sdenom);
+                if sound > 0 {
+                    self.events.push(Event::tone(
+                        self.tuning.freq_mhz(pitch),
+                        micros(sound),
+                        self.volume as u8,
+                    ));
+                }
+                if fill != LEGATO && silence > 0 {
+                    self.events.push(Event::Rest {
+                        micros: micros(silence),
+                    });
+                }
+            }
+        }
+    }
+
+    // playtone() under Timing::Precise: the note lasts exactly its value,
+    // dots included, of which the articulation gap is the same fraction
+    // as spkr.c's. Each length is rounded to the picosecond; the events
+    // span the microseconds between the rounded ends of each part.
+    fn playtone_precise(&mut self, pitch: Option<i32>, value: i32, sustain: i32) {
+        let dots = sustain.min(MAX_PRECISE_DOTS) as u32;
+        let num = WHOLE_NOTE_PICOS * 3u128.pow(dots);
+        let den = self.tempo as u128 * value as u128 * 2u128.pow(dots);
+        let length = (num + den / 2) / den;
+        let gap = (FILLTIME - self.fill) as u128;
+        let silence = (length * gap + FILLTIME as u128 / 2) / FILLTIME as u128;
+
+        match pitch {
+            None => {
+                let micros = self.advance(length);
+                if micros > 0 {
+                    self.events.push(Event::Rest { micros });
+                }
+            }
+            Some(pitch) => {
+                let micros = self.advance(length - silence);
+                if micros > 0 {
+                    self.events.push(Event::tone(
+                        self.tuning.freq_mhz(pitch),
+                        micros,
+                        self.volume as u8,
+                    ));
+                }
+                let micros = self.advance(silence);
+                if micros > 0 {
+                    self.events.push(Event::Rest { micros });
                 }
             }
-            _ => {}
         }
-        i += 1;
     }
 
-    st.events
+    // Move the clock on by `picos` and return the microseconds between
+    // where it was and where it is now, each rounded to the microsecond.
+    // A part that comes out as 0 pushes no event; the time it did take
+    // still counts towards the next boundary.
+    fn advance(&mut self, picos: u128) -> u32 {
+        let round = |picos: u128| (picos + PICOS_PER_MICRO / 2) / PICOS_PER_MICRO;
+        let start = round(self.clock);
+        self.clock += picos;
+        u32::try_from(round(self.clock) - start).unwrap_or(u32::MAX)
+    }
 }
 
 #[cfg(test)]
@@ -326,8 +1470,8 @@ mod tests {
         assert_eq!(
             ev,
             vec![
-                Event::Tone { freq_hz: 1047, centisecs: 44 },
-                Event::Rest { centisecs: 6 },
+                Event::tone(1_047_000, 440_000, 15),
+                Event::Rest { micros: 60_000 },
             ]
         );
     }
@@ -338,9 +1482,9 @@ mod tests {
         let ev = render("MLc");
         assert_eq!(ev.len(), 1);
         match ev[0] {
-            Event::Tone { freq_hz: 1047, centisecs } => {
+            Event::Tone { freq_hz: 1047, micros, .. } => {
                 // sound = 200/4 - 200*(8-8)/(4*8) = 50
-                assert_eq!(centisecs, 50);
+                assert_eq!(micros, 500_000);
             }
             _ => panic!("expected tone"),
         }
@@ -354,8 +1498,8 @@ mod tests {
         assert_eq!(
             ev,
             vec![
-                Event::Tone { freq_hz: 1047, centisecs: 38 },
-                Event::Rest { centisecs: 12 },
+                Event::tone(1_047_000, 380_000, 15),
+                Event::Rest { micros: 120_000 },
             ]
         );
     }
@@ -364,7 +1508,8 @@ mod tests {
     fn rest_default() {
         // P uses value=4 → cs = 200/4 = 50.
         let ev = render("p");
-        assert_eq!(ev, vec![Event::Rest { centisecs: 50 }]);
+        assert_eq!(ev, vec![Event::Rest { micros: 500_000 }]);
+        assert_eq!(duration(&ev), Duration::from_millis(500));
     }
 
     #[test]
@@ -375,8 +1520,8 @@ mod tests {
         assert_eq!(
             ev,
             vec![
-                Event::Tone { freq_hz: 1047, centisecs: 69 },
-                Event::Rest { centisecs: 9 },
+                Event::tone(1_047_000, 690_000, 15),
+                Event::Rest { micros: 90_000 },
             ]
         );
     }
@@ -399,8 +1544,8 @@ mod tests {
         assert_eq!(
             ev,
             vec![
-                Event::Tone { freq_hz: 1047, centisecs: 22 },
-                Event::Rest { centisecs: 3 },
+                Event::tone(1_047_000, 220_000, 15),
+                Event::Rest { micros: 30_000 },
             ]
         );
     }
@@ -412,8 +1557,8 @@ mod tests {
         assert_eq!(
             ev,
             vec![
-                Event::Tone { freq_hz: 1047, centisecs: 44 },
-                Event::Rest { centisecs: 6 },
+                Event::tone(1_047_000, 440_000, 15),
+                Event::Rest { micros: 60_000 },
             ]
         );
     }
@@ -425,9 +1570,403 @@ mod tests {
         // c with LEGATO fill: sound=50, no silence
         // d with NORMAL fill: sound=44, silence=6
         assert_eq!(ev.len(), 3);
-        assert_eq!(ev[0], Event::Tone { freq_hz: 1047, centisecs: 50 });
+        assert_eq!(ev[0], Event::tone(1_047_000, 500_000, 15));
         // d4 → pitch 50 → pitchtab[50]=1175
-        assert_eq!(ev[1], Event::Tone { freq_hz: 1175, centisecs: 44 });
-        assert_eq!(ev[2], Event::Rest { centisecs: 6 });
+        assert_eq!(ev[1], Event::tone(1_175_000, 440_000, 15));
+        assert_eq!(ev[2], Event::Rest { micros: 60_000 });
+    }
+
+    #[test]
+    fn parse_clean_melody_has_no_diagnostics() {
+        let score = parse("t120 l8 o4 c d e f# g_ a. b-16 mn p4 >c <c n49 ml ol on");
+        assert!(score.diagnostics.is_empty(), "{:?}", score.diagnostics);
+        assert_eq!(
+            score.nodes[0],
+            Node { span: 0..4, command: Command::Tempo(Some(120)) }
+        );
+    }
+
+    #[test]
+    fn out_of_range_tempo_is_reported() {
+        let score = parse("cT300d");
+        assert_eq!(
+            score.diagnostics,
+            vec![Diagnostic {
+                span: 1..5,
+                severity: Severity::Warning,
+                message: "T300 out of range 32..=255, using 120".to_string(),
+            }]
+        );
+        // The clamp itself is unchanged: T300 plays at the default tempo.
+        assert_eq!(score.render(), render("cT120d"));
+    }
+
+    #[test]
+    fn unrecognised_input_is_reported_as_one_error_per_run() {
+        let score = parse("{\"x\": 1} c");
+        let errors: Vec<_> = score
+            .diagnostics
+            .iter()
+            .map(|d| (d.span.clone(), d.severity))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![(0..5, Severity::Error), (6..8, Severity::Error)]
+        );
+        assert_eq!(score.render(), render("c"));
+    }
+
+    #[test]
+    fn semantic_warnings() {
+        // MB: spkr.c ignores the M and plays the B.
+        let score = parse("mb");
+        assert_eq!(score.diagnostics.len(), 1);
+        assert_eq!(score.diagnostics[0].span, 0..1);
+        assert_eq!(score.render().len(), 2);
+
+        // O6B# is one past the top of the pitch table.
+        let score = parse("o6b#");
+        assert_eq!(score.diagnostics.len(), 1);
+        assert_eq!(score.diagnostics[0].span, 2..4);
+        assert!(score.render().is_empty());
+    }
+
+    #[test]
+    fn split_commands_keeps_every_byte() {
+        let melody = "t160 o3 c4.d_ ?e mle";
+        let pieces = split_commands(melody);
+        assert_eq!(pieces, vec!["t160 ", "o3 ", "c4.", "d_ ?", "e ", "ml", "e"]);
+        assert_eq!(pieces.concat(), melody);
+
+        // Octave tracking does not survive a split.
+        assert_eq!(split_commands("ol cgc"), vec!["ol cgc"]);
+        assert_eq!(split_commands(""), vec![""]);
+    }
+
+    #[test]
+    fn timeline_points_at_the_source() {
+        let melody = "t240 c ?d8";
+        let timeline = timeline(melody);
+        let spans: Vec<&str> = timeline.iter().map(|e| &melody[e.span.clone()]).collect();
+        assert_eq!(spans, vec!["c", "c", "d8", "d8"]);
+        let events: Vec<Event> = timeline.into_iter().map(|e| e.event).collect();
+        assert_eq!(events, render(melody));
+    }
+
+    #[test]
+    fn voices_start_from_the_defaults() {
+        // The second voice does not inherit the first one's T, O or L.
+        let melody = "t240 o5 l8 c; c";
+        assert_eq!(voice_count(melody), 2);
+        let voices = render_voices(melody);
+        assert_eq!(voices[0], render("t240 o5 l8 c"));
+        assert_eq!(voices[1], render("c"));
+        assert_eq!(render(melody), voices[0]);
+        assert_eq!(playing_time(melody), duration(&voices[1]));
+
+        let timeline = timeline(melody);
+        assert_eq!(timeline.last().unwrap().voice, 1);
+        assert_eq!(&melody[timeline.last().unwrap().span.clone()], "c");
+        assert!(parse(melody).diagnostics.is_empty());
+        assert_eq!(voice_count("cde"), 1);
+    }
+
+    #[test]
+    fn volume_commands() {
+        let volumes: Vec<u8> = render("c v8 c )2c (c v c (20c")
+            .into_iter()
+            .filter_map(|e| match e {
+                Event::Tone { volume, .. } => Some(volume),
+                Event::Rest { .. } => None,
+            })
+            .collect();
+        assert_eq!(volumes, vec![15, 8, 10, 9, 15, 0]);
+        // Volume changes nothing else.
+        let at_full_volume: Vec<Event> = render("t200 v3 c (d")
+            .into_iter()
+            .map(|e| match e {
+                Event::Tone { freq_mhz, micros, .. } => Event::tone(freq_mhz, micros, 15),
+                rest => rest,
+            })
+            .collect();
+        assert_eq!(at_full_volume, render("t200 c d"));
+
+        let diags = parse("v16c )c").diagnostics;
+        assert_eq!(diags.len(), 2, "{:?}", diags);
+        assert_eq!(diags[0].message, "V16 out of range 0..=15, using 15");
+        assert_eq!(diags[1].span, 5..6);
+
+        // The kernel is never sent them.
+        assert_eq!(split_commands("t200 v10 c (d"), vec!["t200 ", "c ", "d"]);
+        assert_eq!(split_commands("ol v5 cgc"), vec!["ol cgc"]);
+    }
+
+    const REPEATS: Dialect = Dialect {
+        repeats: true,
+        extended: false,
+        transform: Transform::IDENTITY,
+    };
+    const EXTENDED: Dialect = Dialect {
+        repeats: false,
+        extended: true,
+        transform: Transform::IDENTITY,
+    };
+
+    #[test]
+    fn repeats_expand_in_place() {
+        assert_eq!(REPEATS.render("[c]3"), render("ccc"));
+        assert_eq!(REPEATS.render("[[cd]e]3"), render("cdcde cdcde cdcde"));
+        assert_eq!(REPEATS.render("c |: d e :| f"), render("c dede f"));
+        assert_eq!(REPEATS.render("c d :| e :|"), render("cdcd ee"));
+        // State carries from one pass into the next, as if written out.
+        assert_eq!(REPEATS.render("o2 [>c]3"), render("o2 >c>c>c"));
+        // Each voice expands on its own; a bracket still open at a `;`
+        // closes there, and the `]` left over in the next voice is ignored.
+        assert_eq!(
+            REPEATS.render_voices("[c]2; [d; e]3"),
+            vec![render("cc"), render("d"), render("e")]
+        );
+        assert!(REPEATS.parse("[cdef]2 |:g:|").diagnostics.is_empty());
+
+        // Outside the extension the repeat syntax is junk, as in spkr.c.
+        assert_eq!(render("[c]3 |:d:|"), render("c d"));
+        assert_eq!(parse("[c]3").diagnostics.len(), 2);
+    }
+
+    #[test]
+    fn repeats_point_at_the_source() {
+        let melody = "t240 [c]2";
+        let spans: Vec<&str> = REPEATS
+            .timeline(melody)
+            .iter()
+            .map(|e| &melody[e.span.clone()])
+            .collect();
+        assert_eq!(spans, vec!["c", "c", "c", "c"]);
+        assert_eq!(REPEATS.split_commands(melody), vec!["t240", "c", "c"]);
+        assert_eq!(REPEATS.split_commands("ol [cg]2"), vec!["olcgcg"]);
+    }
+
+    #[test]
+    fn repeat_errors_and_limits() {
+        let diags = REPEATS.parse("c]2 [d [e]0").diagnostics;
+        let messages: Vec<(usize, &str)> = diags
+            .iter()
+            .map(|d| (d.span.start, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (1, "`]` without `[`; ignored"),
+                (4, "`[` without `]`; played once"),
+                (9, "repeat count 0 out of range 1..=255, using 2"),
+            ]
+        );
+        assert_eq!(REPEATS.render("c]2 [d [e]0"), render("c dee"));
+        // A warning inside a repeat is reported once, not once per pass.
+        assert_eq!(REPEATS.parse("[t999 c]9").diagnostics.len(), 1);
+
+        let depth = MAX_REPEAT_DEPTH + 1;
+        let deep = format!("{}c{}", "[".repeat(depth), "]1".repeat(depth));
+        let diags = REPEATS.parse(&deep).diagnostics;
+        assert_eq!(diags.len(), 2, "{:?}", diags);
+        assert_eq!(diags[0].span.start, MAX_REPEAT_DEPTH);
+
+        let huge = "[[[[c]99]99]99]99";
+        assert_eq!(REPEATS.render(huge).len(), 2 * MAX_EXPANDED_COMMANDS);
+        assert!(REPEATS.check_expansion(huge).is_err());
+        assert!(REPEATS.check_expansion("[[c]99]99").is_ok());
+        assert!(Dialect::default().check_expansion(huge).is_ok());
+    }
+
+    #[test]
+    fn extended_range() {
+        let freqs = |melody| -> Vec<u32> {
+            EXTENDED
+                .render(melody)
+                .into_iter()
+                .filter_map(|e| match e {
+                    Event::Tone { freq_hz, .. } => Some(freq_hz),
+                    Event::Rest { .. } => None,
+                })
+                .collect()
+        };
+        // Without O or N a melody is unchanged; O counts standard octaves,
+        // out to what the table cannot reach.
+        assert_eq!(EXTENDED.render("t200 cdeg p8 c."), render("t200 cdeg p8 c."));
+        assert_eq!(EXTENDED.render("o6 c o4 a"), render("o4 c o2 a"));
+        assert_eq!(freqs("o0 c o1 a o9 b"), vec![16, 55, 15804]);
+        assert_eq!(freqs("o9 > b"), vec![15804]);
+        // N is a MIDI note number, and K transposes everything after it.
+        assert_eq!(freqs("n60 n69 n12 n127 n0"), vec![262, 440, 16, 12544]);
+        assert_eq!(freqs("o4 a k3 a n69 k-12 a k a"), vec![440, 523, 523, 220, 440]);
+        // Octave tracking follows the notes as written.
+        assert_eq!(freqs("ol k2 c g c"), freqs("ol d a d"));
+
+        let diags = EXTENDED.parse("o10 n128 n5 k49 o0 c- o9 b+").diagnostics;
+        let messages: Vec<&str> = diags.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "O10 out of range 0..=9, using 6",
+                "N128 out of range 0..=127; skipped",
+                "note outside the playable range; skipped",
+                "K49 out of range -48..=48, using 0",
+                "note outside the playable range; skipped",
+                "note outside the playable range; skipped",
+            ]
+        );
+        // A number too large for any arithmetic is only out of range.
+        let diags = EXTENDED.parse("k48 n99999999999").diagnostics;
+        assert_eq!(diags[0].message, format!("N{} out of range 0..=127; skipped", i32::MAX));
+        // Off, it is all spkr.c's: K is junk and the table ends at O6.
+        assert_eq!(render("k3 a"), render("a"));
+        assert_eq!(parse("k3 a").diagnostics.len(), 1);
+        assert_eq!(parse("o7 c").diagnostics[0].message, "O7 out of range 0..=6, using 4");
+    }
+
+    #[test]
+    fn tuned_rendering() {
+        let melody = "t200 o2 c a; o4 b-";
+        let dialect = Dialect::default();
+        assert_eq!(
+            dialect.render_voices_with(melody, &Tuning::default(), Timing::default()),
+            render_voices(melody)
+        );
+
+        let just = Tuning::load("just", 432.0).unwrap();
+        let tones: Vec<(u32, u32)> = dialect
+            .render_voices_with(melody, &just, Timing::default())
+            .concat()
+            .into_iter()
+            .filter_map(|e| match e {
+                Event::Tone { freq_hz, freq_mhz, .. } => Some((freq_hz, freq_mhz)),
+                Event::Rest { .. } => None,
+            })
+            .collect();
+        // O2 is the standard fourth octave: C is A over 5/3, and the B-
+        // two octaves up is 9/5 of the C there.
+        assert_eq!(tones, vec![(259, 259_200), (432, 432_000), (1866, 1_866_240)]);
+        // Timing is the tuning's business no more than volume is.
+        let durations = |voices: Vec<Vec<Event>>| voices.iter().map(|v| duration(v)).collect::<Vec<_>>();
+        assert_eq!(
+            durations(dialect.render_voices_with(melody, &just, Timing::default())),
+            durations(render_voices(melody))
+        );
+        let score = dialect.parse(melody);
+        assert_eq!(score.timeline_with(&just, Timing::default()).len(), score.timeline().len());
+    }
+
+    #[test]
+    fn transforms() {
+        let with = |transpose, tempo_permille| Dialect {
+            transform: Transform { transpose, tempo_permille },
+            ..Default::default()
+        };
+        // Up a minor third and half as fast again: T120 becomes T180, so
+        // a quarter note is 200 * 120 / 180 / 4 = 33 cs.
+        assert_eq!(
+            with(3, 1500).render("c"),
+            vec![Event::tone(1_245_000, 290_000, 15), Event::Rest { micros: 40_000 }]
+        );
+        assert_eq!(with(3, 1500).render("t80 c"), render("t120 d#"));
+        // Scaled tempos may leave the kernel's range: T200 twice as fast
+        // is T400, a 60 cs whole note. N and OL are transposed too, the
+        // latter after octave tracking.
+        assert_eq!(with(0, 4000).render("t60 c"), render("t240 c"));
+        let whole: u32 = with(0, 2000).render("t200 l1 c").iter().map(Event::micros).sum();
+        assert_eq!(whole, 600_000);
+        assert_eq!(with(-12, 1000).render("n37 ol o3 c b"), render("n25 o2 c < b"));
+        assert_eq!(with(0, 1000).render("o2 c"), render("o2 c"));
+        // Notes moved off the table are skipped, as out-of-range ones are.
+        assert_eq!(with(12, 1000).render("o6 c b"), vec![]);
+        assert_eq!(with(12, 1000).parse("o6 c").diagnostics.len(), 1);
+        // N saturates at i32::MAX, which no transposition may overflow.
+        for transpose in [12, -12] {
+            let huge = with(transpose, 1000);
+            assert_eq!(huge.render("n99999999999"), vec![]);
+            assert_eq!(huge.parse("n99999999999").diagnostics.len(), 1);
+            assert_eq!(huge.rewrite("n99999999999 c").as_deref(), Ok(if transpose > 0 { "o5c" } else { "o3c" }));
+        }
+        // K adds to the transform in the extended range.
+        let extended = Dialect { extended: true, ..with(12, 1000) };
+        assert_eq!(extended.render("k-12 a"), Dialect { extended: true, ..Default::default() }.render("a"));
+
+        // rewrite() gives the kernel a melody that plays the same.
+        let melodies = [
+            "t100 l8 o2 c d e- f# g a b > c",
+            "ol c e g b > c < a f mn",
+            "n37 n0. n84 p4 c.. t300 d",
+            "ml c_ d ms e8. mn f16 l32 g v5 a o6 b o0 c- p",
+        ];
+        for (transpose, tempo) in [(3, 1500), (-5, 750), (12, 1000), (0, 2000), (-13, 333)] {
+            let dialect = with(transpose, tempo);
+            for melody in melodies {
+                let rewritten = dialect.rewrite(melody).unwrap();
+                assert_eq!(render(&rewritten), dialect.render(melody), "{} -> {}", melody, rewritten);
+                assert!(check_strict(&rewritten).is_ok(), "{}", rewritten);
+            }
+        }
+        assert_eq!(
+            with(3, 1500).rewrite("t80 o2 c4. l8 b n1 mlp").as_deref(),
+            Ok("t180t120o2d#4.l8o3dn4mlp")
+        );
+        let repeats = Dialect { repeats: true, ..with(0, 1000) };
+        assert_eq!(repeats.rewrite("[c]3").as_deref(), Ok("o4ccc"));
+        // The kernel cannot be given a tempo outside T32..T255, the
+        // implicit T120 included.
+        assert!(with(0, 1500).rewrite("t200 c").unwrap_err().contains("T300"));
+        assert!(with(0, 250).rewrite("c").unwrap_err().contains("T30"));
+    }
+
+    #[test]
+    fn precise_timing() {
+        let precise = |melody: &str| {
+            Dialect::default()
+                .render_voices_with(melody, &Tuning::default(), Timing::Precise)
+                .remove(0)
+        };
+        // Each tone's start, in microseconds.
+        let starts = |events: &[Event]| {
+            let mut at = 0;
+            let mut starts = Vec::new();
+            for e in events {
+                if let Event::Tone { .. } = e {
+                    starts.push(at);
+                }
+                at += e.micros();
+            }
+            starts
+        };
+
+        // Where whole notes down to eighths are whole centiseconds, both
+        // put every note where the other does, and differ only in where
+        // the articulation gap falls, by less than a centisecond.
+        for tempo in [60, 75, 100, 120, 150, 200] {
+            let melody = format!("t{} c4 d8 e8 f2 p4 g1 ml a4 ms b8 p8 mn >c2", tempo);
+            let (kernel, exact) = (render(&melody), precise(&melody));
+            assert_eq!(kernel.len(), exact.len(), "T{}", tempo);
+            assert_eq!(starts(&kernel), starts(&exact), "T{}", tempo);
+            assert_eq!(duration(&kernel), duration(&exact), "T{}", tempo);
+            for (k, e) in kernel.iter().zip(&exact) {
+                assert!(k.micros().abs_diff(e.micros()) < MICROS_PER_CENTISEC, "T{}: {:?} {:?}", tempo, k, e);
+            }
+        }
+        let c = precise("c");
+        assert_eq!(c, vec![Event::tone(1_047_000, 437_500, 15), Event::Rest { micros: 62_500 }]);
+        assert_eq!((c[0].centisecs(), c[1].centisecs()), (44, 6));
+
+        // At T255 a sixty-fourth is 3.68 cs, which the kernel plays as 1:
+        // a whole note's worth of them takes 64 cs instead of 94.1. The
+        // precise total is the whole note to the microsecond, 240 s / 255.
+        let sixty_fourths = format!("t255 l64 {}", "c".repeat(64));
+        assert_eq!(duration(&render(&sixty_fourths)).as_millis(), 640);
+        assert_eq!(duration(&precise(&sixty_fourths)).as_micros(), 941_176);
+        // 97 dotted eighths at T97 are 97 * 3/16 of a 240/97 s whole note.
+        assert_eq!(duration(&precise(&format!("t97 {}", "c8.".repeat(97)))).as_micros(), 45_000_000);
+        // A dotted note is its written length, gap included, where spkr.c
+        // takes off the undotted note's gap and adds the dotted one's.
+        assert_eq!(precise("c."), vec![Event::tone(1_047_000, 656_250, 15), Event::Rest { micros: 93_750 }]);
+        assert_eq!(duration(&render("c.")).as_millis(), 780);
+        assert_eq!(precise("p4 mlc"), vec![Event::Rest { micros: 500_000 }, Event::tone(1_047_000, 500_000, 15)]);
     }
 }
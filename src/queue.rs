@@ -0,0 +1,548 @@
+// Playback queue shared by every backend. Replaces the per-backend
+// try-lock/open-and-sleep polling loop: a request that finds the device in
+// use now waits in a bounded FIFO and starts the instant the previous
+// melody ends, in arrival order. When the queue is already full the
+// request fails immediately with SpeakerError::QueueFull rather than
+// polling out its --retry-timeout.
+//
+// The right to play is the single permit of a tokio Semaphore, whose
+// acquire() is fair (waiters are served first-come first-served) and
+// cancel-safe: a waiter whose HTTP client disconnects simply drops out of
+// line. Occupancy — the request playing plus those waiting behind it — is
+// counted separately so admission can be refused without waiting; the
+// limit is --queue-depth waiters plus the one playing, so a depth of 0
+// means "503 whenever the device is busy".
+//
+// Admission and waiting are separate steps. join() is synchronous and
+// returns a Ticket (or QueueFull), so the HTTP layer can refuse a request
+// before accepting it — including an asynchronous job, which must get its
+// 503 before the 202. The backend then waits on the Ticket for a Slot, the
+// caller's turn at the device. A Slot is an owned value so a backend can
+// move it into whatever task actually drives the audio (both backends use
+// spawn_blocking), keeping the device reserved until playback really ends
+// even if the request future is dropped first.
+//
+// PlayRequest bundles what a backend needs to know about one request —
+// the melody, who sent it, and the limits it is validated and queued
+// under — as assembled by the HTTP layer from the startup configuration
+// and the request's own query parameters.
+//
+// AbortOnDrop is the backends' shared cancellation hook: installed in the
+// request future, it raises the playback's abort flag when that future is
+// dropped (client disconnect, or an asynchronous job being cancelled), and
+// the task driving the device stops at its next check. The same flag is
+// registered with the queue for as long as the Slot is held, which is how
+// POST /stop (PlayQueue::stop) interrupts whoever is playing; a backend
+// that observes the flag reports SpeakerError::Interrupted. The record
+// also carries the melody's rendered length, from which GET /status
+// reports elapsed and remaining time.
+//
+// The queue only orders spkrd's own requests. A device node another
+// process holds open answers EBUSY, and retry_while_busy is the backends'
+// shared loop for that case: it retries once a second from the blocking
+// task that holds the Slot, until the request's --retry-timeout — which
+// also covered its wait in the queue — runs out.
+
+use crate::error::SpeakerError;
+use crate::metrics::METRICS;
+use crate::mml;
+use crate::synth::{SoundOverrides, Waveform};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub struct PlayRequest<'a> {
+    pub melody: &'a str,
+    pub client_addr: SocketAddr,
+    // Longest a request may wait for its turn (and, for /dev/speaker, for
+    // another process to release the device).
+    pub retry_timeout: Duration,
+    pub max_melody_length: usize,
+    pub strict: bool,
+    // The MML extensions the melody may use, and how long it may play once
+    // its repeats are expanded.
+    pub dialect: mml::Dialect,
+    pub max_expanded_duration: Duration,
+    // How the backend works out lengths, for the playing time.
+    pub timing: mml::Timing,
+    // Changes to a synthesising backend's sound, the loudest volume they
+    // may ask for, and whether there is a --wavetable for them to ask
+    // for. The beepers ignore them.
+    pub sound: SoundOverrides,
+    pub max_volume: f32,
+    pub wavetable: bool,
+    // Whether the backend can play more than one voice at once; if not, a
+    // polyphonic melody is refused.
+    pub polyphonic: bool,
+    // Whether the backend interprets melodies itself, and so can play the
+    // extended range; the kernel's interpreter cannot.
+    pub extended_range: bool,
+    pub debug: bool,
+}
+
+impl PlayRequest<'_> {
+    // Length limit, sound overrides, voice count, transform, extended
+    // range, repeat expansion limits, then (in strict mode) the MML grammar
+    // check. Run by the HTTP layer before the request joins the queue, so
+    // an invalid melody is a 400 even when the device is busy.
+    pub fn validate(&self) -> Result<(), SpeakerError> {
+        if self.melody.len() > self.max_melody_length {
+            return Err(SpeakerError::InvalidMelody(format!(
+                "Melody exceeds {} bytes",
+                self.max_melody_length
+            )));
+        }
+        if let Some(volume) = self.sound.volume {
+            if !(0.0..=self.max_volume).contains(&volume) {
+                return Err(SpeakerError::InvalidMelody(format!(
+                    "Volume {} is outside 0..={}",
+                    volume, self.max_volume
+                )));
+            }
+        }
+        if self.sound.waveform == Some(Waveform::Wavetable) && !self.wavetable {
+            return Err(SpeakerError::InvalidMelody(
+                "No --wavetable is loaded for the wavetable waveform".to_string(),
+            ));
+        }
+        if !self.polyphonic {
+            let voices = self.dialect.voice_count(self.melody);
+            if voices > 1 {
+                return Err(SpeakerError::InvalidMelody(format!(
+                    "Melody has {} voices; this backend plays one at a time (use cpal, file or null)",
+                    voices
+                )));
+            }
+        }
+        let transform = self.dialect.transform;
+        if transform.transpose.abs() > mml::MAX_TRANSPOSE {
+            return Err(SpeakerError::InvalidMelody(format!(
+                "Transpose {} is outside {}..={}",
+                transform.transpose,
+                -mml::MAX_TRANSPOSE,
+                mml::MAX_TRANSPOSE
+            )));
+        }
+        if !(mml::MIN_TEMPO_PERMILLE..=mml::MAX_TEMPO_PERMILLE).contains(&transform.tempo_permille) {
+            return Err(SpeakerError::InvalidMelody(format!(
+                "Tempo {} is outside {}..={}",
+                f64::from(transform.tempo_permille) / 1000.0,
+                f64::from(mml::MIN_TEMPO_PERMILLE) / 1000.0,
+                f64::from(mml::MAX_TEMPO_PERMILLE) / 1000.0
+            )));
+        }
+        // The kernel's interpreter is handed the melody rewritten, and only
+        // takes tempos in its own range.
+        if !self.extended_range && !transform.is_identity() {
+            self.dialect.rewrite(self.melody).map_err(SpeakerError::InvalidMelody)?;
+        }
+        if self.dialect.extended && !self.extended_range {
+            return Err(SpeakerError::InvalidMelody(
+                "The extended range needs a backend that interprets MML itself (use --spkrtone, cpal, file or null)"
+                    .to_string(),
+            ));
+        }
+        if self.dialect.repeats {
+            self.dialect
+                .check_expansion(self.melody)
+                .map_err(SpeakerError::InvalidMelody)?;
+            if self.dialect.playing_time_with(self.melody, self.timing) > self.max_expanded_duration {
+                return Err(SpeakerError::InvalidMelody(format!(
+                    "Melody plays for more than {} seconds once its repeats are expanded",
+                    self.max_expanded_duration.as_secs()
+                )));
+            }
+        }
+        if self.strict {
+            self.dialect
+                .check_strict(self.melody)
+                .map_err(SpeakerError::InvalidMelody)?;
+        }
+        Ok(())
+    }
+}
+
+// Sets the inner abort flag on drop. See the module comment.
+pub struct AbortOnDrop(pub Arc<AtomicBool>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+const BUSY_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// Run `attempt` (open the device and play), retrying while it fails with
+// DeviceBusy. See the module comment. Returns the number of retries.
+pub fn retry_while_busy(
+    start_time: Instant,
+    retry_timeout: Duration,
+    abort: &AtomicBool,
+    mut attempt: impl FnMut() -> Result<(), SpeakerError>,
+) -> Result<u32, SpeakerError> {
+    let mut retries = 0;
+
+    loop {
+        if abort.load(Ordering::SeqCst) {
+            return Err(SpeakerError::Interrupted);
+        }
+        match attempt() {
+            Ok(()) => return Ok(retries),
+            Err(SpeakerError::DeviceBusy) => {
+                if start_time.elapsed() >= retry_timeout {
+                    return Err(SpeakerError::Timeout);
+                }
+                retries += 1;
+                std::thread::sleep(BUSY_RETRY_INTERVAL);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub struct PlayQueue {
+    turn: Arc<Semaphore>,
+    occupancy: AtomicUsize,
+    depth: usize,
+    playing: Mutex<Option<Playback>>,
+}
+
+// The request holding the device, as reported by PlayQueue::stop and
+// PlayQueue::playing.
+#[derive(Clone, Debug)]
+pub struct Playback {
+    pub client_addr: SocketAddr,
+    pub melody_bytes: usize,
+    // When the turn came, and how long the melody plays for.
+    pub started: Instant,
+    pub duration: Duration,
+    abort: Arc<AtomicBool>,
+}
+
+impl Playback {
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.duration.saturating_sub(self.elapsed())
+    }
+}
+
+// Counts one request against the queue's occupancy until dropped.
+struct Place(Arc<PlayQueue>);
+
+impl Drop for Place {
+    fn drop(&mut self) {
+        self.0.occupancy.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// An admitted request's place in line. Dropping it leaves the queue.
+pub struct Ticket {
+    place: Place,
+    joined: Instant,
+    on_turn: Option<Box<dyn FnOnce() + Send>>,
+}
+
+// The device, taken while idle by PlayQueue::try_reserve.
+pub struct Reservation {
+    _permit: OwnedSemaphorePermit,
+}
+
+// The caller's turn at the device; the next request in line starts when
+// this is dropped.
+pub struct Slot {
+    // Declared first so occupancy is released before the permit is handed
+    // on: a request arriving in between is then at worst admitted early,
+    // never refused spuriously.
+    place: Place,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(playback) = self.place.0.playing.lock().unwrap().take() {
+            METRICS.playback_duration.observe(playback.elapsed());
+        }
+    }
+}
+
+impl PlayQueue {
+    pub fn new(depth: usize) -> Arc<Self> {
+        Arc::new(Self {
+            turn: Arc::new(Semaphore::new(1)),
+            occupancy: AtomicUsize::new(0),
+            depth,
+            playing: Mutex::new(None),
+        })
+    }
+
+    // Requests currently playing or waiting.
+    pub fn len(&self) -> usize {
+        self.occupancy.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Join the back of the queue. Fails immediately with QueueFull if
+    // --queue-depth requests are already waiting.
+    pub fn join(self: &Arc<Self>) -> Result<Ticket, SpeakerError> {
+        self.occupancy
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n <= self.depth).then_some(n + 1)
+            })
+            .map_err(|_| SpeakerError::QueueFull)?;
+        Ok(Ticket {
+            place: Place(Arc::clone(self)),
+            joined: Instant::now(),
+            on_turn: None,
+        })
+    }
+
+    // Take the device for a moment if it is idle, without joining the
+    // queue: for readiness probes, which must not overlap playback. A
+    // request arriving meanwhile waits for the Reservation to be dropped.
+    pub fn try_reserve(&self) -> Option<Reservation> {
+        let permit = Arc::clone(&self.turn).try_acquire_owned().ok()?;
+        Some(Reservation { _permit: permit })
+    }
+
+    // The request holding the device, if any.
+    pub fn playing(&self) -> Option<Playback> {
+        self.playing.lock().unwrap().clone()
+    }
+
+    // Interrupt the request holding the device, if any, by raising its
+    // abort flag. Returns who was playing.
+    pub fn stop(&self) -> Option<Playback> {
+        let playing = self.playing.lock().unwrap().clone()?;
+        playing.abort.store(true, Ordering::SeqCst);
+        Some(playing)
+    }
+}
+
+impl Ticket {
+    // Run `f` when this ticket's turn comes, just before wait() returns.
+    pub fn on_turn(mut self, f: impl FnOnce() + Send + 'static) -> Self {
+        self.on_turn = Some(Box::new(f));
+        self
+    }
+
+    // Wait for this caller's turn, then register `req` and its abort flag
+    // as the playback in progress until the Slot is dropped. Fails with
+    // Timeout if the turn does not come within req.retry_timeout, leaving
+    // the queue.
+    pub async fn wait(
+        self,
+        req: &PlayRequest<'_>,
+        abort: &Arc<AtomicBool>,
+    ) -> Result<Slot, SpeakerError> {
+        let turn = Arc::clone(&self.place.0.turn);
+        let permit = tokio::time::timeout(req.retry_timeout, turn.acquire_owned())
+            .await
+            .map_err(|_| SpeakerError::Timeout)?
+            .expect("play queue semaphore is never closed");
+        METRICS.queue_wait.observe(self.joined.elapsed());
+        *self.place.0.playing.lock().unwrap() = Some(Playback {
+            client_addr: req.client_addr,
+            melody_bytes: req.melody.len(),
+            started: Instant::now(),
+            duration: req.dialect.playing_time_with(req.melody, req.timing),
+            abort: Arc::clone(abort),
+        });
+        if let Some(f) = self.on_turn {
+            f();
+        }
+        Ok(Slot {
+            place: self.place,
+            _permit: permit,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(timeout: Duration) -> PlayRequest<'static> {
+        PlayRequest {
+            melody: "cde",
+            client_addr: SocketAddr::from(([127, 0, 0, 1], 1111)),
+            retry_timeout: timeout,
+            max_melody_length: 1000,
+            strict: false,
+            dialect: mml::Dialect::default(),
+            max_expanded_duration: Duration::from_secs(600),
+            timing: mml::Timing::default(),
+            sound: Default::default(),
+            max_volume: 1.0,
+            wavetable: false,
+            polyphonic: true,
+            extended_range: true,
+            debug: false,
+        }
+    }
+
+    async fn take_turn(queue: &Arc<PlayQueue>, timeout: Duration) -> Result<Slot, SpeakerError> {
+        let abort = Arc::new(AtomicBool::new(false));
+        queue.join()?.wait(&request(timeout), &abort).await
+    }
+
+    #[tokio::test]
+    async fn full_queue_is_refused_immediately() {
+        let queue = PlayQueue::new(1);
+        let playing = take_turn(&queue, Duration::from_secs(1)).await.unwrap();
+
+        // One waiter fits behind the player...
+        let q = Arc::clone(&queue);
+        let waiter = tokio::spawn(async move {
+            take_turn(&q, Duration::from_secs(5)).await.is_ok()
+        });
+        while queue.len() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        // ...a second does not, and is told so without waiting.
+        assert!(matches!(queue.join(), Err(SpeakerError::QueueFull)));
+
+        drop(playing);
+        assert!(waiter.await.unwrap());
+        assert!(queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn turns_are_granted_in_arrival_order() {
+        let queue = PlayQueue::new(8);
+        let first = take_turn(&queue, Duration::from_secs(1)).await.unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        for n in 0..4 {
+            let q = Arc::clone(&queue);
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let _slot = take_turn(&q, Duration::from_secs(5)).await.unwrap();
+                tx.send(n).unwrap();
+            });
+            // Let each waiter enqueue before spawning the next.
+            while queue.len() < n + 2 {
+                tokio::task::yield_now().await;
+            }
+        }
+        drop(tx);
+        drop(first);
+
+        let mut order = Vec::new();
+        while let Some(n) = rx.recv().await {
+            order.push(n);
+        }
+        assert_eq!(order, vec![0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn wait_times_out() {
+        let queue = PlayQueue::new(1);
+        let _playing = take_turn(&queue, Duration::from_secs(1)).await.unwrap();
+        assert!(matches!(
+            take_turn(&queue, Duration::from_millis(10)).await,
+            Err(SpeakerError::Timeout)
+        ));
+        // The timed-out waiter has left the queue.
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn current_playback_is_reported_and_can_be_stopped() {
+        let queue = PlayQueue::new(1);
+        assert!(queue.stop().is_none());
+
+        let abort = Arc::new(AtomicBool::new(false));
+        let slot = queue.join().unwrap().wait(&request(Duration::from_secs(1)), &abort).await.unwrap();
+        let playing = queue.playing().unwrap();
+        assert_eq!(playing.duration, Duration::from_millis(1500));
+        assert!(playing.remaining() <= playing.duration);
+        let stopped = queue.stop().unwrap();
+        assert_eq!(stopped.melody_bytes, 3);
+        assert!(abort.load(Ordering::SeqCst));
+
+        drop(slot);
+        assert!(queue.stop().is_none());
+    }
+
+    #[test]
+    fn repeats_are_opt_in_and_bounded() {
+        let repeats = mml::Dialect {
+            repeats: true,
+            ..Default::default()
+        };
+        let req = |melody, dialect| PlayRequest {
+            melody,
+            dialect,
+            max_expanded_duration: Duration::from_secs(60),
+            ..request(Duration::from_secs(1))
+        };
+        // Without the extension the brackets are junk, skipped unless strict.
+        assert!(req("[c]200", mml::Dialect::default()).validate().is_ok());
+        assert!(req("[c]100", repeats).validate().is_ok());
+
+        let err = req("[c]200", repeats).validate().unwrap_err().to_string();
+        assert!(err.contains("more than 60 seconds"), "{}", err);
+        let err = req("[[[p64]99]99]99", repeats).validate().unwrap_err().to_string();
+        assert!(err.contains("past 10000 commands"), "{}", err);
+    }
+
+    #[test]
+    fn transforms_are_bounded() {
+        let transform = |transpose, tempo_permille| mml::Dialect {
+            transform: mml::Transform { transpose, tempo_permille },
+            ..Default::default()
+        };
+        let req = |melody, dialect| PlayRequest {
+            melody,
+            dialect,
+            ..request(Duration::from_secs(1))
+        };
+        assert!(req("c", transform(-48, 4000)).validate().is_ok());
+        assert!(req("c", transform(48, 250)).validate().is_ok());
+        let err = req("c", transform(49, 1000)).validate().unwrap_err().to_string();
+        assert!(err.ends_with("Transpose 49 is outside -48..=48"), "{}", err);
+        let err = req("c", transform(0, 0)).validate().unwrap_err().to_string();
+        assert!(err.ends_with("Tempo 0 is outside 0.25..=4"), "{}", err);
+
+        // The kernel is only given tempos in its own range.
+        let kernel = |melody| PlayRequest {
+            extended_range: false,
+            ..req(melody, transform(0, 2000))
+        };
+        assert!(kernel("t100 c").validate().is_ok());
+        let err = kernel("t200 c").validate().unwrap_err().to_string();
+        assert!(err.contains("T400"), "{}", err);
+    }
+
+    #[test]
+    fn extended_range_needs_a_capable_backend() {
+        let extended = mml::Dialect {
+            extended: true,
+            ..Default::default()
+        };
+        let req = |dialect| PlayRequest {
+            melody: "o9 c",
+            dialect,
+            ..request(Duration::from_secs(1))
+        };
+        assert!(req(extended).validate().is_ok());
+        // The kernel cannot play the extended range.
+        let kernel = PlayRequest {
+            extended_range: false,
+            ..req(extended)
+        };
+        let err = kernel.validate().unwrap_err().to_string();
+        assert!(err.contains("extended range"), "{}", err);
+        assert!(PlayRequest { dialect: mml::Dialect::default(), ..kernel }.validate().is_ok());
+    }
+}
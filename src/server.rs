@@ -1,12 +1,36 @@
-// HTTP server setup and routing. Holds the chosen output backend (either the
-// FreeBSD /dev/speaker writer or, when compiled with the `cpal` feature, the
-// CPAL audio renderer) and dispatches /play requests accordingly. The melody
-// length limit is configured at startup and threaded through to whichever
-// backend validates the incoming body. Error mapping to HTTP status codes is
-// shared between the available backends. run() binds one listener per
-// address in the caller-supplied list (see the bind module for how that
-// list is parsed from --bind) and serves the same app on all of them
-// concurrently.
+// HTTP server setup and routing. Holds the chosen output backend (the
+// FreeBSD /dev/speaker writer, the Linux pcspkr and console beepers, the
+// file/null sink or, when compiled with the `cpal` feature, the CPAL audio
+// renderer) and dispatches /play requests accordingly. Error mapping to
+// HTTP status codes is shared between the available backends.
+//
+// The melody length limit and strict-MML mode are configured at startup
+// and threaded through to whichever backend validates the incoming body.
+// A request can additionally opt into strict checking with ?strict=1, but
+// cannot opt out of a server-wide --mml-strict. The repeat extension to
+// the MML dialect is opted into the same way, with --mml-repeat or
+// ?repeat=1, and bounded by --max-expanded-duration; so is the extended
+// range, with --mml-extended or ?extended=1. ?transpose= and ?tempo= play
+// a melody in another key or at another speed (mml::Transform).
+//
+// With --tunes-dir, PUT /play/{name} plays a tune from the library (see
+// the tunes module) as if its text had been the body, and GET /tunes
+// lists them.
+//
+// Requests take turns at the device through a single play queue (see the
+// queue module) sized by --queue-depth. They are validated and admitted
+// to it before a backend is involved, so a request submitted as an
+// asynchronous job (POST /jobs, or /play?async=1; see the jobs module) is
+// refused exactly as a blocking one would be.
+//
+// POST /render is validated the same way but never admitted: it returns
+// the melody's event timeline without touching the queue or the device.
+// /render.wav is not queued either, so it is bounded by its own sample
+// cap and semaphore (see MAX_RENDER_SAMPLES).
+//
+// run() binds one listener per address in the caller-supplied list (see
+// the bind module for how that list is parsed from --bind) and serves the
+// same app on all of them concurrently.
 //
 // IPv6 listeners are bound v6-only (bind_listener sets IPV6_V6ONLY). The
 // default --bind spec is "0.0.0.0,[::]", which only works if the two
@@ -22,56 +46,356 @@
 // IPv4 clients; list "0.0.0.0" as well to serve both.
 
 #[cfg(feature = "cpal")]
-use crate::cpal_backend::CpalBackend;
+use crate::cpal_backend::{CpalBackend, CpalConfig, DeviceStatus};
 use crate::error::SpeakerError;
+use crate::file_backend::{FileBackend, FileConfig};
+use crate::console_beeper;
 use crate::freebsd_speaker;
+use crate::linux_pcspkr;
+use crate::health::ReadinessCheck;
+use crate::jobs::{Cancel, Jobs};
+use crate::metrics::METRICS;
+use crate::mml::{self, Event, Timing};
+use crate::queue::{PlayQueue, PlayRequest, Ticket};
+use crate::speaker_profile::SpeakerProfile;
+use crate::tunes::Tunes;
+use crate::tuning::Tuning;
+use crate::synth::{Envelope, Sound, SoundOverrides, Waveform, DEFAULT_VOLUME};
+use crate::wav;
 use axum::{
     body::Body,
-    extract::ConnectInfo,
-    http::{Request, StatusCode},
+    extract::{ConnectInfo, Path, Query},
+    http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode},
     response::Response,
-    routing::put,
+    routing::{get, post, put},
     Router,
 };
 use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
 use socket2::{Domain, Protocol, Socket, Type};
 use std::net::SocketAddr;
-#[cfg(feature = "cpal")]
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
 
 #[derive(Clone)]
 pub enum Backend {
     FreebsdSpeaker { device_path: String },
+    // --output=freebsd-speaker --spkrtone.
+    FreebsdSpkrtone { device_path: String },
+    LinuxPcspkr { device_path: String },
+    Console { device_path: String },
     #[cfg(feature = "cpal")]
     Cpal(Arc<CpalBackend>),
+    // --output=file, or --output=null when the config has no path.
+    File(Arc<FileBackend>),
+}
+
+impl Backend {
+    // The synthesising backends mix any number of voices; the beepers
+    // sound one tone at a time.
+    pub fn polyphonic(&self) -> bool {
+        match self {
+            Backend::FreebsdSpeaker { .. }
+            | Backend::FreebsdSpkrtone { .. }
+            | Backend::LinuxPcspkr { .. }
+            | Backend::Console { .. } => false,
+            #[cfg(feature = "cpal")]
+            Backend::Cpal(_) => true,
+            Backend::File(_) => true,
+        }
+    }
+
+    // Every backend but the /dev/speaker writer renders melodies with the
+    // mml module, and so can play the extended range.
+    pub fn extended_range(&self) -> bool {
+        !matches!(self, Backend::FreebsdSpeaker { .. })
+    }
+
+    // The sound of the synthesising backends, and the one /render.wav
+    // gives the beepers.
+    pub fn sound(&self) -> Sound {
+        match self {
+            #[cfg(feature = "cpal")]
+            Backend::Cpal(b) => b.config().sound(),
+            Backend::File(b) => b.config().sound(),
+            Backend::FreebsdSpeaker { .. }
+            | Backend::FreebsdSpkrtone { .. }
+            | Backend::LinuxPcspkr { .. }
+            | Backend::Console { .. } => Sound {
+                waveform: Waveform::PcSpeaker,
+                volume: DEFAULT_VOLUME,
+                envelope: None,
+                wavetable: None,
+                speaker: SpeakerProfile::default(),
+            },
+        }
+    }
+
+    // The tuning the synthesising backends render melodies in; the
+    // beepers play spkr.c's.
+    pub fn tuning(&self) -> Tuning {
+        match self {
+            #[cfg(feature = "cpal")]
+            Backend::Cpal(b) => b.config().tuning.clone(),
+            Backend::File(b) => b.config().tuning.clone(),
+            Backend::FreebsdSpeaker { .. }
+            | Backend::FreebsdSpkrtone { .. }
+            | Backend::LinuxPcspkr { .. }
+            | Backend::Console { .. } => Tuning::default(),
+        }
+    }
+
+    // Likewise the timing: --precise-timing's, or spkr.c's centiseconds.
+    pub fn timing(&self) -> Timing {
+        match self {
+            #[cfg(feature = "cpal")]
+            Backend::Cpal(b) => b.config().timing,
+            Backend::File(b) => b.config().timing,
+            Backend::FreebsdSpeaker { .. }
+            | Backend::FreebsdSpkrtone { .. }
+            | Backend::LinuxPcspkr { .. }
+            | Backend::Console { .. } => Timing::default(),
+        }
+    }
+}
+
+// Startup settings shared by every request, built by main() from the CLI
+// flags. Default matches the CLI defaults.
+#[derive(Clone)]
+pub struct Config {
+    pub retry_timeout: Duration,
+    pub max_melody_length: usize,
+    pub mml_strict: bool,
+    // Whether every request may use repeats, and the longest a melody may
+    // play once they are expanded.
+    pub mml_repeat: bool,
+    pub max_expanded_duration: Duration,
+    // Whether every request is read with the extended range.
+    pub mml_extended: bool,
+    // Loudest volume a request may ask a synthesising backend for.
+    pub max_volume: f32,
+    pub queue_depth: usize,
+    // Bearer token POST /stop must present; None leaves it open.
+    pub admin_token: Option<String>,
+    // How long a /readyz probe result is reused, and whether a failed cpal
+    // probe rebuilds the device.
+    pub ready_interval: Duration,
+    pub ready_rebuild: bool,
+    // The --tunes-dir library, if any; run() keeps it up to date.
+    pub tunes: Option<Arc<Tunes>>,
+    pub debug: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            retry_timeout: Duration::from_secs(30),
+            max_melody_length: 1000,
+            mml_strict: false,
+            mml_repeat: false,
+            max_expanded_duration: Duration::from_secs(600),
+            mml_extended: false,
+            max_volume: DEFAULT_VOLUME,
+            queue_depth: 16,
+            admin_token: None,
+            ready_interval: Duration::from_secs(10),
+            ready_rebuild: false,
+            tunes: None,
+            debug: false,
+        }
+    }
 }
 
 #[derive(Clone)]
 struct AppState {
-    retry_timeout: Duration,
+    config: Config,
     backend: Backend,
-    max_melody_length: usize,
-    debug: bool,
+    queue: Arc<PlayQueue>,
+    jobs: Arc<Jobs>,
+    readiness: Arc<ReadinessCheck>,
+    // Permits to synthesise for /render.wav; see MAX_RENDER_SAMPLES.
+    renders: Arc<Semaphore>,
+    started: Instant,
+}
+
+// GET /status. Durations are in milliseconds.
+#[derive(Serialize)]
+struct Status {
+    version: &'static str,
+    uptime_ms: u128,
+    backend: BackendStatus,
+    queue: QueueStatus,
+    playing: Option<PlaybackStatus>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum BackendStatus {
+    FreebsdSpeaker {
+        device_path: String,
+        spkrtone: bool,
+    },
+    LinuxPcspkr {
+        device_path: String,
+    },
+    Console {
+        device_path: String,
+    },
+    #[cfg(feature = "cpal")]
+    Cpal {
+        config: CpalConfig,
+        device: DeviceStatus,
+    },
+    File {
+        config: FileConfig,
+    },
+    Null {
+        config: FileConfig,
+    },
+}
+
+#[derive(Serialize)]
+struct QueueStatus {
+    depth: usize,
+    waiting: usize,
+}
+
+// POST /render. Times are in centiseconds, the unit spkr.c works in, and
+// again in microseconds, which --precise-timing works in; every voice
+// starts at 0. Source offsets are bytes into the request body.
+#[derive(Serialize)]
+struct Rendering<'a> {
+    events: Vec<RenderedEvent<'a>>,
+    duration_cs: u64,
+    duration_us: u64,
+    diagnostics: Vec<RenderedDiagnostic>,
+}
+
+#[derive(Serialize)]
+struct RenderedEvent<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    freq_hz: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    freq_mhz: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    volume: Option<u8>,
+    voice: usize,
+    start_cs: u64,
+    centisecs: u32,
+    start_us: u64,
+    micros: u32,
+    source_start: usize,
+    source_end: usize,
+    source: &'a str,
+}
+
+#[derive(Serialize)]
+struct RenderedDiagnostic {
+    source_start: usize,
+    source_end: usize,
+    severity: String,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct PlaybackStatus {
+    client: std::net::IpAddr,
+    melody_bytes: usize,
+    duration_ms: u128,
+    elapsed_ms: u128,
+    remaining_ms: u128,
+}
+
+// Query parameters accepted by /play and /jobs. The sound parameters are
+// parsed as --waveform and --envelope are; a value that does not parse is
+// a 400 from the Query extractor, as is a transpose or tempo that is not
+// a number.
+#[derive(Deserialize)]
+struct PlayParams {
+    strict: Option<String>,
+    repeat: Option<String>,
+    extended: Option<String>,
+    #[serde(default, deserialize_with = "semitones")]
+    transpose: Option<i32>,
+    tempo: Option<f64>,
+    waveform: Option<Waveform>,
+    volume: Option<f32>,
+    envelope: Option<Envelope>,
+    #[serde(rename = "async")]
+    async_: Option<String>,
+}
+
+// Further query parameters of /render.wav. `melody` carries the melody for
+// GET, which has no body.
+#[derive(Deserialize)]
+struct WavParams {
+    rate: Option<u32>,
+    melody: Option<String>,
+}
+
+// Longest melody /render.wav will synthesise, and the most samples it
+// will synthesise at the requested rate. Rendering is not queued, so the
+// sample cap bounds the memory one request can take: 8 Mi samples are
+// 32 MB of mixed f32 and 16 MB of WAV, or about 3 minutes at 48 kHz. At
+// most RENDER_CONCURRENCY requests render at once; the rest wait their
+// turn.
+const MAX_RENDER_DURATION: Duration = Duration::from_secs(600);
+const MAX_RENDER_SAMPLES: u64 = 8 * 1024 * 1024;
+const RENDER_CONCURRENCY: usize = 2;
+
+// ?transpose= is a signed number of semitones. An unescaped `+` in a
+// query string means a space, so `transpose=+3` arrives as " 3".
+fn semitones<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<i32>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    let value = value.trim();
+    value
+        .strip_prefix('+')
+        .unwrap_or(value)
+        .parse()
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+// Boolean query flags accept the usual spellings; anything else is false.
+fn query_flag(value: Option<&str>) -> bool {
+    matches!(value, Some("1" | "true" | "yes" | "on"))
 }
 
 pub async fn run(
     addrs: Vec<SocketAddr>,
-    retry_timeout: Duration,
     backend: Backend,
-    max_melody_length: usize,
-    debug: bool,
+    config: Config,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(tunes) = &config.tunes {
+        tokio::spawn(crate::tunes::watch(Arc::clone(tunes)));
+    }
     let state = AppState {
-        retry_timeout,
+        queue: PlayQueue::new(config.queue_depth),
+        jobs: Jobs::new(),
+        readiness: Arc::new(ReadinessCheck::new(config.ready_interval, config.ready_rebuild)),
+        renders: Arc::new(Semaphore::new(RENDER_CONCURRENCY)),
+        started: Instant::now(),
+        config,
         backend,
-        max_melody_length,
-        debug,
     };
 
     let app = Router::new()
         .route("/play", put(play_handler))
+        .route("/play/{name}", put(play_tune_handler))
+        .route("/tunes", get(tunes_handler))
+        .route("/jobs", post(jobs_handler))
+        .route("/jobs/{id}", get(job_status_handler).delete(cancel_job_handler))
+        .route("/stop", post(stop_handler))
+        .route("/status", get(status_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/render", post(render_handler))
+        .route("/render.wav", get(render_wav_handler).post(render_wav_handler))
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
         .with_state(state);
 
     let mut listeners = Vec::with_capacity(addrs.len());
@@ -129,91 +453,626 @@ fn bind_listener(addr: SocketAddr) -> std::io::Result<TcpListener> {
 async fn play_handler(
     ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
     axum::extract::State(state): axum::extract::State<AppState>,
+    Query(params): Query<PlayParams>,
     request: Request<Body>,
 ) -> Response<String> {
+    match read_melody(client_addr, request).await {
+        Ok(melody) => play_melody(client_addr, state, params, melody).await,
+        Err(response) => response,
+    }
+}
+
+// PUT /play/{name}: the tune's text stands in for the body.
+async fn play_tune_handler(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(name): Path<String>,
+    Query(params): Query<PlayParams>,
+) -> Response<String> {
+    match state.config.tunes.as_ref().and_then(|tunes| tunes.get(&name)) {
+        Some(melody) => play_melody(client_addr, state, params, melody.to_string()).await,
+        None => {
+            error!("Request from {} for unknown tune {:?}", client_addr.ip(), name);
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(format!("No tune named {}", name))
+                .unwrap()
+        }
+    }
+}
+
+async fn play_melody(
+    client_addr: SocketAddr,
+    state: AppState,
+    params: PlayParams,
+    melody: String,
+) -> Response<String> {
+    if query_flag(params.async_.as_deref()) {
+        return submit_job(client_addr, state, params, melody).await;
+    }
+
+    let req = play_request(&state, &melody, client_addr, &params);
+
+    let result = match admit(&state, &req) {
+        Ok(ticket) => play(&state.backend, &req, ticket).await,
+        Err(e) => Err(e),
+    };
+    METRICS.request(&result);
+
+    match result {
+        Ok(retries) => {
+            if state.config.debug {
+                debug!(
+                    "Request from {} completed successfully after {} retries",
+                    client_addr.ip(),
+                    retries
+                );
+            }
+            Response::builder()
+                .status(StatusCode::OK)
+                .body("".to_string())
+                .unwrap()
+        }
+        Err(e) => error_response(client_addr, e),
+    }
+}
+
+async fn jobs_handler(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Query(params): Query<PlayParams>,
+    request: Request<Body>,
+) -> Response<String> {
+    match read_melody(client_addr, request).await {
+        Ok(melody) => submit_job(client_addr, state, params, melody).await,
+        Err(response) => response,
+    }
+}
+
+// Admit the melody to the play queue and answer 202 with the new job's
+// status; the melody plays from a spawned task. Refusals (400, 503) are
+// the same as for a blocking /play.
+async fn submit_job(
+    client_addr: SocketAddr,
+    state: AppState,
+    params: PlayParams,
+    melody: String,
+) -> Response<String> {
+    let ticket = match admit(&state, &play_request(&state, &melody, client_addr, &params)) {
+        Ok(ticket) => ticket,
+        Err(e) => {
+            METRICS.request_failed(&e);
+            return error_response(client_addr, e);
+        }
+    };
+
+    let status = state.jobs.create(client_addr.ip(), melody.len());
+    let id = status.id;
+    let jobs = Arc::clone(&state.jobs);
+    let ticket = ticket.on_turn(move || jobs.started(id));
+    let jobs = Arc::clone(&state.jobs);
+    let task = tokio::spawn(async move {
+        let req = play_request(&state, &melody, client_addr, &params);
+        let result = play(&state.backend, &req, ticket).await;
+        METRICS.request(&result);
+        if let Err(e) = &result {
+            error!("Job {} from {} failed: {}", id, client_addr.ip(), e);
+        }
+        state.jobs.finish(id, &result);
+    });
+    jobs.attach(id, task.abort_handle());
+
+    let mut response = json_response(StatusCode::ACCEPTED, &status);
+    response.headers_mut().insert(
+        header::LOCATION,
+        HeaderValue::from_str(&format!("/jobs/{}", id)).unwrap(),
+    );
+    response
+}
+
+async fn job_status_handler(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(id): Path<u64>,
+) -> Response<String> {
+    match state.jobs.get(id) {
+        Some(status) => json_response(StatusCode::OK, &status),
+        None => unknown_job(),
+    }
+}
+
+async fn cancel_job_handler(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(id): Path<u64>,
+) -> Response<String> {
+    match state.jobs.cancel(id) {
+        Some(Cancel::Cancelled(status)) => {
+            info!("Job {} cancelled", id);
+            json_response(StatusCode::OK, &status)
+        }
+        Some(Cancel::AlreadyFinished(status)) => json_response(StatusCode::CONFLICT, &status),
+        None => unknown_job(),
+    }
+}
+
+// Interrupt whatever is playing, on behalf of any client (or only those
+// presenting --admin-token-file's token). The interrupted request fails
+// with SpeakerError::Interrupted; requests waiting behind it then play as
+// usual.
+async fn stop_handler(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    headers: HeaderMap,
+) -> Response<String> {
+    if let Some(token) = &state.config.admin_token {
+        let presented = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        if !presented.is_some_and(|presented| token_matches(presented, token)) {
+            error!("Rejected /stop from {}: missing or wrong admin token", client_addr.ip());
+            return Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(header::WWW_AUTHENTICATE, "Bearer")
+                .body("Admin token required".to_string())
+                .unwrap();
+        }
+    }
+
+    let interrupted = state.queue.stop().map(|playback| {
+        info!(
+            "Stopped playback for {} at the request of {}",
+            playback.client_addr.ip(),
+            client_addr.ip()
+        );
+        serde_json::json!({
+            "client": playback.client_addr.ip(),
+            "melody_bytes": playback.melody_bytes,
+        })
+    });
+    json_response(StatusCode::OK, &serde_json::json!({ "interrupted": interrupted }))
+}
+
+async fn status_handler(
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Response<String> {
+    let backend = match &state.backend {
+        Backend::FreebsdSpeaker { device_path } => BackendStatus::FreebsdSpeaker {
+            device_path: device_path.clone(),
+            spkrtone: false,
+        },
+        Backend::FreebsdSpkrtone { device_path } => BackendStatus::FreebsdSpeaker {
+            device_path: device_path.clone(),
+            spkrtone: true,
+        },
+        Backend::LinuxPcspkr { device_path } => BackendStatus::LinuxPcspkr {
+            device_path: device_path.clone(),
+        },
+        Backend::Console { device_path } => BackendStatus::Console {
+            device_path: device_path.clone(),
+        },
+        #[cfg(feature = "cpal")]
+        Backend::Cpal(b) => BackendStatus::Cpal {
+            config: b.config().clone(),
+            device: b.device_status(),
+        },
+        Backend::File(b) => {
+            let config = b.config().clone();
+            if config.path.is_some() {
+                BackendStatus::File { config }
+            } else {
+                BackendStatus::Null { config }
+            }
+        }
+    };
+    let playing = state.queue.playing();
+    let status = Status {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_ms: state.started.elapsed().as_millis(),
+        backend,
+        queue: QueueStatus {
+            depth: state.config.queue_depth,
+            waiting: state.queue.len().saturating_sub(usize::from(playing.is_some())),
+        },
+        playing: playing.map(|p| PlaybackStatus {
+            client: p.client_addr.ip(),
+            melody_bytes: p.melody_bytes,
+            duration_ms: p.duration.as_millis(),
+            elapsed_ms: p.elapsed().as_millis(),
+            remaining_ms: p.remaining().as_millis(),
+        }),
+    };
+    json_response(StatusCode::OK, &status)
+}
+
+async fn metrics_handler(
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Response<String> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(METRICS.render(&state.queue))
+        .unwrap()
+}
+
+// Render the melody as it would play, without queueing it or touching the
+// device. The length limit, ?strict=1 and ?repeat=1 apply as for /play.
+async fn render_handler(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Query(params): Query<PlayParams>,
+    request: Request<Body>,
+) -> Response<String> {
+    let melody = match read_melody(client_addr, request).await {
+        Ok(melody) => melody,
+        Err(response) => return response,
+    };
+    // Rendering is not playing: every voice, and the extended range, can
+    // be rendered.
+    let req = PlayRequest {
+        polyphonic: true,
+        extended_range: true,
+        ..play_request(&state, &melody, client_addr, &params)
+    };
+    if let Err(e) = req.validate() {
+        return error_response(client_addr, e);
+    }
+
+    let score = req.dialect.parse(&melody);
+    let mut voice = 0;
+    let mut start_us = 0;
+    let mut duration_us = 0;
+    let to_cs = |us: u64| (us + 5000) / 10_000;
+    let events = score
+        .timeline_with(&state.backend.tuning(), state.backend.timing())
+        .into_iter()
+        .map(|e| {
+            if e.voice != voice {
+                voice = e.voice;
+                start_us = 0;
+            }
+            let (kind, freq, volume) = match e.event {
+                Event::Tone {
+                    freq_hz,
+                    freq_mhz,
+                    volume,
+                    ..
+                } => ("tone", Some((freq_hz, freq_mhz)), Some(volume)),
+                Event::Rest { .. } => ("rest", None, None),
+            };
+            let rendered = RenderedEvent {
+                kind,
+                freq_hz: freq.map(|(hz, _)| hz),
+                freq_mhz: freq.map(|(_, mhz)| mhz),
+                volume,
+                voice,
+                start_cs: to_cs(start_us),
+                centisecs: e.event.centisecs(),
+                start_us,
+                micros: e.event.micros(),
+                source_start: e.span.start,
+                source_end: e.span.end,
+                source: &melody[e.span],
+            };
+            start_us += u64::from(e.event.micros());
+            duration_us = duration_us.max(start_us);
+            rendered
+        })
+        .collect();
+    let diagnostics = score
+        .diagnostics
+        .iter()
+        .map(|d| RenderedDiagnostic {
+            source_start: d.span.start,
+            source_end: d.span.end,
+            severity: d.severity.to_string(),
+            message: d.message.clone(),
+        })
+        .collect();
+    json_response(
+        StatusCode::OK,
+        &Rendering {
+            events,
+            duration_cs: to_cs(duration_us),
+            duration_us,
+            diagnostics,
+        },
+    )
+}
+
+// Synthesise the melody to a WAV file. The sound defaults to the CPAL or
+// file backend's, or to --waveform's and --volume's defaults for the
+// beepers, and can be overridden as for /play; the rate defaults to 48 kHz.
+async fn render_wav_handler(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Query(params): Query<PlayParams>,
+    Query(wav_params): Query<WavParams>,
+    request: Request<Body>,
+) -> Response<Body> {
+    let rate = wav_params.rate.unwrap_or(48000);
+    if !(wav::MIN_RATE..=wav::MAX_RATE).contains(&rate) {
+        return bad_request(format!(
+            "rate {} is outside {}..={}",
+            rate,
+            wav::MIN_RATE,
+            wav::MAX_RATE
+        ));
+    }
+
+    let melody = if request.method() == Method::GET {
+        match wav_params.melody {
+            Some(melody) => melody,
+            None => return bad_request("Missing melody parameter".to_string()),
+        }
+    } else {
+        match read_melody(client_addr, request).await {
+            Ok(melody) => melody,
+            Err(response) => return response.map(Body::from),
+        }
+    };
+    // Rendering is not playing: every voice, and the extended range, can
+    // be rendered.
+    let req = PlayRequest {
+        polyphonic: true,
+        extended_range: true,
+        ..play_request(&state, &melody, client_addr, &params)
+    };
+    if let Err(e) = req.validate() {
+        return error_response(client_addr, e).map(Body::from);
+    }
+
+    let sound = req.sound.apply(state.backend.sound());
+    let voices = req
+        .dialect
+        .render_voices_with(&melody, &state.backend.tuning(), state.backend.timing());
+    let max_duration =
+        MAX_RENDER_DURATION.min(Duration::from_secs_f64(MAX_RENDER_SAMPLES as f64 / f64::from(rate)));
+    if voices.iter().any(|voice| mml::duration(voice) > max_duration) {
+        return bad_request(format!(
+            "Melody plays for more than {} seconds, the most that can be rendered at {} Hz",
+            max_duration.as_secs(),
+            rate
+        ));
+    }
+    // The permit goes with the rendering, so a client that hangs up does
+    // not free it while the synthesiser is still running.
+    let permit = Arc::clone(&state.renders).acquire_owned().await.unwrap();
+    let rendering = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        wav::render(&voices, rate, &sound)
+    });
+    let wav = match rendering.await {
+        Ok(wav) => wav,
+        Err(e) => {
+            error!("WAV rendering failed for {}: {}", client_addr.ip(), e);
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from("WAV rendering failed"))
+                .unwrap();
+        }
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "audio/wav")
+        .body(Body::from(wav))
+        .unwrap()
+}
+
+fn bad_request(msg: String) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::from(msg))
+        .unwrap()
+}
+
+// Compare a presented admin token in constant time, so how long a wrong
+// guess takes to refuse says nothing about how much of it was right. Only
+// the length can leak, and it says little about the token.
+fn token_matches(presented: &str, token: &str) -> bool {
+    presented.len() == token.len()
+        && presented
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn healthz_handler() -> Response<String> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .body("ok".to_string())
+        .unwrap()
+}
+
+async fn readyz_handler(
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Response<String> {
+    let readiness = state.readiness.check(&state.backend, &state.queue).await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    json_response(status, &readiness)
+}
+
+// GET /tunes: the --tunes-dir library, by name, with how long each tune
+// plays under the server's dialect (worked out as the tunes are read).
+#[derive(Serialize)]
+struct TuneList {
+    tunes: Vec<TuneInfo>,
+}
+
+#[derive(Serialize)]
+struct TuneInfo {
+    name: String,
+    bytes: usize,
+    duration_ms: u64,
+}
+
+async fn tunes_handler(
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Response<String> {
+    let Some(tunes) = &state.config.tunes else {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body("No tune library configured (--tunes-dir)".to_string())
+            .unwrap();
+    };
+    let list = TuneList {
+        tunes: tunes
+            .table()
+            .iter()
+            .map(|(name, tune)| TuneInfo {
+                name: name.clone(),
+                bytes: tune.melody.len(),
+                duration_ms: tune.duration.as_millis() as u64,
+            })
+            .collect(),
+    };
+    json_response(StatusCode::OK, &list)
+}
+
+fn unknown_job() -> Response<String> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body("Unknown job".to_string())
+        .unwrap()
+}
+
+fn json_response<T: serde::Serialize>(status: StatusCode, value: &T) -> Response<String> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(value).unwrap())
+        .unwrap()
+}
+
+async fn read_melody(client_addr: SocketAddr, request: Request<Body>) -> Result<String, Response<String>> {
     let body_bytes = match axum::body::to_bytes(request.into_body(), usize::MAX).await {
         Ok(bytes) => bytes,
         Err(e) => {
             error!("Failed to read request body from {}: {}", client_addr.ip(), e);
-            return Response::builder()
+            return Err(Response::builder()
                 .status(StatusCode::BAD_REQUEST)
                 .body("Failed to read request body".to_string())
-                .unwrap();
+                .unwrap());
         }
     };
 
-    let melody = match String::from_utf8(body_bytes.to_vec()) {
-        Ok(s) => s,
+    match String::from_utf8(body_bytes.to_vec()) {
+        Ok(s) => Ok(s),
         Err(e) => {
             error!("Invalid UTF-8 in melody data from {}: {}", client_addr.ip(), e);
-            return Response::builder()
+            Err(Response::builder()
                 .status(StatusCode::BAD_REQUEST)
                 .body("Invalid UTF-8 in melody data".to_string())
-                .unwrap();
+                .unwrap())
         }
-    };
+    }
+}
+
+fn play_request<'a>(
+    state: &AppState,
+    melody: &'a str,
+    client_addr: SocketAddr,
+    params: &PlayParams,
+) -> PlayRequest<'a> {
+    PlayRequest {
+        melody,
+        client_addr,
+        retry_timeout: state.config.retry_timeout,
+        max_melody_length: state.config.max_melody_length,
+        strict: state.config.mml_strict || query_flag(params.strict.as_deref()),
+        dialect: mml::Dialect {
+            repeats: state.config.mml_repeat || query_flag(params.repeat.as_deref()),
+            extended: state.config.mml_extended || query_flag(params.extended.as_deref()),
+            transform: mml::Transform {
+                transpose: params.transpose.unwrap_or(0),
+                // Saturating: NaN and negatives become 0, and are refused.
+                tempo_permille: params.tempo.map_or(1000, |tempo| (tempo * 1000.0).round() as u32),
+            },
+        },
+        max_expanded_duration: state.config.max_expanded_duration,
+        timing: state.backend.timing(),
+        sound: SoundOverrides {
+            waveform: params.waveform,
+            volume: params.volume,
+            envelope: params.envelope,
+        },
+        max_volume: state.config.max_volume,
+        wavetable: state.backend.sound().wavetable.is_some(),
+        polyphonic: state.backend.polyphonic(),
+        extended_range: state.backend.extended_range(),
+        debug: state.config.debug,
+    }
+}
 
-    let result = match &state.backend {
+// Validate the request and take its place in the play queue, so that it
+// is refused (400 or 503) before anything is played or accepted.
+fn admit(state: &AppState, req: &PlayRequest<'_>) -> Result<Ticket, SpeakerError> {
+    req.validate()?;
+    state.queue.join()
+}
+
+async fn play(backend: &Backend, req: &PlayRequest<'_>, ticket: Ticket) -> Result<u32, SpeakerError> {
+    match backend {
         Backend::FreebsdSpeaker { device_path } => {
-            freebsd_speaker::play_melody(
-                &melody,
-                client_addr,
-                state.retry_timeout,
-                device_path,
-                state.max_melody_length,
-                state.debug,
-            )
-            .await
+            freebsd_speaker::play_melody(req, ticket, device_path, false).await
         }
-        #[cfg(feature = "cpal")]
-        Backend::Cpal(b) => {
-            b.play_melody(
-                &melody,
-                client_addr,
-                state.retry_timeout,
-                state.max_melody_length,
-                state.debug,
-            )
-            .await
+        Backend::FreebsdSpkrtone { device_path } => {
+            freebsd_speaker::play_melody(req, ticket, device_path, true).await
         }
-    };
-
-    match result {
-        Ok(retries) => {
-            if state.debug {
-                debug!(
-                    "Request from {} completed successfully after {} retries",
-                    client_addr.ip(),
-                    retries
-                );
-            }
-            Response::builder()
-                .status(StatusCode::OK)
-                .body("".to_string())
-                .unwrap()
+        Backend::LinuxPcspkr { device_path } => {
+            linux_pcspkr::play_melody(req, ticket, device_path).await
         }
-        Err(SpeakerError::InvalidMelody(msg)) => {
+        Backend::Console { device_path } => {
+            console_beeper::play_melody(req, ticket, device_path).await
+        }
+        #[cfg(feature = "cpal")]
+        Backend::Cpal(b) => b.play_melody(req, ticket).await,
+        Backend::File(b) => b.play_melody(req, ticket).await,
+    }
+}
+
+fn error_response(client_addr: SocketAddr, err: SpeakerError) -> Response<String> {
+    match err {
+        SpeakerError::InvalidMelody(msg) => {
             error!("Invalid melody from {}: {}", client_addr.ip(), msg);
             Response::builder()
                 .status(StatusCode::BAD_REQUEST)
                 .body(msg)
                 .unwrap()
         }
-        Err(SpeakerError::Timeout) => {
+        SpeakerError::Timeout => {
             error!("Request from {} timed out (device busy)", client_addr.ip());
             Response::builder()
                 .status(StatusCode::SERVICE_UNAVAILABLE)
                 .body("Device busy - request timed out".to_string())
                 .unwrap()
         }
-        Err(SpeakerError::DeviceError(e)) => {
+        SpeakerError::QueueFull => {
+            error!("Play queue full, rejecting request from {}", client_addr.ip());
+            Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body("Device busy - play queue full".to_string())
+                .unwrap()
+        }
+        SpeakerError::Interrupted => {
+            info!("Request from {} interrupted by /stop", client_addr.ip());
+            Response::builder()
+                .status(StatusCode::CONFLICT)
+                .body("Playback interrupted".to_string())
+                .unwrap()
+        }
+        SpeakerError::DeviceError(e) => {
             error!("Device error for request from {}: {}", client_addr.ip(), e);
             Response::builder()
                 .status(StatusCode::INTERNAL_SERVER_ERROR)
                 .body(format!("Device error: {}", e))
                 .unwrap()
         }
-        Err(SpeakerError::DeviceBusy) => {
+        SpeakerError::DeviceBusy => {
             error!("Device busy for request from {}", client_addr.ip());
             Response::builder()
                 .status(StatusCode::SERVICE_UNAVAILABLE)
@@ -221,18 +1080,18 @@ async fn play_handler(
                 .unwrap()
         }
         #[cfg(feature = "cpal")]
-        Err(SpeakerError::CpalError(msg)) => {
+        SpeakerError::CpalError(msg) => {
             error!("CPAL error for request from {}: {}", client_addr.ip(), msg);
             Response::builder()
                 .status(StatusCode::INTERNAL_SERVER_ERROR)
                 .body(format!("CPAL error: {}", msg))
                 .unwrap()
         }
-        // Reaching this case means acquire_and_play exhausted
+        // Reaching this case means play_with_reconnect exhausted
         // --retry-timeout while trying to rebuild the device. Surface as
         // a 500 — the host/device is genuinely unreachable for now.
         #[cfg(feature = "cpal")]
-        Err(SpeakerError::CpalDisconnect(msg)) => {
+        SpeakerError::CpalDisconnect(msg) => {
             error!(
                 "CPAL disconnect for request from {} after retries: {}",
                 client_addr.ip(),
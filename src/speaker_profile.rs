@@ -0,0 +1,204 @@
+// Speaker emulation profiles for the pc-speaker waveform (--speaker-profile).
+// The synth module models a PC speaker as a PIT-quantised square wave fed
+// through a chain of biquad filters and a tanh saturator; a profile is
+// that chain and the saturator's drive. The built-in presets approximate
+// the kind of speaker they are named after; any other speaker can be
+// described in a TOML file:
+//
+//     drive = 2.0
+//
+//     [[filter]]
+//     type = "highpass"
+//     hz = 800
+//     q = 0.707
+//
+//     [[filter]]
+//     type = "peak"
+//     hz = 3000
+//     q = 3.0
+//     gain_db = 9.0
+//
+// Filters run in the order given. Their corners are pulled under the
+// Nyquist frequency at low sample rates, as the presets' are.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Bounds on a profile, to keep a typo from producing a filter that blows
+// up or a chain that makes synthesis crawl.
+pub const MAX_FILTERS: usize = 16;
+pub const MAX_GAIN_DB: f32 = 40.0;
+pub const MAX_DRIVE: f32 = 100.0;
+
+pub const DEFAULT_PROFILE: &str = "piezo";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Filter {
+    Highpass { hz: f32, q: f32 },
+    Lowpass { hz: f32, q: f32 },
+    Peak { hz: f32, q: f32, gain_db: f32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpeakerProfile {
+    // The preset's name, or the path of the file the profile came from.
+    #[serde(skip_deserializing)]
+    pub name: String,
+    // The saturator computes tanh(drive * x): around 1 a loud tone is
+    // gently rounded, well above it every tone is clipped to a square.
+    pub drive: f32,
+    #[serde(rename = "filter", default)]
+    pub filters: Vec<Filter>,
+}
+
+// name, description: the presets, as --help and the docs list them.
+pub const PRESETS: &[(&str, &str)] = &[
+    ("piezo", "modern piezo disc on a motherboard"),
+    ("cone", "1980s 2.25-inch cone speaker in a steel PC case"),
+    ("laptop", "laptop beeper routed through a tiny speaker"),
+];
+
+impl SpeakerProfile {
+    pub fn preset(name: &str) -> Option<Self> {
+        use Filter::*;
+        let (drive, filters) = match name {
+            // Steep HP to kill sub-bass the disc can't move, peaking boost
+            // in the resonant midrange for the buzzy character, LP for
+            // cone roll-off, and the saturator for the driver-clip edge.
+            "piezo" => (
+                2.0,
+                vec![
+                    Highpass { hz: 800.0, q: 0.707 },
+                    Peak { hz: 3000.0, q: 3.0, gain_db: 9.0 },
+                    Lowpass { hz: 6000.0, q: 0.707 },
+                ],
+            ),
+            // A paper cone reaches far lower and rolls off earlier; the
+            // case adds a resonance of its own, and the whole is driven
+            // less hard.
+            "cone" => (
+                1.5,
+                vec![
+                    Highpass { hz: 150.0, q: 0.707 },
+                    Peak { hz: 450.0, q: 4.0, gain_db: 3.0 },
+                    Peak { hz: 1200.0, q: 1.5, gain_db: 4.0 },
+                    Lowpass { hz: 4000.0, q: 0.707 },
+                ],
+            ),
+            // A speaker smaller still than the piezo: no low end to speak
+            // of, a sharp presence peak and hard clipping.
+            "laptop" => (
+                3.0,
+                vec![
+                    Highpass { hz: 1500.0, q: 0.707 },
+                    Peak { hz: 4000.0, q: 2.0, gain_db: 6.0 },
+                    Lowpass { hz: 8000.0, q: 0.707 },
+                ],
+            ),
+            _ => return None,
+        };
+        Some(SpeakerProfile {
+            name: name.to_string(),
+            drive,
+            filters,
+        })
+    }
+
+    // A preset name, or else the path of a TOML file.
+    pub fn load(name_or_path: &str) -> Result<Self, String> {
+        if let Some(profile) = Self::preset(name_or_path) {
+            return Ok(profile);
+        }
+        let text = std::fs::read_to_string(name_or_path).map_err(|e| {
+            let presets: Vec<&str> = PRESETS.iter().map(|&(name, _)| name).collect();
+            format!("{}: {} (and not a preset: {})", name_or_path, e, presets.join(", "))
+        })?;
+        Self::parse(name_or_path, &text)
+    }
+
+    pub fn parse(name: &str, text: &str) -> Result<Self, String> {
+        let mut profile: SpeakerProfile =
+            toml::from_str(text).map_err(|e| format!("{}: {}", name, e.message()))?;
+        profile.name = name.to_string();
+        profile.check().map_err(|e| format!("{}: {}", name, e))?;
+        Ok(profile)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if !(self.drive > 0.0 && self.drive <= MAX_DRIVE) {
+            return Err(format!("drive {} is outside (0, {}]", self.drive, MAX_DRIVE));
+        }
+        if self.filters.len() > MAX_FILTERS {
+            return Err(format!("more than {} filters", MAX_FILTERS));
+        }
+        for filter in &self.filters {
+            let (hz, q, gain_db) = match *filter {
+                Filter::Highpass { hz, q } | Filter::Lowpass { hz, q } => (hz, q, 0.0),
+                Filter::Peak { hz, q, gain_db } => (hz, q, gain_db),
+            };
+            if !(hz > 0.0 && q > 0.0 && hz.is_finite() && q.is_finite()) {
+                return Err(format!("filter {:?} needs a positive hz and q", filter));
+            }
+            if !(-MAX_GAIN_DB..=MAX_GAIN_DB).contains(&gain_db) {
+                return Err(format!("filter gain {} dB is outside ±{}", gain_db, MAX_GAIN_DB));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for SpeakerProfile {
+    fn default() -> Self {
+        Self::preset(DEFAULT_PROFILE).unwrap()
+    }
+}
+
+impl fmt::Display for SpeakerProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_and_files() {
+        for &(name, _) in PRESETS {
+            let profile = SpeakerProfile::preset(name).unwrap();
+            assert_eq!(profile.check(), Ok(()));
+            assert_eq!(profile.to_string(), name);
+        }
+        assert_eq!(SpeakerProfile::default().name, "piezo");
+
+        let text = "drive = 1.5\n\
+                    [[filter]]\ntype = \"highpass\"\nhz = 200\nq = 0.7\n\
+                    [[filter]]\ntype = \"peak\"\nhz = 1000.0\nq = 2.0\ngain_db = -3.0\n";
+        let profile = SpeakerProfile::parse("mine.toml", text).unwrap();
+        assert_eq!(profile.name, "mine.toml");
+        assert_eq!(profile.drive, 1.5);
+        assert_eq!(
+            profile.filters,
+            [
+                Filter::Highpass { hz: 200.0, q: 0.7 },
+                Filter::Peak { hz: 1000.0, q: 2.0, gain_db: -3.0 },
+            ]
+        );
+
+        for bad in [
+            "",
+            "drive = 0",
+            "drive = 1\ncolour = \"red\"",
+            "drive = 1\n[[filter]]\ntype = \"bandpass\"\nhz = 1\nq = 1",
+            "drive = 1\n[[filter]]\ntype = \"lowpass\"\nhz = 1000",
+            "drive = 1\n[[filter]]\ntype = \"lowpass\"\nhz = -1\nq = 1",
+            "drive = 1\n[[filter]]\ntype = \"peak\"\nhz = 1\nq = 1\ngain_db = 90",
+        ] {
+            assert!(SpeakerProfile::parse("bad", bad).is_err(), "{:?}", bad);
+        }
+        assert!(SpeakerProfile::load("no-such-preset").unwrap_err().contains("piezo, cone, laptop"));
+    }
+}
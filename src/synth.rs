@@ -0,0 +1,885 @@
+// Waveform synthesis: turns the mml module's event sequence into mono f32
+// PCM at a given sample rate. The cpal backend plays the result through a
+// live output stream; the wav module wraps it in a RIFF/WAVE file for
+// POST /render.wav and `spkrd render`. Nothing here touches an audio
+// device, so it is compiled with or without the `cpal` feature.
+//
+// What a synthesising backend sounds like is a Sound: waveform, overall
+// volume and, for the generic waveforms, an ADSR envelope. The backends
+// configure one at startup; a request may override any part of it
+// (SoundOverrides), within the server's --max-volume.
+//
+// Each tone is scaled by its MML volume (note_gain), on top of the overall
+// volume: V15 plays at the full volume and every step below it is 2 dB
+// quieter, roughly the steps of the PSG chips V comes from, down to V0,
+// which is silent.
+//
+// A polyphonic melody is synthesised voice by voice, each with its own
+// oscillator and filter state as if on its own speaker, and mix() sums
+// the voices scaled by 1/n, so that n voices at full volume cannot clip.
+//
+// Besides the classic shapes there are chiptune voices: the NES's narrow
+// pulse waves, its noise channel in both modes, a two-operator FM voice
+// and a single-cycle wavetable loaded from a file (--wavetable).
+
+use crate::mml::{Event, MAX_VOLUME};
+use crate::speaker_profile::{Filter, SpeakerProfile};
+use crate::wav;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", try_from = "String")]
+pub enum Waveform {
+    Square,
+    SquareBandlimited,
+    Sine,
+    Triangle,
+    Sawtooth,
+    // Band-limited pulse waves of 12.5 % and 25 % duty, the NES's narrow
+    // ones; its 50 % one is SquareBandlimited.
+    #[serde(rename = "pulse-12")]
+    Pulse12,
+    #[serde(rename = "pulse-25")]
+    Pulse25,
+    // The NES noise channel: a 15-bit LFSR clocked at NOISE_STEPS times
+    // the note's frequency, in its long (white) or 93-step (periodic,
+    // pitched) mode.
+    Noise,
+    PeriodicNoise,
+    // Two-operator FM: a sine carrier phase-modulated by a sine at
+    // FM_RATIO times its frequency, with index FM_INDEX.
+    Fm,
+    // The --wavetable cycle, repeated at the note's frequency.
+    Wavetable,
+    // PC-speaker simulation: square wave generated at a PIT-quantised
+    // frequency, processed through the biquad chain of a speaker profile
+    // (by default a modern piezo disc), then soft-clipped via tanh. Filter
+    // state persists across the entire event sequence so rests ring out
+    // naturally instead of cutting off abruptly.
+    PcSpeaker,
+}
+
+impl std::str::FromStr for Waveform {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "square" => Ok(Waveform::Square),
+            "square-bandlimited" | "squarebandlimited" | "bl-square" => {
+                Ok(Waveform::SquareBandlimited)
+            }
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            "sawtooth" | "saw" => Ok(Waveform::Sawtooth),
+            "pulse-12" | "pulse12" => Ok(Waveform::Pulse12),
+            "pulse-25" | "pulse25" => Ok(Waveform::Pulse25),
+            "pulse-50" | "pulse50" => Ok(Waveform::SquareBandlimited),
+            "noise" | "white-noise" => Ok(Waveform::Noise),
+            "periodic-noise" => Ok(Waveform::PeriodicNoise),
+            "fm" => Ok(Waveform::Fm),
+            "wavetable" => Ok(Waveform::Wavetable),
+            "pc-speaker" | "pcspeaker" | "pc" => Ok(Waveform::PcSpeaker),
+            other => Err(format!("unknown waveform: {}", other)),
+        }
+    }
+}
+
+impl TryFrom<String> for Waveform {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+// Samples a wavetable may have.
+pub const MIN_WAVETABLE_LEN: usize = 2;
+pub const MAX_WAVETABLE_LEN: usize = 65536;
+
+// One cycle of a waveform, played by Waveform::Wavetable with linear
+// interpolation between samples. It is read from a 16-bit mono PCM WAV
+// file, at whatever rate, or from a text file of numbers in [-1, 1]
+// separated by whitespace or commas. It serializes as the path it was
+// loaded from.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct Wavetable {
+    path: String,
+    #[serde(skip)]
+    samples: Arc<[f32]>,
+}
+
+impl Wavetable {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        let samples = if bytes.starts_with(b"RIFF") {
+            wav::read_pcm(&bytes)
+                .map(|(_rate, samples)| samples)
+                .ok_or_else(|| format!("{}: not a 16-bit mono PCM WAV file", path))?
+        } else {
+            let text = String::from_utf8_lossy(&bytes);
+            let samples = text
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|f| !f.is_empty())
+                .map(|f| f.parse::<f32>().ok().filter(|s| (-1.0..=1.0).contains(s)))
+                .collect::<Option<Vec<f32>>>();
+            samples.ok_or_else(|| format!("{}: not a list of numbers in [-1, 1]", path))?
+        };
+        Self::new(path, samples)
+    }
+
+    pub fn new(path: &str, samples: Vec<f32>) -> Result<Self, String> {
+        if !(MIN_WAVETABLE_LEN..=MAX_WAVETABLE_LEN).contains(&samples.len()) {
+            return Err(format!(
+                "{}: {} samples, not {}..={}",
+                path,
+                samples.len(),
+                MIN_WAVETABLE_LEN,
+                MAX_WAVETABLE_LEN
+            ));
+        }
+        Ok(Wavetable {
+            path: path.to_string(),
+            samples: samples.into(),
+        })
+    }
+
+    fn at(&self, phase: f32) -> f32 {
+        let pos = phase * self.samples.len() as f32;
+        let i = (pos as usize).min(self.samples.len() - 1);
+        let next = self.samples[(i + 1) % self.samples.len()];
+        let frac = pos - i as f32;
+        self.samples[i] + (next - self.samples[i]) * frac
+    }
+}
+
+// Longest attack, decay or release an envelope may have.
+pub const MAX_ENVELOPE_MS: f32 = 10_000.0;
+
+// ADSR envelope applied to each tone of the generic waveforms: a linear
+// rise to full level over `attack_ms`, a linear fall to `sustain` (a level
+// in [0, 1]) over `decay_ms`, then, over the last `release_ms` of the
+// tone, a linear fall to silence. The release ends with the tone's sound,
+// before its articulation gap, so the envelope never changes a melody's
+// timing. Attack and release are each cut to a quarter of a tone too
+// short for them, and the decay to what is left.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Envelope {
+    pub attack_ms: f32,
+    pub decay_ms: f32,
+    pub sustain: f32,
+    pub release_ms: f32,
+}
+
+impl Envelope {
+    // The default of the smooth waveforms: long enough to push the
+    // amplitude-step transient at a note boundary below the audible click
+    // threshold, short enough to be inaudible as a fade.
+    pub const CLICK_FREE: Envelope = Envelope {
+        attack_ms: 5.0,
+        decay_ms: 0.0,
+        sustain: 1.0,
+        release_ms: 5.0,
+    };
+}
+
+// "attack,decay,sustain,release", as --envelope and ?envelope= take it.
+impl std::str::FromStr for Envelope {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<f32> = s
+            .split(',')
+            .map(|f| f.trim().parse::<f32>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("envelope {:?} is not four numbers", s))?;
+        let [attack_ms, decay_ms, sustain, release_ms] = fields[..] else {
+            return Err(format!(
+                "envelope {:?} is not attack,decay,sustain,release",
+                s
+            ));
+        };
+        for ms in [attack_ms, decay_ms, release_ms] {
+            if !(0.0..=MAX_ENVELOPE_MS).contains(&ms) {
+                return Err(format!(
+                    "envelope time {} ms is outside 0..={}",
+                    ms, MAX_ENVELOPE_MS
+                ));
+            }
+        }
+        if !(0.0..=1.0).contains(&sustain) {
+            return Err(format!("envelope sustain level {} is outside 0..=1", sustain));
+        }
+        Ok(Envelope {
+            attack_ms,
+            decay_ms,
+            sustain,
+            release_ms,
+        })
+    }
+}
+
+impl TryFrom<String> for Envelope {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Envelope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{}",
+            self.attack_ms, self.decay_ms, self.sustain, self.release_ms
+        )
+    }
+}
+
+// How a melody is synthesised. `envelope` None is the waveform's own:
+// CLICK_FREE for the smooth waveforms, none for Square, which is
+// kernel-faithful, and PcSpeaker, which models the hardware. An explicit
+// envelope applies to every waveform but PcSpeaker. `wavetable` is what
+// Waveform::Wavetable plays; without one it plays a sine. `speaker` is the
+// speaker PcSpeaker emulates.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Sound {
+    pub waveform: Waveform,
+    pub volume: f32,
+    pub envelope: Option<Envelope>,
+    pub wavetable: Option<Wavetable>,
+    pub speaker: SpeakerProfile,
+}
+
+// A request's changes to a backend's Sound. Unset fields keep the
+// backend's.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SoundOverrides {
+    pub waveform: Option<Waveform>,
+    pub volume: Option<f32>,
+    pub envelope: Option<Envelope>,
+}
+
+impl SoundOverrides {
+    pub fn apply(&self, sound: Sound) -> Sound {
+        Sound {
+            waveform: self.waveform.unwrap_or(sound.waveform),
+            volume: self.volume.unwrap_or(sound.volume),
+            envelope: self.envelope.or(sound.envelope),
+            wavetable: sound.wavetable,
+            speaker: sound.speaker,
+        }
+    }
+}
+
+// Output level when none is given: --volume's default. Full scale is
+// unpleasantly loud for a square wave.
+pub const DEFAULT_VOLUME: f32 = 0.25;
+
+// Attenuation per V step below MAX_VOLUME.
+const VOLUME_STEP_DB: f32 = 2.0;
+
+// Intel 8254 PIT clock — used by real PC-speaker hardware. Note frequencies
+// in the simulation are quantised to PIT_FREQ / divisor for an integer
+// divisor, matching what the kernel driver actually programs.
+const PIT_FREQ: u32 = 1_193_182;
+
+// LFSR steps per cycle of the note for the noise waveforms: the length of
+// the periodic mode's sequence, so that it sounds at the note's pitch.
+const NOISE_STEPS: f32 = 93.0;
+
+// FM modulator frequency as a multiple of the carrier's, and modulation
+// index: a bright, clarinet-like tone with only odd harmonics to speak of.
+const FM_RATIO: f32 = 2.0;
+const FM_INDEX: f32 = 1.5;
+
+// Synthesise the event sequence into a mono f32 PCM buffer. Dispatches to a
+// dedicated PC-speaker path (square + biquad chain + saturation) or to the
+// generic oscillator path (every other waveform) which applies the
+// envelope.
+pub fn synth(events: &[Event], sr: u32, sound: &Sound) -> Vec<f32> {
+    match sound.waveform {
+        Waveform::PcSpeaker => synth_pcspeaker(events, sr, sound.volume, &sound.speaker),
+        wf => {
+            let envelope = match wf {
+                Waveform::Square => sound.envelope,
+                _ => Some(sound.envelope.unwrap_or(Envelope::CLICK_FREE)),
+            };
+            synth_generic(events, sr, wf, sound.volume, envelope, sound.wavetable.as_ref())
+        }
+    }
+}
+
+// Synthesise each voice and mix them into one buffer, as long as the
+// longest voice.
+pub fn mix(voices: &[Vec<Event>], sr: u32, sound: &Sound) -> Vec<f32> {
+    if let [voice] = voices {
+        return synth(voice, sr, sound);
+    }
+    let gain = 1.0 / voices.len().max(1) as f32;
+    let mut out: Vec<f32> = Vec::new();
+    for voice in voices {
+        let samples = synth(voice, sr, sound);
+        if samples.len() > out.len() {
+            out.resize(samples.len(), 0.0);
+        }
+        for (o, s) in out.iter_mut().zip(samples) {
+            *o += s * gain;
+        }
+    }
+    out
+}
+
+// Gain of a tone at MML volume `volume`, relative to the overall volume.
+pub fn note_gain(volume: u8) -> f32 {
+    if volume == 0 {
+        return 0.0;
+    }
+    let steps = f32::from(MAX_VOLUME.saturating_sub(volume));
+    10f32.powf(-steps * VOLUME_STEP_DB / 20.0)
+}
+
+// Precompute the total sample count for buffer preallocation.
+fn total_samples(events: &[Event], sr: u32) -> usize {
+    let total_us: u64 = events.iter().map(|e| u64::from(e.micros())).sum();
+    (total_us * u64::from(sr) / 1_000_000) as usize
+}
+
+// Where each event's samples start and end. The boundaries are worked out
+// from the time into the sequence rather than summed event by event, so
+// the fractions of a sample an event's duration falls between do not add
+// up into drift over a long melody.
+struct SampleClock {
+    sr: u64,
+    micros: u64,
+}
+
+impl SampleClock {
+    fn new(sr: u32) -> Self {
+        SampleClock { sr: u64::from(sr), micros: 0 }
+    }
+
+    // The number of samples `event` takes, counting from where the events
+    // before it left off.
+    fn samples(&mut self, event: &Event) -> usize {
+        let start = self.micros * self.sr / 1_000_000;
+        self.micros += u64::from(event.micros());
+        (self.micros * self.sr / 1_000_000 - start) as usize
+    }
+}
+
+// Generic oscillator path. Behaviour is per-waveform:
+//
+//   * Waveform::Square is the kernel-faithful raw output: phase is reset to
+//     0 at every Tone event start (mirroring spkr.c's timer_spkr_setfreq()
+//     resetting the PIT counter), and no envelope is applied unless one is
+//     asked for. This reproduces the FreeBSD driver's hard-edged
+//     amplitude-step transients at note boundaries — the "plink" the ear
+//     locks onto as articulation even when consecutive notes share a
+//     frequency. Boundary clicks are intentional here.
+//
+//   * The remaining software-only waveforms have no FreeBSD analog. They
+//     keep phase continuity across consecutive tones and apply an
+//     envelope, by default Envelope::CLICK_FREE, to suppress the
+//     amplitude-step clicks that would otherwise be audible at every Tone
+//     boundary. The noise LFSR likewise runs on from note to note.
+fn synth_generic(
+    events: &[Event],
+    sr: u32,
+    wf: Waveform,
+    volume: f32,
+    envelope: Option<Envelope>,
+    wavetable: Option<&Wavetable>,
+) -> Vec<f32> {
+    let sr_f = sr as f32;
+    let mut out: Vec<f32> = Vec::with_capacity(total_samples(events, sr));
+    let samples = |ms: f32| (sr_f * ms / 1000.0) as usize;
+
+    let kernel_faithful = matches!(wf, Waveform::Square);
+
+    let mut phase: f32 = 0.0;
+    let mut fm_phase: f32 = 0.0;
+    let mut lfsr = Lfsr::default();
+    let mut lfsr_due: f32 = 0.0;
+    let mut clock = SampleClock::new(sr);
+
+    for ev in events {
+        let n = clock.samples(ev);
+        match *ev {
+            Event::Rest { .. } => {
+                out.extend(std::iter::repeat_n(0.0, n));
+                phase = 0.0;
+                fm_phase = 0.0;
+            }
+            Event::Tone {
+                freq_mhz,
+                volume: note_volume,
+                ..
+            } => {
+                if n == 0 {
+                    continue;
+                }
+                let level = volume * note_gain(note_volume);
+                if kernel_faithful {
+                    phase = 0.0;
+                }
+                let f = freq_mhz as f32 / 1000.0;
+                let dphase = f / sr_f;
+                let shape = envelope.map(|e| {
+                    // A zero attack or release stays zero; otherwise each
+                    // lasts at least a sample.
+                    let ramp = |ms: f32| if ms > 0.0 { samples(ms).min(n / 4).max(1) } else { 0 };
+                    let (attack, release) = (ramp(e.attack_ms), ramp(e.release_ms));
+                    let decay = samples(e.decay_ms).min(n.saturating_sub(attack + release));
+                    (attack, decay, e.sustain, release)
+                });
+                for i in 0..n {
+                    let s = match wf {
+                        Waveform::Square => {
+                            if phase < 0.5 { 1.0 } else { -1.0 }
+                        }
+                        Waveform::SquareBandlimited => pulse(phase, dphase, 0.5),
+                        Waveform::Pulse12 => pulse(phase, dphase, 0.125),
+                        Waveform::Pulse25 => pulse(phase, dphase, 0.25),
+                        Waveform::Noise | Waveform::PeriodicNoise => {
+                            lfsr_due += dphase * NOISE_STEPS;
+                            while lfsr_due >= 1.0 {
+                                lfsr.step(wf == Waveform::PeriodicNoise);
+                                lfsr_due -= 1.0;
+                            }
+                            lfsr.level()
+                        }
+                        Waveform::Fm => {
+                            let s = (2.0 * PI * phase + FM_INDEX * (2.0 * PI * fm_phase).sin()).sin();
+                            fm_phase = (fm_phase + FM_RATIO * dphase).fract();
+                            s
+                        }
+                        Waveform::Wavetable => match wavetable {
+                            Some(table) => table.at(phase),
+                            None => (2.0 * PI * phase).sin(),
+                        },
+                        Waveform::Sine => (2.0 * PI * phase).sin(),
+                        Waveform::Triangle => {
+                            if phase < 0.25 {
+                                4.0 * phase
+                            } else if phase < 0.75 {
+                                2.0 - 4.0 * phase
+                            } else {
+                                -4.0 + 4.0 * phase
+                            }
+                        }
+                        Waveform::Sawtooth => 2.0 * phase - 1.0,
+                        Waveform::PcSpeaker => unreachable!(),
+                    };
+                    let gain = match shape {
+                        None => 1.0,
+                        Some((attack, decay, sustain, release)) => {
+                            let level = if i < attack {
+                                i as f32 / attack as f32
+                            } else if i < attack + decay {
+                                1.0 - (1.0 - sustain) * (i - attack) as f32 / decay as f32
+                            } else {
+                                sustain
+                            };
+                            if i + release >= n {
+                                level * (n - 1 - i) as f32 / release as f32
+                            } else {
+                                level
+                            }
+                        }
+                    };
+                    out.push(s * gain * level);
+                    phase += dphase;
+                    if phase >= 1.0 {
+                        phase -= phase.floor();
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+// PC-speaker simulation path. The note frequency is rounded to the nearest
+// PIT-achievable value (PIT_FREQ / divisor) before sample generation —
+// matching what real hardware would actually play. A ±1 square at that
+// frequency is fed through the speaker profile's biquads (by default HP ->
+// peaking -> LP, the modern piezo disc) and through a tanh saturator at
+// the profile's drive. Filter state persists across all
+// events including rests, so the speaker "rings out" naturally on note-off,
+// at the volume of the note it rings out from.
+//
+// Phase is reset to 0 at every Tone event start to mirror the PIT counter
+// reset that timer_spkr_setfreq() performs in the FreeBSD kernel. The
+// resulting amplitude-step transient is shaped by the biquad chain into a
+// mechanical-style "plink" — what a real piezo would produce when the gate
+// reopens at a fresh PIT count, rather than the sharp DAC click you'd get
+// from feeding the same raw signal to a modern audio output.
+fn synth_pcspeaker(events: &[Event], sr: u32, volume: f32, speaker: &SpeakerProfile) -> Vec<f32> {
+    let sr_f = sr as f32;
+    let mut out: Vec<f32> = Vec::with_capacity(total_samples(events, sr));
+
+    // A corner at or above Nyquist makes the RBJ coefficients unstable (the
+    // output runs off to NaN), which the piezo's 6 kHz LP hits at rates
+    // below 12 kHz. Pull the corners just under Nyquist there instead.
+    let corner = |hz: f32| hz.min(0.45 * sr_f);
+    let mut chain: Vec<Biquad> = speaker
+        .filters
+        .iter()
+        .map(|filter| match *filter {
+            Filter::Highpass { hz, q } => Biquad::highpass(sr, corner(hz), q),
+            Filter::Lowpass { hz, q } => Biquad::lowpass(sr, corner(hz), q),
+            Filter::Peak { hz, q, gain_db } => Biquad::peak(sr, corner(hz), q, gain_db),
+        })
+        .collect();
+    let mut speaker_out = |x: f32| {
+        let y = chain.iter_mut().fold(x, |x, biquad| biquad.process(x));
+        (speaker.drive * y).tanh()
+    };
+    let mut level = volume;
+    let mut clock = SampleClock::new(sr);
+
+    for ev in events {
+        let n = clock.samples(ev);
+        match *ev {
+            Event::Rest { .. } => {
+                for _ in 0..n {
+                    out.push(speaker_out(0.0) * level);
+                }
+            }
+            Event::Tone {
+                freq_hz,
+                volume: note_volume,
+                ..
+            } => {
+                if n == 0 {
+                    continue;
+                }
+                // Scaled after the saturator, so a quiet note is the same
+                // timbre, only softer.
+                level = volume * note_gain(note_volume);
+                // The PIT is programmed from whole hertz, as spkr.c does,
+                // so a tuning's fractions are lost here as on the device.
+                let q_freq = pit_quantize(freq_hz);
+                let dphase = q_freq as f32 / sr_f;
+                let mut phase: f32 = 0.0;
+                for _ in 0..n {
+                    let raw = if phase < 0.5 { 1.0 } else { -1.0 };
+                    out.push(speaker_out(raw) * level);
+                    phase += dphase;
+                    if phase >= 1.0 {
+                        phase -= phase.floor();
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+// Round a desired frequency to the nearest frequency the PIT can actually
+// produce: divisor = round(PIT_FREQ / freq), achievable = PIT_FREQ / divisor.
+fn pit_quantize(freq_hz: u32) -> u32 {
+    if freq_hz == 0 {
+        return 0;
+    }
+    // The counter is 16 bits, so the PIT cannot go below about 18 Hz.
+    let divisor = ((PIT_FREQ + freq_hz / 2) / freq_hz).clamp(1, 0xFFFF);
+    PIT_FREQ / divisor
+}
+
+// Direct-form-2 transposed biquad. Coefficients are pre-normalised by a0 at
+// construction time so `process` is just five mul-adds.
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    s1: f32,
+    s2: f32,
+}
+
+impl Biquad {
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.s1;
+        self.s1 = self.b1 * x - self.a1 * y + self.s2;
+        self.s2 = self.b2 * x - self.a2 * y;
+        y
+    }
+
+    // RBJ audio cookbook: lowpass biquad.
+    fn lowpass(sr: u32, hz: f32, q: f32) -> Self {
+        let omega = 2.0 * PI * hz / sr as f32;
+        let alpha = omega.sin() / (2.0 * q);
+        let cos_w = omega.cos();
+        let b0 = (1.0 - cos_w) * 0.5;
+        let b1 = 1.0 - cos_w;
+        let b2 = (1.0 - cos_w) * 0.5;
+        let a0 = 1.0 + alpha;
+        let a1 = -2.0 * cos_w;
+        let a2 = 1.0 - alpha;
+        Self::normalise(a0, b0, b1, b2, a1, a2)
+    }
+
+    // RBJ audio cookbook: highpass biquad.
+    fn highpass(sr: u32, hz: f32, q: f32) -> Self {
+        let omega = 2.0 * PI * hz / sr as f32;
+        let alpha = omega.sin() / (2.0 * q);
+        let cos_w = omega.cos();
+        let b0 = (1.0 + cos_w) * 0.5;
+        let b1 = -(1.0 + cos_w);
+        let b2 = (1.0 + cos_w) * 0.5;
+        let a0 = 1.0 + alpha;
+        let a1 = -2.0 * cos_w;
+        let a2 = 1.0 - alpha;
+        Self::normalise(a0, b0, b1, b2, a1, a2)
+    }
+
+    // RBJ audio cookbook: peaking EQ biquad.
+    fn peak(sr: u32, hz: f32, q: f32, gain_db: f32) -> Self {
+        let a_amp = 10f32.powf(gain_db / 40.0);
+        let omega = 2.0 * PI * hz / sr as f32;
+        let alpha = omega.sin() / (2.0 * q);
+        let cos_w = omega.cos();
+        let b0 = 1.0 + alpha * a_amp;
+        let b1 = -2.0 * cos_w;
+        let b2 = 1.0 - alpha * a_amp;
+        let a0 = 1.0 + alpha / a_amp;
+        let a1 = -2.0 * cos_w;
+        let a2 = 1.0 - alpha / a_amp;
+        Self::normalise(a0, b0, b1, b2, a1, a2)
+    }
+
+    fn normalise(a0: f32, b0: f32, b1: f32, b2: f32, a1: f32, a2: f32) -> Self {
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+            s1: 0.0,
+            s2: 0.0,
+        }
+    }
+}
+
+// Band-limited pulse wave at one level for `duty` of each cycle and at
+// the other for the rest: the difference of two PolyBLEP sawtooths,
+// scaled so its peak is 1. The difference has no DC to remove.
+fn pulse(phase: f32, dphase: f32, duty: f32) -> f32 {
+    let saw1 = 2.0 * phase - 1.0;
+    let phase2 = (phase + 1.0 - duty).fract();
+    let saw2 = 2.0 * phase2 - 1.0;
+    let p = saw1 - saw2 - poly_blep(phase, dphase) + poly_blep(phase2, dphase);
+    p / (2.0 * duty.max(1.0 - duty))
+}
+
+// The NES noise channel's shift register. Each step shifts in the XOR
+// of bit 0 and bit 1, or of bit 0 and bit 6 in the periodic mode, whose
+// sequence repeats every 93 steps.
+struct Lfsr(u16);
+
+impl Default for Lfsr {
+    fn default() -> Self {
+        Lfsr(1)
+    }
+}
+
+impl Lfsr {
+    fn step(&mut self, periodic: bool) {
+        let tap = if periodic { 6 } else { 1 };
+        let feedback = (self.0 ^ (self.0 >> tap)) & 1;
+        self.0 = (self.0 >> 1) | (feedback << 14);
+    }
+
+    fn level(&self) -> f32 {
+        if self.0 & 1 == 0 { 1.0 } else { -1.0 }
+    }
+}
+
+// PolyBLEP correction for band-limited oscillators. `t` is phase in [0,1),
+// `dt` is per-sample phase increment.
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let x = t / dt;
+        x + x - x * x - 1.0
+    } else if t > 1.0 - dt {
+        let x = (t - 1.0) / dt;
+        x * x + x + x + 1.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sound(waveform: Waveform, volume: f32) -> Sound {
+        Sound {
+            waveform,
+            volume,
+            envelope: None,
+            wavetable: None,
+            speaker: SpeakerProfile::default(),
+        }
+    }
+
+    #[test]
+    fn pit_quantize_round_trip() {
+        // PIT_FREQ / 1140 = 1046.65... → rounds to divisor 1140 → 1046 Hz.
+        assert_eq!(pit_quantize(1047), 1193182 / 1140);
+        // 440 Hz: divisor = round(1193182/440) = 2712 → PIT/2712 = 439 Hz.
+        assert_eq!(pit_quantize(440), 1193182 / 2712);
+        // Zero-input guard.
+        assert_eq!(pit_quantize(0), 0);
+    }
+
+    #[test]
+    fn length_follows_the_events() {
+        let events = [
+            Event::tone(440_000, 100_000, 15),
+            Event::Rest { micros: 50_000 },
+        ];
+        // 8 kHz puts the piezo LP corner above Nyquist.
+        for wf in [Waveform::Square, Waveform::Sine, Waveform::PcSpeaker] {
+            let pcm = synth(&events, 8000, &sound(wf, 0.5));
+            assert_eq!(pcm.len(), 1200);
+            assert!(pcm.iter().all(|s| s.abs() <= 0.5), "{:?}", wf);
+            assert!(pcm[..800].iter().any(|&s| s != 0.0));
+        }
+        // The generic path is silent during rests; the piezo model rings on.
+        let pcm = synth(&events, 8000, &sound(Waveform::Sine, 0.5));
+        assert!(pcm[800..].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn notes_are_scaled_by_their_volume() {
+        assert_eq!(note_gain(MAX_VOLUME), 1.0);
+        assert_eq!(note_gain(0), 0.0);
+        assert!((note_gain(12) - 0.5).abs() < 0.01);
+
+        let events = [
+            Event::tone(440_000, 100_000, 15),
+            Event::tone(440_000, 100_000, 12),
+            Event::tone(440_000, 100_000, 0),
+        ];
+        let peak = |s: &[f32]| s.iter().fold(0.0f32, |m, x| m.max(x.abs()));
+        for wf in [Waveform::Square, Waveform::Sine, Waveform::PcSpeaker] {
+            let pcm = synth(&events, 8000, &sound(wf, 0.5));
+            let (loud, soft) = (peak(&pcm[..800]), peak(&pcm[800..1600]));
+            assert!((soft / loud - 0.5).abs() < 0.05, "{:?}: {} vs {}", wf, loud, soft);
+        }
+        let pcm = synth(&events, 8000, &sound(Waveform::Sine, 0.5));
+        assert!(pcm[1600..].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn voices_are_mixed_with_headroom() {
+        let long = vec![Event::tone(440_000, 100_000, 15)];
+        let short = vec![Event::tone(440_000, 50_000, 15)];
+        let pcm = mix(&[long.clone(), short], 8000, &sound(Waveform::Square, 1.0));
+        assert_eq!(pcm.len(), 800);
+        // In phase, two full-volume voices sum to full volume, not twice it.
+        assert!(pcm.iter().all(|s| s.abs() <= 1.0));
+        assert!(pcm[..400].iter().any(|s| s.abs() > 0.99));
+        assert!(pcm[400..].iter().all(|s| s.abs() <= 0.5));
+        // One voice is synthesised as it always was.
+        let mono = synth(&long, 8000, &sound(Waveform::Square, 1.0));
+        assert_eq!(mix(std::slice::from_ref(&long), 8000, &sound(Waveform::Square, 1.0)), mono);
+    }
+
+    #[test]
+    fn envelope_shapes_each_tone() {
+        assert_eq!(
+            "10, 50,0.5,20".parse::<Envelope>(),
+            Ok(Envelope { attack_ms: 10.0, decay_ms: 50.0, sustain: 0.5, release_ms: 20.0 })
+        );
+        assert_eq!(Envelope::CLICK_FREE.to_string(), "5,0,1,5");
+        for bad in ["", "1,2,3", "1,2,0.5,x", "1,2,1.5,3", "-1,0,1,0", "20000,0,1,0"] {
+            assert!(bad.parse::<Envelope>().is_err(), "{:?}", bad);
+        }
+
+        // 10 ms attack, 20 ms decay to half level, 20 ms release, on a
+        // 100 ms square tone at 8 kHz.
+        let events = [Event::tone(400_000, 100_000, 15)];
+        let envelope = Envelope { attack_ms: 10.0, decay_ms: 20.0, sustain: 0.5, release_ms: 20.0 };
+        let shaped = Sound { envelope: Some(envelope), ..sound(Waveform::Square, 1.0) };
+        let pcm = synth(&events, 8000, &shaped);
+        assert_eq!(pcm.len(), 800);
+        assert_eq!(pcm[0], 0.0);
+        assert!((pcm[40].abs() - 0.5).abs() < 0.01);
+        assert!((pcm[80].abs() - 1.0).abs() < 0.01);
+        assert!((pcm[160].abs() - 0.75).abs() < 0.01);
+        assert!(pcm[240..640].iter().all(|s| (s.abs() - 0.5).abs() < 0.01));
+        assert!((pcm[720].abs() - 0.25).abs() < 0.01);
+        assert!(pcm[799].abs() < 0.01);
+
+        // Square has no envelope of its own; a smooth waveform fades in.
+        assert_eq!(synth(&events, 8000, &sound(Waveform::Square, 1.0))[0], 1.0);
+        assert_eq!(synth(&events, 8000, &sound(Waveform::Sawtooth, 1.0))[0], 0.0);
+        // The piezo model ignores envelopes.
+        let piezo = synth(&events, 8000, &sound(Waveform::PcSpeaker, 1.0));
+        let shaped = Sound { waveform: Waveform::PcSpeaker, ..shaped };
+        assert_eq!(synth(&events, 8000, &shaped), piezo);
+    }
+
+    #[test]
+    fn chiptune_voices() {
+        // No envelope, so that every sample is the oscillator's.
+        let flat = |waveform| Sound {
+            envelope: Some(Envelope { attack_ms: 0.0, decay_ms: 0.0, sustain: 1.0, release_ms: 0.0 }),
+            ..sound(waveform, 1.0)
+        };
+        for name in ["pulse-12", "pulse-25", "pulse-50", "noise", "periodic-noise", "fm", "wavetable"] {
+            assert!(name.parse::<Waveform>().is_ok(), "{}", name);
+        }
+
+        // A 100 Hz pulse-12 at 8 kHz spends an eighth of each cycle at its
+        // low level, and has no DC.
+        let events = [Event::tone(100_000, 100_000, 15)];
+        let pcm = synth(&events, 8000, &flat(Waveform::Pulse12));
+        let low = pcm.iter().filter(|&&s| s < -0.5).count();
+        assert!((85..=105).contains(&low), "{}", low);
+        assert!(pcm.iter().all(|s| s.abs() <= 1.01));
+        assert!(pcm.iter().sum::<f32>().abs() / (pcm.len() as f32) < 0.01);
+
+        // At one LFSR step per sample, periodic noise repeats every 93
+        // samples and white noise does not.
+        let events = [Event::tone(100_000, 100_000, 15)];
+        let periodic = synth(&events, 9300, &flat(Waveform::PeriodicNoise));
+        assert!((0..500).all(|i| periodic[i] == periodic[i + 93]));
+        let white = synth(&events, 9300, &flat(Waveform::Noise));
+        assert!((0..500).any(|i| white[i] != white[i + 93]));
+        assert!(white.iter().all(|&s| s.abs() == 1.0));
+
+        let fm = synth(&events, 8000, &flat(Waveform::Fm));
+        let sine = synth(&events, 8000, &flat(Waveform::Sine));
+        assert!(fm.iter().all(|s| s.abs() <= 1.0));
+        assert_ne!(fm, sine);
+
+        // A table is read with linear interpolation; without one, the
+        // wavetable waveform is a sine.
+        let table = Wavetable::new("test", vec![0.0, 1.0, 0.0, -1.0]).unwrap();
+        let events = [Event::tone(1_000_000, 10_000, 15)];
+        let shaped = Sound { wavetable: Some(table), ..flat(Waveform::Wavetable) };
+        let pcm = synth(&events, 8000, &shaped);
+        assert_eq!(&pcm[..8], &[0.0, 0.5, 1.0, 0.5, 0.0, -0.5, -1.0, -0.5]);
+        assert_eq!(synth(&events, 8000, &flat(Waveform::Wavetable)), synth(&events, 8000, &flat(Waveform::Sine)));
+        assert!(Wavetable::new("short", vec![0.0]).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("table.txt");
+        std::fs::write(&path, "0, 0.5\n1 -1\n").unwrap();
+        let table = Wavetable::load(path.to_str().unwrap()).unwrap();
+        assert_eq!(&table.samples[..], &[0.0, 0.5, 1.0, -1.0]);
+        std::fs::write(&path, "0 2").unwrap();
+        assert!(Wavetable::load(path.to_str().unwrap()).is_err());
+        let path = dir.path().join("table.wav");
+        std::fs::write(&path, wav::encode(&[0.0, 1.0, -1.0], 44100)).unwrap();
+        let table = Wavetable::load(path.to_str().unwrap()).unwrap();
+        assert_eq!(&table.samples[..], &[0.0, 1.0, -1.0]);
+    }
+}
@@ -0,0 +1,168 @@
+// Playback for beepers driven one tone at a time: devices that are told
+// "start sounding this frequency" and "stop", and leave the timing to us,
+// unlike /dev/speaker, which is handed the MML itself. The melody is
+// rendered by the mml module and walked event by event, each tone or rest
+// held until its deadline. Deadlines are measured from the start of the
+// melody rather than from each event, so the time taken by the writes
+// themselves does not accumulate into drift.
+//
+// While waiting out an event, the abort flag is checked every ABORT_POLL,
+// so /stop, a client disconnect or a cancelled job silences the beeper
+// within a few milliseconds, mid-note, rather than at the next note. The
+// beeper is always left silent, whether the melody finished, was
+// interrupted or failed.
+//
+// play_melody is the request side shared by the beeper backends: it waits
+// for the queue slot, then opens the device and plays from a
+// spawn_blocking task, retrying while another process holds the device
+// (queue::retry_while_busy).
+
+use crate::error::SpeakerError;
+use crate::mml::Event;
+use crate::queue::{self, AbortOnDrop, PlayRequest, Ticket};
+use log::debug;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const ABORT_POLL: Duration = Duration::from_millis(5);
+
+pub trait Beeper {
+    // Start sounding `freq_hz`, replacing any tone already sounding; 0
+    // silences the beeper. `hold` is how long the tone is meant to last
+    // (zero for silence); a beeper whose kernel can time tones itself may
+    // use it so the speaker stops even if spkrd dies mid-note.
+    fn set_tone(&mut self, freq_hz: u32, hold: Duration) -> io::Result<()>;
+}
+
+pub async fn play_melody<B, F>(req: &PlayRequest<'_>, ticket: Ticket, open: F) -> Result<u32, SpeakerError>
+where
+    B: Beeper,
+    F: Fn() -> Result<B, SpeakerError> + Send + 'static,
+{
+    if req.debug {
+        debug!("Request from {}: {} bytes", req.client_addr.ip(), req.melody.len());
+    }
+
+    let start_time = Instant::now();
+    let abort = Arc::new(AtomicBool::new(false));
+    let _abort_on_drop = AbortOnDrop(Arc::clone(&abort));
+
+    let slot = ticket.wait(req, &abort).await?;
+    let events = req.dialect.render(req.melody);
+    let retry_timeout = req.retry_timeout;
+    let join = tokio::task::spawn_blocking(move || {
+        let _slot = slot;
+        queue::retry_while_busy(start_time, retry_timeout, &abort, || {
+            let mut beeper = open()?;
+            play(&mut beeper, &events, &abort)
+        })
+    });
+
+    match join.await {
+        Ok(result) => result,
+        Err(e) => Err(SpeakerError::DeviceError(io::Error::other(e))),
+    }
+}
+
+pub fn play(beeper: &mut dyn Beeper, events: &[Event], abort: &AtomicBool) -> Result<(), SpeakerError> {
+    let result = play_events(beeper, events, abort);
+    let silenced = beeper.set_tone(0, Duration::ZERO);
+    result?;
+    Ok(silenced?)
+}
+
+fn play_events(beeper: &mut dyn Beeper, events: &[Event], abort: &AtomicBool) -> Result<(), SpeakerError> {
+    let start = Instant::now();
+    let mut due = start;
+    let mut sounding = false;
+    for event in events {
+        // Every tone is (re)started, as spkr.c reprograms the timer for
+        // each note; a rest only needs a write if something is sounding.
+        match *event {
+            Event::Tone { freq_hz, .. } => {
+                beeper.set_tone(freq_hz, event.duration())?;
+                sounding = freq_hz != 0;
+            }
+            Event::Rest { .. } if sounding => {
+                beeper.set_tone(0, Duration::ZERO)?;
+                sounding = false;
+            }
+            Event::Rest { .. } => {}
+        }
+        due += event.duration();
+        wait_until(due, abort)?;
+    }
+    Ok(())
+}
+
+fn wait_until(due: Instant, abort: &AtomicBool) -> Result<(), SpeakerError> {
+    loop {
+        if abort.load(Ordering::SeqCst) {
+            return Err(SpeakerError::Interrupted);
+        }
+        let now = Instant::now();
+        if now >= due {
+            return Ok(());
+        }
+        std::thread::sleep((due - now).min(ABORT_POLL));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder(Vec<(u32, Instant)>);
+
+    impl Beeper for Recorder {
+        fn set_tone(&mut self, freq_hz: u32, _hold: Duration) -> io::Result<()> {
+            self.0.push((freq_hz, Instant::now()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn tones_are_held_for_their_duration() {
+        let events = [
+            Event::tone(440_000, 30_000, 15),
+            Event::tone(440_000, 20_000, 15),
+            Event::Rest { micros: 20_000 },
+            Event::Rest { micros: 10_000 },
+            Event::tone(880_000, 20_000, 15),
+        ];
+        let mut beeper = Recorder::default();
+        let start = Instant::now();
+        play(&mut beeper, &events, &AtomicBool::new(false)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        // A repeated tone is restarted; the second rest needs no write.
+        let freqs: Vec<u32> = beeper.0.iter().map(|&(f, _)| f).collect();
+        assert_eq!(freqs, [440, 440, 0, 880, 0]);
+        let at = |i: usize| beeper.0[i].1 - start;
+        assert!(at(1) >= Duration::from_millis(30));
+        assert!(at(2) >= Duration::from_millis(50));
+        assert!(at(3) >= Duration::from_millis(80));
+    }
+
+    #[test]
+    fn abort_silences_mid_note() {
+        let events = [Event::tone(440_000, 10_000_000, 15)];
+        let mut beeper = Recorder::default();
+        let abort = AtomicBool::new(false);
+        let start = Instant::now();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(20));
+                abort.store(true, Ordering::SeqCst);
+            });
+            let result = play(&mut beeper, &events, &abort);
+            assert!(matches!(result, Err(SpeakerError::Interrupted)));
+        });
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let freqs: Vec<u32> = beeper.0.iter().map(|&(f, _)| f).collect();
+        assert_eq!(freqs, [440, 0]);
+    }
+}
@@ -0,0 +1,259 @@
+// The tune library (--tunes-dir): named melodies read from a directory, so
+// a client can PUT /play/{name} instead of sending the MML, and GET /tunes
+// lists what there is. Every `NAME.mml` file directly in the directory is
+// the tune NAME; names are limited to letters, digits, `-`, `_` and `.` so
+// they need no escaping in a URL, and other files are skipped with a
+// warning, as is a file that cannot be read as UTF-8 or is longer than
+// --max-melody-length, which it could never be played under. How long each
+// tune plays is worked out as it is read, for GET /tunes.
+//
+// The directory is read at startup, where a failure stops the server, and
+// read again on SIGHUP and whenever watch()'s poll, every POLL_INTERVAL,
+// sees a tune file added, removed or changed (by size and modification
+// time). A reload that cannot read the directory keeps the tunes it had,
+// so a directory briefly moved aside does not empty the library. Requests
+// see one set of tunes or the other, never a mixture: a reload swaps in a
+// whole new table.
+
+use crate::mml::{Dialect, Timing};
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+const EXTENSION: &str = "mml";
+
+pub struct Tune {
+    pub melody: Arc<str>,
+    // Playing time in the server's dialect, on its backend.
+    pub duration: Duration,
+}
+
+// Name to tune, in name order.
+pub type Table = BTreeMap<String, Tune>;
+
+// What a poll compares: each tune file's name, size and modification time.
+type Fingerprint = Vec<(String, u64, Option<SystemTime>)>;
+
+pub struct Tunes {
+    dir: PathBuf,
+    max_length: usize,
+    dialect: Dialect,
+    timing: Timing,
+    table: Mutex<Arc<Table>>,
+    fingerprint: Mutex<Fingerprint>,
+}
+
+impl Tunes {
+    // Read `dir`, which is made absolute first so that a daemon's chdir to
+    // / does not move it, skipping tunes longer than `max_length` bytes.
+    // Durations are those of `dialect`, played with `timing`.
+    pub fn load(dir: &str, max_length: usize, dialect: Dialect, timing: Timing) -> Result<Self, String> {
+        let dir = std::fs::canonicalize(dir).map_err(|e| format!("{}: {}", dir, e))?;
+        let (table, fingerprint) = read(&dir, max_length, dialect, timing)?;
+        info!("Loaded {} tunes from {}", table.len(), dir.display());
+        Ok(Tunes {
+            dir,
+            max_length,
+            dialect,
+            timing,
+            table: Mutex::new(Arc::new(table)),
+            fingerprint: Mutex::new(fingerprint),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<str>> {
+        self.table.lock().unwrap().get(name).map(|tune| Arc::clone(&tune.melody))
+    }
+
+    pub fn table(&self) -> Arc<Table> {
+        Arc::clone(&self.table.lock().unwrap())
+    }
+
+    // Read the directory again, keeping the current tunes if it cannot be
+    // read. Returns the number of tunes now loaded.
+    pub fn reload(&self) -> Result<usize, String> {
+        let (table, fingerprint) = read(&self.dir, self.max_length, self.dialect, self.timing)?;
+        let count = table.len();
+        *self.table.lock().unwrap() = Arc::new(table);
+        *self.fingerprint.lock().unwrap() = fingerprint;
+        info!("Reloaded {} tunes from {}", count, self.dir.display());
+        Ok(count)
+    }
+
+    // Whether the tune files differ from those last read.
+    fn changed(&self) -> bool {
+        match fingerprint(&self.dir) {
+            Ok((now, _)) => now != *self.fingerprint.lock().unwrap(),
+            Err(_) => false,
+        }
+    }
+}
+
+// Reload `tunes` on SIGHUP and when its files change, until the task is
+// dropped. Run by server::run; the polling, reading and rendering happen
+// on a blocking thread.
+pub async fn watch(tunes: Arc<Tunes>) {
+    let mut hangup = signal(SignalKind::hangup())
+        .map_err(|e| warn!("Cannot catch SIGHUP, tunes reload on change only: {}", e))
+        .ok();
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        let hangup = async {
+            match hangup.as_mut() {
+                Some(hangup) => hangup.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = poll.tick() => {
+                let polling = Arc::clone(&tunes);
+                if !tokio::task::spawn_blocking(move || polling.changed()).await.unwrap_or(false) {
+                    continue;
+                }
+            }
+            _ = hangup => {}
+        }
+        let reloading = Arc::clone(&tunes);
+        match tokio::task::spawn_blocking(move || reloading.reload()).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => warn!("Keeping the tunes loaded before: {}", e),
+            Err(e) => warn!("Keeping the tunes loaded before: {}", e),
+        }
+    }
+}
+
+fn read(dir: &Path, max_length: usize, dialect: Dialect, timing: Timing) -> Result<(Table, Fingerprint), String> {
+    let (fingerprint, invalid) = fingerprint(dir)?;
+    for path in invalid {
+        warn!("Skipping tune {}: not a valid tune name", path.display());
+    }
+    let mut table = Table::new();
+    for (name, len, _) in &fingerprint {
+        let path = dir.join(format!("{}.{}", name, EXTENSION));
+        if *len > max_length as u64 {
+            warn!("Skipping tune {}: longer than {} bytes", path.display(), max_length);
+            continue;
+        }
+        // Read no more than the limit, in case the file grew since.
+        let mut melody = String::new();
+        let read = std::fs::File::open(&path)
+            .and_then(|file| file.take(max_length as u64 + 1).read_to_string(&mut melody));
+        match read {
+            Ok(_) if melody.len() > max_length => {
+                warn!("Skipping tune {}: longer than {} bytes", path.display(), max_length)
+            }
+            Ok(_) => {
+                let duration = dialect.playing_time_with(&melody, timing);
+                table.insert(
+                    name.clone(),
+                    Tune {
+                        melody: melody.into(),
+                        duration,
+                    },
+                );
+            }
+            Err(e) => warn!("Skipping tune {}: {}", path.display(), e),
+        }
+    }
+    Ok((table, fingerprint))
+}
+
+// Also returns the tune files whose names are not valid, which read() warns
+// about; a poll skips them silently, rather than every POLL_INTERVAL.
+fn fingerprint(dir: &Path) -> Result<(Fingerprint, Vec<PathBuf>), String> {
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let mut fingerprint = Vec::new();
+    let mut invalid = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata() else { continue };
+        if !metadata.is_file() {
+            continue;
+        }
+        match path.file_stem().and_then(|s| s.to_str()).filter(|s| valid_name(s)) {
+            Some(name) => fingerprint.push((name.to_string(), metadata.len(), metadata.modified().ok())),
+            None => invalid.push(path),
+        }
+    }
+    fingerprint.sort();
+    Ok((fingerprint, invalid))
+}
+
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn loads_and_reloads_a_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("success.mml"), "t200 l16 c e g >c").unwrap();
+        fs::write(dir.path().join("build-failed.mml"), "t90 o2 c p c").unwrap();
+        fs::write(dir.path().join("notes.txt"), "not a tune").unwrap();
+        fs::write(dir.path().join("bad name.mml"), "c").unwrap();
+        fs::write(dir.path().join("latin1.mml"), b"c\xe9").unwrap();
+        fs::create_dir(dir.path().join("sub.mml")).unwrap();
+        fs::write(dir.path().join("long.mml"), "c".repeat(33)).unwrap();
+
+        let load = |path: &Path| Tunes::load(path.to_str().unwrap(), 32, Dialect::default(), Timing::default());
+        let tunes = load(dir.path()).unwrap();
+        assert_eq!(tunes.table().keys().collect::<Vec<_>>(), ["build-failed", "success"]);
+        assert_eq!(tunes.get("success").as_deref(), Some("t200 l16 c e g >c"));
+        assert_eq!(
+            tunes.table()["build-failed"].duration,
+            crate::mml::playing_time("t90 o2 c p c")
+        );
+        assert!(tunes.get("notes").is_none());
+        assert!(!tunes.changed());
+
+        // Timed as the backend plays them.
+        let precise = Tunes::load(dir.path().to_str().unwrap(), 32, Dialect::default(), Timing::Precise).unwrap();
+        let duration = Dialect::default().playing_time_with("t90 o2 c p c", Timing::Precise);
+        assert_eq!(precise.table()["build-failed"].duration, duration);
+        assert_ne!(duration, crate::mml::playing_time("t90 o2 c p c"));
+
+        // A new file, a changed one and a removed one are all changes.
+        fs::write(dir.path().join("ding.mml"), "o4 g").unwrap();
+        assert!(tunes.changed());
+        assert_eq!(tunes.reload(), Ok(3));
+        assert!(!tunes.changed());
+        fs::write(dir.path().join("success.mml"), "t200 l16 c e g >c e").unwrap();
+        assert!(tunes.changed());
+        fs::remove_file(dir.path().join("build-failed.mml")).unwrap();
+        assert_eq!(tunes.reload(), Ok(2));
+        assert_eq!(tunes.get("success").as_deref(), Some("t200 l16 c e g >c e"));
+
+        // The directory going away keeps what was loaded.
+        let path = dir.path().to_path_buf();
+        dir.close().unwrap();
+        assert!(tunes.reload().unwrap_err().contains(&path.display().to_string()));
+        assert_eq!(tunes.table().len(), 2);
+        assert!(load(&path).is_err());
+
+        assert!(valid_name("build-failed_2.v1"));
+        for name in ["", ".hidden", "a b", "a/b", "ü"] {
+            assert!(!valid_name(name), "{:?}", name);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+// Tunings for the synthesising backends (--tuning, --reference-pitch). The
+// mml module renders pitches through a Tuning: the default, `spkr`, is
+// spkr.c's A440 equal-tempered table rounded to whole hertz, so melodies
+// play exactly as the kernel's do. The others compute fractional
+// frequencies:
+//
+//   spkr         spkr.c's table, scaled by the reference pitch over 440;
+//                past its ends (the extended range), A440 equal
+//                temperament rounded to whole hertz, as the table is
+//   equal        twelve-tone equal temperament, unrounded
+//   just         5-limit just intonation on C
+//   pythagorean  Pythagorean tuning on C (fifths of 3/2, the wolf at F#)
+//   FILE.scl     any scale in the Scala format, laid out on the keys from C
+//
+// The reference pitch is always A4's frequency; the scales are built up
+// from whatever C that puts under it. A Scala scale of other than twelve
+// notes maps its degrees onto consecutive keys, so C, C#, D... are degrees
+// 0, 1, 2... and the scale repeats at its period (its last line) after
+// that many keys, as Scala's default keyboard mapping does.
+
+use crate::mml::PITCHTAB;
+use serde::Serialize;
+use std::fmt;
+use std::sync::Arc;
+
+pub const DEFAULT_TUNING: &str = "spkr";
+pub const DEFAULT_REFERENCE_HZ: f64 = 440.0;
+
+// An octave either side of A440, which covers every concert pitch there
+// has been.
+pub const MIN_REFERENCE_HZ: f64 = 220.0;
+pub const MAX_REFERENCE_HZ: f64 = 880.0;
+
+// Scala files describe scales of up to a few hundred notes; anything much
+// bigger is not a scale.
+pub const MAX_SCALE_NOTES: usize = 1024;
+
+// Where A4 and C4 fall in spkr.c's pitch table, whose index 0 is C2.
+const A4: i32 = 33;
+const C4: i32 = 24;
+
+// name, description: the built-in tunings, as --help and the docs list them.
+pub const TUNINGS: &[(&str, &str)] = &[
+    ("spkr", "spkr.c's A440 table, rounded to whole Hz"),
+    ("equal", "twelve-tone equal temperament"),
+    ("just", "5-limit just intonation on C"),
+    ("pythagorean", "Pythagorean tuning on C"),
+];
+
+const JUST: [f64; 12] = [
+    1.0,
+    16.0 / 15.0,
+    9.0 / 8.0,
+    6.0 / 5.0,
+    5.0 / 4.0,
+    4.0 / 3.0,
+    45.0 / 32.0,
+    3.0 / 2.0,
+    8.0 / 5.0,
+    5.0 / 3.0,
+    9.0 / 5.0,
+    15.0 / 8.0,
+];
+
+const PYTHAGOREAN: [f64; 12] = [
+    1.0,
+    256.0 / 243.0,
+    9.0 / 8.0,
+    32.0 / 27.0,
+    81.0 / 64.0,
+    4.0 / 3.0,
+    729.0 / 512.0,
+    3.0 / 2.0,
+    128.0 / 81.0,
+    27.0 / 16.0,
+    16.0 / 9.0,
+    243.0 / 128.0,
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Tuning {
+    // The built-in tuning's name, or the path of the .scl file.
+    pub name: String,
+    pub reference_hz: f64,
+    // Each degree's ratio to the first, which is 1, and the ratio the
+    // scale repeats at. Empty for spkr, which looks its table up instead.
+    #[serde(skip)]
+    degrees: Arc<[f64]>,
+    #[serde(skip)]
+    period: f64,
+}
+
+impl Tuning {
+    pub fn builtin(name: &str, reference_hz: f64) -> Option<Self> {
+        let degrees: Arc<[f64]> = match name {
+            "spkr" => Arc::new([]),
+            "equal" => (0..12).map(|k| 2f64.powf(f64::from(k) / 12.0)).collect(),
+            "just" => Arc::new(JUST),
+            "pythagorean" => Arc::new(PYTHAGOREAN),
+            _ => return None,
+        };
+        Some(Tuning {
+            name: name.to_string(),
+            reference_hz,
+            degrees,
+            period: 2.0,
+        })
+    }
+
+    // A built-in name, or else the path of a Scala file.
+    pub fn load(name_or_path: &str, reference_hz: f64) -> Result<Self, String> {
+        check_reference(reference_hz)?;
+        if let Some(tuning) = Self::builtin(name_or_path, reference_hz) {
+            return Ok(tuning);
+        }
+        let text = std::fs::read_to_string(name_or_path).map_err(|e| {
+            let names: Vec<&str> = TUNINGS.iter().map(|&(name, _)| name).collect();
+            format!("{}: {} (and not a tuning: {})", name_or_path, e, names.join(", "))
+        })?;
+        Self::parse_scl(name_or_path, &text, reference_hz)
+    }
+
+    // A scale in the Scala format: `!` comment lines, a description line,
+    // the number of notes, then one pitch per line — cents if it has a
+    // `.`, else a ratio such as 3/2 or 2 — of which the last is the period.
+    pub fn parse_scl(name: &str, text: &str, reference_hz: f64) -> Result<Self, String> {
+        let err = |message: String| format!("{}: {}", name, message);
+        check_reference(reference_hz).map_err(err)?;
+        let mut lines = text.lines().filter(|line| !line.starts_with('!'));
+        lines.next().ok_or_else(|| err("missing description line".to_string()))?;
+        let count = lines
+            .next()
+            .and_then(|line| line.split_whitespace().next())
+            .and_then(|count| count.parse::<usize>().ok())
+            .ok_or_else(|| err("missing note count".to_string()))?;
+        if !(1..=MAX_SCALE_NOTES).contains(&count) {
+            return Err(err(format!("note count {} is outside 1..={}", count, MAX_SCALE_NOTES)));
+        }
+        let mut degrees = vec![1.0];
+        for _ in 0..count {
+            let line = lines.next().ok_or_else(|| err(format!("fewer than {} notes", count)))?;
+            let pitch = line.split_whitespace().next().unwrap_or("");
+            let ratio = parse_pitch(pitch).ok_or_else(|| err(format!("bad pitch {:?}", pitch)))?;
+            degrees.push(ratio);
+        }
+        let period = degrees.pop().unwrap_or(2.0);
+        if period <= 1.0 {
+            return Err(err(format!("period {} is not above 1/1", period)));
+        }
+        Ok(Tuning {
+            name: name.to_string(),
+            reference_hz,
+            degrees: degrees.into(),
+            period,
+        })
+    }
+
+    // The frequency of spkr.c pitch-table index `pitch` (0 is C2, and
+    // the extended range runs on either side), in Hz.
+    pub fn freq(&self, pitch: i32) -> f64 {
+        if self.degrees.is_empty() {
+            let hz = match usize::try_from(pitch).ok().and_then(|i| PITCHTAB.get(i)) {
+                Some(&hz) => f64::from(hz),
+                None => (DEFAULT_REFERENCE_HZ * 2f64.powf(f64::from(pitch - A4) / 12.0)).round(),
+            };
+            if self.reference_hz == DEFAULT_REFERENCE_HZ {
+                return hz;
+            }
+            return hz * self.reference_hz / DEFAULT_REFERENCE_HZ;
+        }
+        let c4 = self.reference_hz / self.ratio(A4 - C4);
+        c4 * self.ratio(pitch - C4)
+    }
+
+    // The same, in millihertz, as mml::Event carries it.
+    pub fn freq_mhz(&self, pitch: i32) -> u32 {
+        (self.freq(pitch) * 1000.0).round() as u32
+    }
+
+    // Ratio of the key `steps` keys above C4 (below for negative) to C4.
+    fn ratio(&self, steps: i32) -> f64 {
+        let n = self.degrees.len() as i32;
+        self.degrees[steps.rem_euclid(n) as usize] * self.period.powi(steps.div_euclid(n))
+    }
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self::builtin(DEFAULT_TUNING, DEFAULT_REFERENCE_HZ).unwrap()
+    }
+}
+
+impl fmt::Display for Tuning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (A4 = {} Hz)", self.name, self.reference_hz)
+    }
+}
+
+fn check_reference(reference_hz: f64) -> Result<(), String> {
+    if !(MIN_REFERENCE_HZ..=MAX_REFERENCE_HZ).contains(&reference_hz) {
+        return Err(format!(
+            "reference pitch {} Hz is outside {}..={}",
+            reference_hz, MIN_REFERENCE_HZ, MAX_REFERENCE_HZ
+        ));
+    }
+    Ok(())
+}
+
+// One Scala pitch as a frequency ratio: cents (`701.955`), a ratio
+// (`3/2`) or a whole number (`2`, meaning 2/1).
+fn parse_pitch(pitch: &str) -> Option<f64> {
+    let ratio = if pitch.contains('.') {
+        2f64.powf(pitch.parse::<f64>().ok()? / 1200.0)
+    } else if let Some((num, den)) = pitch.split_once('/') {
+        num.parse::<u64>().ok()? as f64 / den.parse::<u64>().ok()? as f64
+    } else {
+        pitch.parse::<u64>().ok()? as f64
+    };
+    (ratio.is_finite() && ratio > 0.0).then_some(ratio)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tunings_and_scala_files() {
+        // The default is spkr.c's table to the hertz.
+        let spkr = Tuning::default();
+        for (pitch, &hz) in PITCHTAB.iter().enumerate() {
+            assert_eq!(spkr.freq_mhz(pitch as i32), hz * 1000);
+        }
+        assert_eq!(Tuning::builtin("spkr", 432.0).unwrap().freq(A4), 432.0);
+
+        for &(name, _) in TUNINGS {
+            let tuning = Tuning::load(name, 415.0).unwrap();
+            assert!((tuning.freq(A4) - 415.0).abs() < 1e-9, "{}", name);
+            assert!((tuning.freq(A4 + 12) - 830.0).abs() < 1e-9, "{}", name);
+        }
+        let equal = Tuning::builtin("equal", 440.0).unwrap();
+        assert!((equal.freq(C4) - 261.6256).abs() < 1e-3);
+        assert!((equal.freq(0) - 65.4064).abs() < 1e-3);
+        let just = Tuning::builtin("just", 440.0).unwrap();
+        assert!((just.freq(C4) - 264.0).abs() < 1e-9);
+        assert!((just.freq(C4 + 7) - 396.0).abs() < 1e-9);
+        let pythagorean = Tuning::builtin("pythagorean", 440.0).unwrap();
+        assert!((pythagorean.freq(C4 + 7) / pythagorean.freq(C4) - 1.5).abs() < 1e-12);
+
+        // A five-note scale: C..E are its degrees, F starts the next period.
+        let scl = "! pentatonic.scl\n!\nSlendro-ish\n 5\n!\n 240.0\n480.0 cents\n720.\n 960.0\n 2/1\n";
+        let slendro = Tuning::parse_scl("pentatonic.scl", scl, 440.0).unwrap();
+        let step = 2f64.powf(240.0 / 1200.0);
+        assert!((slendro.freq(C4 + 1) / slendro.freq(C4) - step).abs() < 1e-9);
+        assert!((slendro.freq(C4 + 5) / slendro.freq(C4) - 2.0).abs() < 1e-9);
+        assert!((slendro.freq(A4) - 440.0).abs() < 1e-9);
+        assert_eq!(slendro.to_string(), "pentatonic.scl (A4 = 440 Hz)");
+
+        for bad in ["", "desc\n", "desc\n0\n", "desc\n2\n3/2\n", "desc\n1\nfoo\n", "desc\n1\n1/0\n", "desc\n1\n-100.0\n"] {
+            assert!(Tuning::parse_scl("bad.scl", bad, 440.0).is_err(), "{:?}", bad);
+        }
+        assert!(Tuning::load("equal", 100.0).unwrap_err().contains("220..=880"));
+        assert!(Tuning::load("no-such-tuning", 440.0).unwrap_err().contains("spkr, equal"));
+    }
+}
@@ -0,0 +1,162 @@
+// RIFF/WAVE encoding of the synth module's output, for POST /render.wav,
+// `spkrd render` and the file backend. The format needed is the plainest
+// one there is — a single 16-bit PCM channel — so the 44-byte header is
+// written by hand rather than pulling in an audio-file crate. The same
+// format is read back for --wavetable, from files other tools wrote.
+
+use crate::mml::Event;
+use crate::synth::{self, Sound};
+
+// Sample rates accepted for rendering: telephone quality up to the highest
+// rate common sound cards play.
+pub const MIN_RATE: u32 = 8000;
+pub const MAX_RATE: u32 = 192_000;
+
+pub const HEADER_LEN: usize = 44;
+
+// Most sample bytes a file can hold: the RIFF chunk size, 36 more than
+// this, is a u32.
+pub const MAX_DATA_LEN: u32 = u32::MAX - 36;
+
+// Synthesise and mix `voices` and encode them as a WAV file.
+pub fn render(voices: &[Vec<Event>], sample_rate: u32, sound: &Sound) -> Vec<u8> {
+    encode(&synth::mix(voices, sample_rate, sound), sample_rate)
+}
+
+// Encode mono samples in [-1.0, 1.0] as 16-bit PCM; anything outside is
+// clipped.
+pub fn encode(samples: &[f32], sample_rate: u32) -> Vec<u8> {
+    let data_len = samples.len() * 2;
+    let mut out = Vec::with_capacity(HEADER_LEN + data_len);
+    out.extend_from_slice(&header(sample_rate, data_len as u32));
+    pcm_into(samples, &mut out);
+    out
+}
+
+// The samples alone, as they follow the header: 16-bit little-endian, which
+// is also the raw PCM format of the file backend.
+pub fn pcm(samples: &[f32]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(samples.len() * 2);
+    pcm_into(samples, &mut out);
+    out
+}
+
+fn pcm_into(samples: &[f32], out: &mut Vec<u8>) {
+    for s in samples {
+        let v = (s.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+}
+
+// The header of a file holding `data_len` bytes of samples, at most
+// MAX_DATA_LEN. The file backend rewrites it as a file grows.
+pub fn header(sample_rate: u32, data_len: u32) -> [u8; HEADER_LEN] {
+    let mut out = [0; HEADER_LEN];
+    out[0..4].copy_from_slice(b"RIFF");
+    out[4..8].copy_from_slice(&(36 + data_len).to_le_bytes());
+    out[8..12].copy_from_slice(b"WAVE");
+
+    out[12..16].copy_from_slice(b"fmt ");
+    out[16..20].copy_from_slice(&16u32.to_le_bytes());
+    out[20..22].copy_from_slice(&1u16.to_le_bytes()); // PCM
+    out[22..24].copy_from_slice(&1u16.to_le_bytes()); // channels
+    out[24..28].copy_from_slice(&sample_rate.to_le_bytes());
+    out[28..32].copy_from_slice(&(sample_rate * 2).to_le_bytes()); // bytes per second
+    out[32..34].copy_from_slice(&2u16.to_le_bytes()); // bytes per frame
+    out[34..36].copy_from_slice(&16u16.to_le_bytes()); // bits per sample
+
+    out[36..40].copy_from_slice(b"data");
+    out[40..44].copy_from_slice(&data_len.to_le_bytes());
+    out
+}
+
+// The sample rate and data length of a header this module wrote; None for
+// anything else, including WAV files laid out differently.
+pub fn parse_header(bytes: &[u8]) -> Option<(u32, u32)> {
+    let sample_rate = u32::from_le_bytes(bytes.get(24..28)?.try_into().ok()?);
+    let data_len = u32::from_le_bytes(bytes.get(40..44)?.try_into().ok()?);
+    if !(MIN_RATE..=MAX_RATE).contains(&sample_rate) || data_len > MAX_DATA_LEN {
+        return None;
+    }
+    (bytes[..HEADER_LEN] == header(sample_rate, data_len)).then_some((sample_rate, data_len))
+}
+
+// The sample rate and samples of a 16-bit mono PCM WAV file, whatever
+// other chunks it has; None for anything else.
+pub fn read_pcm(bytes: &[u8]) -> Option<(u32, Vec<f32>)> {
+    if bytes.get(0..4)? != b"RIFF" || bytes.get(8..12)? != b"WAVE" {
+        return None;
+    }
+    let u16_at = |b: &[u8], i: usize| Some(u16::from_le_bytes(b.get(i..i + 2)?.try_into().ok()?));
+    let u32_at = |b: &[u8], i: usize| Some(u32::from_le_bytes(b.get(i..i + 4)?.try_into().ok()?));
+    let mut rate = None;
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let id = &bytes[pos..pos + 4];
+        let len = u32_at(bytes, pos + 4)? as usize;
+        let body = bytes.get(pos + 8..(pos + 8).checked_add(len)?)?;
+        match id {
+            b"fmt " => {
+                // PCM, one channel, 16 bits.
+                if (u16_at(body, 0)?, u16_at(body, 2)?, u16_at(body, 14)?) != (1, 1, 16) {
+                    return None;
+                }
+                rate = Some(u32_at(body, 4)?);
+            }
+            b"data" => {
+                let samples = body
+                    .chunks_exact(2)
+                    .map(|c| f32::from(i16::from_le_bytes([c[0], c[1]])) / i16::MAX as f32)
+                    .collect();
+                return Some((rate?, samples));
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even length.
+        pos += 8 + len + len % 2;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(b: &[u8], i: usize) -> u32 {
+        u32::from_le_bytes(b[i..i + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn header_describes_the_data() {
+        let wav = encode(&[0.0, 1.0, -1.0, 2.0], 48000);
+        assert_eq!(wav.len(), HEADER_LEN + 8);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32_at(&wav, 4) as usize, wav.len() - 8);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&wav, 24), 48000);
+        assert_eq!(u32_at(&wav, 28), 96000);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32_at(&wav, 40), 8);
+        let samples: Vec<i16> = wav[HEADER_LEN..]
+            .chunks(2)
+            .map(|c| i16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        assert_eq!(samples, [0, i16::MAX, -i16::MAX, i16::MAX]);
+
+        assert_eq!(parse_header(&wav), Some((48000, 8)));
+        assert_eq!(parse_header(&wav[..40]), None);
+        assert_eq!(parse_header(&encode(&[], 8000)), Some((8000, 0)));
+        let mut stereo = wav.clone();
+        stereo[22] = 2;
+        assert_eq!(parse_header(&stereo), None);
+
+        // Reading skips chunks it has no use for.
+        let (rate, samples) = read_pcm(&wav).unwrap();
+        assert_eq!((rate, samples), (48000, vec![0.0, 1.0, -1.0, 1.0]));
+        let mut listed = wav[..36].to_vec();
+        listed.extend_from_slice(b"LIST\x03\0\0\0abc\0");
+        listed.extend_from_slice(&wav[36..]);
+        assert_eq!(read_pcm(&listed), read_pcm(&wav));
+        assert_eq!(read_pcm(&stereo), None);
+    }
+}
@@ -1,5 +1,6 @@
 // Integration tests for spkrd server using temporary files as mock devices
 
+use spkrd::server::Config;
 use std::fs;
 use std::net::SocketAddr;
 use std::time::Duration;
@@ -15,7 +16,7 @@ async fn test_server_with_file_device() {
     let port = find_available_port().await;
     let server_handle = tokio::spawn(async move {
         let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
-        let _ = spkrd::server::run(vec![SocketAddr::from(([0, 0, 0, 0], port))], Duration::from_secs(30), backend, 1000, false).await;
+        let _ = spkrd::server::run(vec![SocketAddr::from(([0, 0, 0, 0], port))], backend, Config::default()).await;
     });
 
     // Wait a moment for the server to start
@@ -55,7 +56,7 @@ async fn test_melody_validation() {
     let port = find_available_port().await;
     let server_handle = tokio::spawn(async move {
         let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
-        let _ = spkrd::server::run(vec![SocketAddr::from(([0, 0, 0, 0], port))], Duration::from_secs(30), backend, 1000, false).await;
+        let _ = spkrd::server::run(vec![SocketAddr::from(([0, 0, 0, 0], port))], backend, Config::default()).await;
     });
 
     // Wait a moment for the server to start
@@ -96,7 +97,7 @@ async fn test_multiple_requests() {
     let port = find_available_port().await;
     let server_handle = tokio::spawn(async move {
         let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
-        let _ = spkrd::server::run(vec![SocketAddr::from(([0, 0, 0, 0], port))], Duration::from_secs(30), backend, 1000, false).await;
+        let _ = spkrd::server::run(vec![SocketAddr::from(([0, 0, 0, 0], port))], backend, Config::default()).await;
     });
 
     // Wait a moment for the server to start
@@ -143,7 +144,7 @@ async fn test_invalid_utf8() {
     let port = find_available_port().await;
     let server_handle = tokio::spawn(async move {
         let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
-        let _ = spkrd::server::run(vec![SocketAddr::from(([0, 0, 0, 0], port))], Duration::from_secs(30), backend, 1000, false).await;
+        let _ = spkrd::server::run(vec![SocketAddr::from(([0, 0, 0, 0], port))], backend, Config::default()).await;
     });
 
     // Wait a moment for the server to start
@@ -195,7 +196,7 @@ async fn test_dual_stack_wildcard_bind() {
     let (err_tx, mut err_rx) = tokio::sync::oneshot::channel();
     let server_handle = tokio::spawn(async move {
         let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
-        if let Err(e) = spkrd::server::run(addrs, Duration::from_secs(30), backend, 1000, false).await
+        if let Err(e) = spkrd::server::run(addrs, backend, Config::default()).await
         {
             let _ = err_tx.send(e.to_string());
         }
@@ -238,6 +239,1459 @@ async fn test_dual_stack_wildcard_bind() {
     server_handle.abort();
 }
 
+#[tokio::test]
+async fn test_strict_query_parameter() {
+    let temp_file = NamedTempFile::new().expect("Failed to create temp file");
+    let device_path = temp_file.path().to_string_lossy().to_string();
+
+    let port = find_available_port().await;
+    let server_handle = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
+        let _ = spkrd::server::run(vec![SocketAddr::from(([0, 0, 0, 0], port))], backend, Config::default()).await;
+    });
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = reqwest::Client::new();
+    let garbage = "{\"melody\": 1}";
+
+    // ?strict=1 rejects the JSON blob before the device is opened, and
+    // reports where the first problem is.
+    let response = client
+        .put(format!("http://127.0.0.1:{}/play?strict=1", port))
+        .body(garbage)
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status(), 400);
+    let error_message = response.text().await.unwrap();
+    assert!(error_message.starts_with("byte 0: error:"), "{}", error_message);
+    let file_contents = fs::read_to_string(temp_file.path()).expect("Failed to read temp file");
+    assert_eq!(file_contents, "");
+
+    // Without it, the body is forwarded verbatim as before.
+    let response = client
+        .put(format!("http://127.0.0.1:{}/play", port))
+        .body(garbage)
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status(), 200);
+    let file_contents = fs::read_to_string(temp_file.path()).expect("Failed to read temp file");
+    assert_eq!(file_contents, garbage);
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_strict_server_flag() {
+    let temp_file = NamedTempFile::new().expect("Failed to create temp file");
+    let device_path = temp_file.path().to_string_lossy().to_string();
+
+    let port = find_available_port().await;
+    let server_handle = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
+        let _ = spkrd::server::run(vec![SocketAddr::from(([0, 0, 0, 0], port))], backend, Config { mml_strict: true, ..Config::default() }).await;
+    });
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = reqwest::Client::new();
+
+    // Out-of-range tempo: the kernel would silently play it at T120.
+    // ?strict=0 does not relax a server-wide --mml-strict.
+    let response = client
+        .put(format!("http://127.0.0.1:{}/play?strict=0", port))
+        .body("cT300d")
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status(), 400);
+    assert_eq!(
+        response.text().await.unwrap(),
+        "byte 1: warning: T300 out of range 32..=255, using 120"
+    );
+
+    let response = client
+        .put(format!("http://127.0.0.1:{}/play", port))
+        .body("t120 l8 cdefgab")
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status(), 200);
+    let file_contents = fs::read_to_string(temp_file.path()).expect("Failed to read temp file");
+    assert_eq!(file_contents, "t120 l8 cdefgab");
+
+    server_handle.abort();
+}
+
+// A FIFO as the mock device makes playback block until the test reads it,
+// so requests pile up in the play queue. Checks that they are served in
+// arrival order and that one past --queue-depth is refused at once. The
+// multi-threaded runtime is needed because the backend's open() of the
+// FIFO blocks a worker until a reader appears.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_play_queue_order_and_depth() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let fifo = dir.path().join("speaker");
+    let status = std::process::Command::new("mkfifo")
+        .arg(&fifo)
+        .status()
+        .expect("Failed to run mkfifo");
+    assert!(status.success());
+    let device_path = fifo.to_string_lossy().to_string();
+
+    let port = find_available_port().await;
+    let server_handle = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
+        let config = Config { queue_depth: 1, ..Config::default() };
+        let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], port))], backend, config).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let url = format!("http://127.0.0.1:{}/play", port);
+    let send = |melody: &'static str| {
+        let url = url.clone();
+        tokio::spawn(async move {
+            reqwest::Client::new()
+                .put(&url)
+                .body(melody)
+                .send()
+                .await
+                .expect("Failed to send request")
+                .status()
+        })
+    };
+
+    // First plays (blocked on the FIFO), second waits behind it.
+    let first = send("cde");
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let second = send("fga");
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // The queue is full: refused without waiting for --retry-timeout.
+    let started = std::time::Instant::now();
+    let third = send("bag").await.unwrap();
+    assert_eq!(third, 503);
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // Drain the device. The second writer may open the FIFO before this
+    // reader sees EOF, so keep reading until both melodies have arrived.
+    let played = tokio::task::spawn_blocking(move || {
+        let mut played = String::new();
+        while played.len() < "cdefga".len() {
+            played += &fs::read_to_string(&fifo).expect("Failed to read FIFO");
+        }
+        played
+    })
+    .await
+    .unwrap();
+    assert_eq!(played, "cdefga");
+    assert_eq!(first.await.unwrap(), 200);
+    assert_eq!(second.await.unwrap(), 200);
+
+    server_handle.abort();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_async_jobs() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let fifo = dir.path().join("speaker");
+    let status = std::process::Command::new("mkfifo")
+        .arg(&fifo)
+        .status()
+        .expect("Failed to run mkfifo");
+    assert!(status.success());
+    let device_path = fifo.to_string_lossy().to_string();
+
+    let port = find_available_port().await;
+    let server_handle = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
+        let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], port))], backend, Config::default()).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let base = format!("http://127.0.0.1:{}", port);
+    let client = reqwest::Client::new();
+    let job = |response: reqwest::Response| async move {
+        let body = response.text().await.expect("Failed to read job status");
+        serde_json::from_str::<serde_json::Value>(&body).expect("Failed to parse job status")
+    };
+
+    // Accepted at once, although nothing is reading the device yet.
+    let response = client.post(format!("{}/jobs", base)).body("cde").send().await.unwrap();
+    assert_eq!(response.status(), 202);
+    let location = response.headers()["location"].to_str().unwrap().to_string();
+    let first = job(response).await;
+    assert_eq!(location, format!("/jobs/{}", first["id"]));
+    assert_eq!(first["state"], "queued");
+    assert_eq!(first["melody_bytes"], 3);
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let first = job(client.get(format!("{}{}", base, location)).send().await.unwrap()).await;
+    assert_eq!(first["state"], "playing");
+    assert!(first["started_at"].is_string());
+
+    // The second waits behind the first and can be cancelled, once.
+    let response = client.put(format!("{}/play?async=1", base)).body("fga").send().await.unwrap();
+    assert_eq!(response.status(), 202);
+    let second = format!("{}/jobs/{}", base, job(response).await["id"]);
+    let response = client.delete(&second).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(job(response).await["state"], "cancelled");
+    assert_eq!(client.delete(&second).send().await.unwrap().status(), 409);
+    assert_eq!(client.get(format!("{}/jobs/999", base)).send().await.unwrap().status(), 404);
+
+    // Invalid melodies are refused before a job is created.
+    let response = client.post(format!("{}/jobs", base)).body("a".repeat(1001)).send().await.unwrap();
+    assert_eq!(response.status(), 400);
+
+    let played = tokio::task::spawn_blocking(move || fs::read_to_string(&fifo).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(played, "cde");
+
+    let mut first = first;
+    for _ in 0..50 {
+        first = job(client.get(format!("{}{}", base, location)).send().await.unwrap()).await;
+        if first["state"] != "playing" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(first["state"], "done");
+    assert_eq!(first["retries"], 0);
+    assert!(first["finished_at"].is_string());
+    assert!(first["error"].is_null());
+
+    server_handle.abort();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_stop_endpoint() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let fifo = dir.path().join("speaker");
+    let status = std::process::Command::new("mkfifo")
+        .arg(&fifo)
+        .status()
+        .expect("Failed to run mkfifo");
+    assert!(status.success());
+    let device_path = fifo.to_string_lossy().to_string();
+
+    // The player stays blocked opening the FIFO until this reader is
+    // released. Dropping `release`, as a failed assert does too, lets it in,
+    // so the player is never left waiting and the suite cannot hang.
+    let (release, released) = std::sync::mpsc::channel::<()>();
+    let reader = std::thread::spawn(move || {
+        let _ = released.recv();
+        fs::read_to_string(&fifo).unwrap()
+    });
+
+    let port = find_available_port().await;
+    let server_handle = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
+        let config = Config { admin_token: Some("s3cret".to_string()), ..Config::default() };
+        let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], port))], backend, config).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let base = format!("http://127.0.0.1:{}", port);
+    let client = reqwest::Client::new();
+    let stop = |token: Option<&'static str>| {
+        let mut request = client.post(format!("{}/stop", base));
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        request.send()
+    };
+
+    // Playing, blocked until the device is read.
+    let url = format!("{}/play", base);
+    let playing = tokio::spawn(async move {
+        reqwest::Client::new().put(&url).body("cde").send().await.unwrap().status()
+    });
+    let status_url = format!("{}/status", base);
+    for _ in 0..100 {
+        let text = client.get(&status_url).send().await.unwrap().text().await.unwrap();
+        let status: serde_json::Value = serde_json::from_str(&text).unwrap();
+        if !status["playing"].is_null() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    assert_eq!(stop(None).await.unwrap().status(), 401);
+    assert_eq!(stop(Some("wrong")).await.unwrap().status(), 401);
+    assert_eq!(stop(Some("s3creT")).await.unwrap().status(), 401);
+
+    let response = stop(Some("s3cret")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["interrupted"]["client"], "127.0.0.1");
+    assert_eq!(body["interrupted"]["melody_bytes"], 3);
+
+    // Nothing more is written, and the interrupted client is told.
+    drop(release);
+    let played = tokio::task::spawn_blocking(move || reader.join().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(played, "");
+    assert_eq!(playing.await.unwrap(), 409);
+
+    let response = stop(Some("s3cret")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert!(body["interrupted"].is_null());
+
+    server_handle.abort();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_status_endpoint() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let fifo = dir.path().join("speaker");
+    let status = std::process::Command::new("mkfifo")
+        .arg(&fifo)
+        .status()
+        .expect("Failed to run mkfifo");
+    assert!(status.success());
+    let device_path = fifo.to_string_lossy().to_string();
+
+    let port = find_available_port().await;
+    let server_device = device_path.clone();
+    let server_handle = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::FreebsdSpeaker { device_path: server_device };
+        let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], port))], backend, Config::default()).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let status_url = format!("http://127.0.0.1:{}/status", port);
+    let get_status = || async {
+        let response = reqwest::get(&status_url).await.unwrap();
+        assert_eq!(response.status(), 200);
+        serde_json::from_str::<serde_json::Value>(&response.text().await.unwrap()).unwrap()
+    };
+
+    let status = get_status().await;
+    assert_eq!(status["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(status["backend"]["type"], "freebsd-speaker");
+    assert_eq!(status["backend"]["device_path"], device_path.as_str());
+    assert_eq!(status["backend"]["spkrtone"], false);
+    assert_eq!(status["queue"]["depth"], 16);
+    assert!(status["playing"].is_null());
+
+    // Blocked opening the device, so still at the start of the melody.
+    let url = format!("http://127.0.0.1:{}/play", port);
+    let playing = tokio::spawn(async move {
+        reqwest::Client::new().put(&url).body("cde").send().await.unwrap().status()
+    });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let status = get_status().await;
+    assert_eq!(status["playing"]["client"], "127.0.0.1");
+    assert_eq!(status["playing"]["melody_bytes"], 3);
+    assert_eq!(status["playing"]["duration_ms"], 1500);
+    assert!(status["playing"]["remaining_ms"].as_u64().unwrap() <= 1500);
+    assert_eq!(status["queue"]["waiting"], 0);
+
+    tokio::task::spawn_blocking(move || fs::read_to_string(&fifo).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(playing.await.unwrap(), 200);
+    assert!(get_status().await["playing"].is_null());
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint() {
+    let temp_file = NamedTempFile::new().expect("Failed to create temp file");
+    let device_path = temp_file.path().to_string_lossy().to_string();
+
+    let port = find_available_port().await;
+    let server_handle = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
+        let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], port))], backend, Config::default()).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = reqwest::Client::new();
+    let url = format!("http://127.0.0.1:{}/play", port);
+    assert_eq!(client.put(&url).body("cde").send().await.unwrap().status(), 200);
+    assert_eq!(client.put(&url).body("a".repeat(1001)).send().await.unwrap().status(), 400);
+
+    let response = reqwest::get(format!("http://127.0.0.1:{}/metrics", port)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.headers()["content-type"].to_str().unwrap().starts_with("text/plain"));
+    let text = response.text().await.unwrap();
+
+    // Counters are process-wide and other tests run alongside, so only
+    // lower bounds can be checked.
+    let value = |series: &str| -> u64 {
+        let line = text
+            .lines()
+            .find(|l| l.starts_with(series) && l[series.len()..].starts_with(' '))
+            .unwrap_or_else(|| panic!("{} missing from:\n{}", series, text));
+        line[series.len() + 1..].parse().unwrap()
+    };
+    assert!(value("spkrd_requests_total{outcome=\"ok\"}") >= 1);
+    assert!(value("spkrd_requests_total{outcome=\"invalid_melody\"}") >= 1);
+    assert!(value("spkrd_queue_wait_seconds_count") >= 1);
+    assert!(value("spkrd_playback_duration_seconds_bucket{le=\"+Inf\"}") >= 1);
+    value("spkrd_cpal_stream_errors_total{class=\"fatal\"}");
+    assert_eq!(value("spkrd_queue_length"), 0);
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_health_probes() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let device = dir.path().join("speaker");
+    let device_path = device.to_string_lossy().to_string();
+
+    let port = find_available_port().await;
+    let server_handle = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
+        let config = Config { ready_interval: Duration::ZERO, ..Config::default() };
+        let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], port))], backend, config).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let base = format!("http://127.0.0.1:{}", port);
+    let response = reqwest::get(format!("{}/healthz", base)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await.unwrap(), "ok");
+
+    // The device does not exist yet.
+    let response = reqwest::get(format!("{}/readyz", base)).await.unwrap();
+    assert_eq!(response.status(), 503);
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["ready"], false);
+    assert!(body["error"].as_str().unwrap().starts_with("Device error"));
+
+    fs::write(&device, "").unwrap();
+    let response = reqwest::get(format!("{}/readyz", base)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["ready"], true);
+    assert!(body["checked_at"].is_string());
+    // Probing opens the device but writes nothing.
+    assert_eq!(fs::read_to_string(&device).unwrap(), "");
+
+    server_handle.abort();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_render_endpoint() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let device = dir.path().join("speaker");
+    let device_path = device.to_string_lossy().to_string();
+
+    let port = find_available_port().await;
+    let server_handle = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
+        let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], port))], backend, Config::default()).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = reqwest::Client::new();
+    let url = format!("http://127.0.0.1:{}/render", port);
+    let response = client.post(&url).body("t120 c4 p8 e").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["duration_cs"], 125);
+    let events = body["events"].as_array().unwrap();
+    assert_eq!(events.len(), 5);
+    // A normal-articulation note is a tone and a short rest, both from "c4".
+    assert_eq!(events[0]["type"], "tone");
+    assert_eq!(events[0]["freq_hz"], 1047);
+    assert_eq!(events[0]["start_cs"], 0);
+    assert_eq!(events[0]["centisecs"], 44);
+    assert_eq!(events[0]["source"], "c4");
+    assert_eq!(events[0]["source_start"], 5);
+    assert_eq!(events[0]["source_end"], 7);
+    assert_eq!(events[1]["type"], "rest");
+    assert!(events[1].get("freq_hz").is_none());
+    assert_eq!(events[1]["source"], "c4");
+    assert_eq!(events[2]["source"], "p8");
+    assert_eq!(events[2]["start_cs"], 50);
+    assert_eq!(events[3]["source"], "e");
+    assert_eq!(events[3]["start_cs"], 75);
+    assert!(body["diagnostics"].as_array().unwrap().is_empty());
+
+    // Diagnostics are reported; ?strict=1 refuses the melody as /play would.
+    let response = client.post(&url).body("c x").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["diagnostics"][0]["source_start"], 2);
+    let response = client.post(format!("{}?strict=1", url)).body("c x").send().await.unwrap();
+    assert_eq!(response.status(), 400);
+
+    // Nothing was played.
+    assert!(!device.exists());
+
+    server_handle.abort();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_render_wav() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let device = dir.path().join("speaker");
+    let device_path = device.to_string_lossy().to_string();
+
+    let port = find_available_port().await;
+    let server_handle = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
+        let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], port))], backend, Config::default()).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = reqwest::Client::new();
+    let url = format!("http://127.0.0.1:{}/render.wav", port);
+
+    // t120 c4 is 50 centiseconds: 4000 samples at 8 kHz.
+    let response = client
+        .post(format!("{}?rate=8000&waveform=sine", url))
+        .body("t120 c4")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "audio/wav");
+    let wav = response.bytes().await.unwrap();
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(&wav[8..12], b"WAVE");
+    assert_eq!(wav.len(), 44 + 4000 * 2);
+
+    // GET takes the melody from the query string.
+    let response = client.get(format!("{}?melody=t120%20c4&rate=8000", url)).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.bytes().await.unwrap().len(), 44 + 4000 * 2);
+
+    for bad in ["?rate=1000", "?waveform=kazoo", "?strict=1&melody=cx", ""] {
+        let response = client.get(format!("{}{}", url, bad)).send().await.unwrap();
+        assert_eq!(response.status(), 400, "{}", bad);
+    }
+
+    // 45 seconds is too many samples at 192 kHz, but not at 8 kHz.
+    let long = "t32 l1 c c c c c c";
+    let response = client.post(format!("{}?rate=192000", url)).body(long).send().await.unwrap();
+    assert_eq!(response.status(), 400);
+    assert!(response.text().await.unwrap().contains("more than 43 seconds"));
+    let response = client.post(format!("{}?rate=8000", url)).body(long).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    // Nothing was played.
+    assert!(!device.exists());
+
+    server_handle.abort();
+}
+
+#[test]
+fn test_render_subcommand() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let input = dir.path().join("tune.mml");
+    let out = dir.path().join("tune.wav");
+    fs::write(&input, "t120 c4 p4").unwrap();
+
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_spkrd"))
+        .args(["render", "--rate", "8000", "--out"])
+        .arg(&out)
+        .arg(&input)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let wav = fs::read(&out).unwrap();
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(wav.len(), 44 + 8000 * 2);
+
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_spkrd"))
+        .args(["render", "--rate", "1", "--out"])
+        .arg(&out)
+        .arg(&input)
+        .status()
+        .unwrap();
+    assert!(!status.success());
+
+    // A wavetable is played at the note's pitch, and is required by its
+    // waveform.
+    let table = dir.path().join("table.txt");
+    fs::write(&table, "0 1 0 -1").unwrap();
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_spkrd"))
+        .args(["render", "--rate", "8000", "--waveform", "wavetable", "--wavetable"])
+        .arg(&table)
+        .arg("--out")
+        .arg(&out)
+        .arg(&input)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(fs::read(&out).unwrap().len(), 44 + 8000 * 2);
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_spkrd"))
+        .args(["render", "--waveform", "wavetable", "--out"])
+        .arg(&out)
+        .arg(&input)
+        .status()
+        .unwrap();
+    assert!(!status.success());
+
+    // Each speaker profile sounds different; a TOML file is one too.
+    let profile = dir.path().join("speaker.toml");
+    fs::write(&profile, "drive = 1.0\n[[filter]]\ntype = \"lowpass\"\nhz = 2000\nq = 0.7\n").unwrap();
+    let render_with = |speaker: &std::path::Path| {
+        let status = std::process::Command::new(env!("CARGO_BIN_EXE_spkrd"))
+            .args(["render", "--rate", "8000", "--speaker-profile"])
+            .arg(speaker)
+            .arg("--out")
+            .arg(&out)
+            .arg(&input)
+            .status()
+            .unwrap();
+        status.success().then(|| fs::read(&out).unwrap())
+    };
+    let piezo = render_with("piezo".as_ref()).unwrap();
+    let cone = render_with("cone".as_ref()).unwrap();
+    let custom = render_with(&profile).unwrap();
+    assert_eq!(piezo, wav);
+    assert_ne!(cone, piezo);
+    assert_ne!(custom, piezo);
+    assert_eq!(render_with("kazoo".as_ref()), None);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_file_and_null_backends() {
+    use spkrd::file_backend::{FileBackend, FileConfig, FileFormat};
+    use spkrd::synth::Waveform;
+    use std::sync::Arc;
+
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let out = dir.path().join("out.wav");
+    let file_config = FileConfig {
+        path: Some(out.to_string_lossy().to_string()),
+        format: FileFormat::Wav,
+        sample_rate: 8000,
+        waveform: Waveform::Square,
+        volume: 0.25,
+        envelope: None,
+        wavetable: None,
+        speaker: Default::default(),
+        tuning: Default::default(),
+        timing: Default::default(),
+        pace: false,
+    };
+    let null_config = FileConfig {
+        path: None,
+        pace: true,
+        ..file_config.clone()
+    };
+
+    let file_port = find_available_port().await;
+    let null_port = find_available_port().await;
+    let mut servers = Vec::new();
+    for (port, cfg) in [(file_port, file_config), (null_port, null_config)] {
+        servers.push(tokio::spawn(async move {
+            let backend = spkrd::server::Backend::File(Arc::new(FileBackend::new(cfg)));
+            let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], port))], backend, Config::default()).await;
+        }));
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Two melodies of 50 centiseconds (4000 samples each) append to one WAV.
+    let client = reqwest::Client::new();
+    for _ in 0..2 {
+        let response = client
+            .put(format!("http://127.0.0.1:{}/play", file_port))
+            .body("t120 c4")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+    let wav = fs::read(&out).unwrap();
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(wav.len(), 44 + 2 * 4000 * 2);
+    assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()), 2 * 4000 * 2);
+
+    let response = reqwest::get(format!("http://127.0.0.1:{}/status", file_port)).await.unwrap();
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["backend"]["type"], "file");
+    assert_eq!(body["backend"]["config"]["format"], "wav");
+
+    // The paced null sink takes as long as the melody would play.
+    let start = std::time::Instant::now();
+    let response = client
+        .put(format!("http://127.0.0.1:{}/play", null_port))
+        .body("t120 c4")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(start.elapsed() >= Duration::from_millis(450), "{:?}", start.elapsed());
+
+    let response = reqwest::get(format!("http://127.0.0.1:{}/status", null_port)).await.unwrap();
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["backend"]["type"], "null");
+
+    for server in servers {
+        server.abort();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_linux_pcspkr_backend() {
+    use spkrd::linux_pcspkr::{EVENT_LEN, EV_SND, SND_TONE};
+    use spkrd::mml::{self, Event};
+
+    let temp_file = NamedTempFile::new().expect("Failed to create temp file");
+    let device_path = temp_file.path().to_string_lossy().to_string();
+    let port = find_available_port().await;
+    let server_handle = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::LinuxPcspkr { device_path };
+        let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], port))], backend, Config::default()).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let melody = "t255 l32 c p d";
+    let response = reqwest::Client::new()
+        .put(format!("http://127.0.0.1:{}/play", port))
+        .body(melody)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    // One SND_TONE event per tone, one for the rest, and a final silence.
+    let bytes = fs::read(temp_file.path()).unwrap();
+    assert_eq!(bytes.len() % EVENT_LEN, 0);
+    let mut freqs = Vec::new();
+    for record in bytes.chunks(EVENT_LEN) {
+        let tail = &record[EVENT_LEN - 8..];
+        assert_eq!(u16::from_ne_bytes([tail[0], tail[1]]), EV_SND);
+        assert_eq!(u16::from_ne_bytes([tail[2], tail[3]]), SND_TONE);
+        freqs.push(i32::from_ne_bytes(tail[4..8].try_into().unwrap()));
+    }
+    let mut expected: Vec<i32> = mml::render(melody)
+        .iter()
+        .map(|e| match *e {
+            Event::Tone { freq_hz, .. } => freq_hz as i32,
+            Event::Rest { .. } => 0,
+        })
+        .collect();
+    expected.push(0);
+    assert_eq!(freqs, expected);
+
+    let response = reqwest::get(format!("http://127.0.0.1:{}/status", port)).await.unwrap();
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["backend"]["type"], "linux-pcspkr");
+
+    server_handle.abort();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_console_backend_needs_a_console() {
+    // A regular file answers the console ioctls with ENOTTY, which is a
+    // device error rather than something to retry.
+    let temp_file = NamedTempFile::new().expect("Failed to create temp file");
+    let device_path = temp_file.path().to_string_lossy().to_string();
+    let port = find_available_port().await;
+    let server_handle = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::Console { device_path };
+        let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], port))], backend, Config::default()).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::Client::new()
+        .put(format!("http://127.0.0.1:{}/play", port))
+        .body("c")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 500);
+    assert!(fs::read(temp_file.path()).unwrap().is_empty());
+
+    let response = reqwest::get(format!("http://127.0.0.1:{}/status", port)).await.unwrap();
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["backend"]["type"], "console");
+
+    server_handle.abort();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_spkrtone_backend() {
+    // SPKRTONE is a FreeBSD ioctl: elsewhere, and on anything but a
+    // speaker device, the first note fails and nothing is written.
+    let temp_file = NamedTempFile::new().expect("Failed to create temp file");
+    let device_path = temp_file.path().to_string_lossy().to_string();
+    let port = find_available_port().await;
+    let server_handle = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::FreebsdSpkrtone { device_path };
+        let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], port))], backend, Config::default()).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::Client::new()
+        .put(format!("http://127.0.0.1:{}/play", port))
+        .body("cdefgab")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 500);
+    assert!(fs::read(temp_file.path()).unwrap().is_empty());
+
+    let response = reqwest::get(format!("http://127.0.0.1:{}/status", port)).await.unwrap();
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["backend"]["type"], "freebsd-speaker");
+    assert_eq!(body["backend"]["spkrtone"], true);
+
+    server_handle.abort();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_polyphonic_melodies() {
+    use spkrd::file_backend::{FileBackend, FileConfig, FileFormat};
+    use spkrd::synth::Waveform;
+    use std::sync::Arc;
+
+    let temp_file = NamedTempFile::new().expect("Failed to create temp file");
+    let device_path = temp_file.path().to_string_lossy().to_string();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let out = dir.path().join("out.wav");
+    let file_config = FileConfig {
+        path: Some(out.to_string_lossy().to_string()),
+        format: FileFormat::Wav,
+        sample_rate: 8000,
+        waveform: Waveform::Square,
+        volume: 0.25,
+        envelope: None,
+        wavetable: None,
+        speaker: Default::default(),
+        tuning: Default::default(),
+        timing: Default::default(),
+        pace: false,
+    };
+
+    let speaker_port = find_available_port().await;
+    let file_port = find_available_port().await;
+    let backends = [
+        (speaker_port, spkrd::server::Backend::FreebsdSpeaker { device_path }),
+        (file_port, spkrd::server::Backend::File(Arc::new(FileBackend::new(file_config)))),
+    ];
+    let mut servers = Vec::new();
+    for (port, backend) in backends {
+        servers.push(tokio::spawn(async move {
+            let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], port))], backend, Config::default()).await;
+        }));
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Two voices of 50 and 25 centiseconds.
+    let melody = "t120 c4; e8";
+    let client = reqwest::Client::new();
+    let put = |port: u16| client.put(format!("http://127.0.0.1:{}/play", port)).body(melody).send();
+
+    // The speaker sounds one tone at a time, so the melody is refused.
+    let response = put(speaker_port).await.unwrap();
+    assert_eq!(response.status(), 400);
+    assert!(response.text().await.unwrap().contains("2 voices"));
+    assert!(fs::read(temp_file.path()).unwrap().is_empty());
+
+    // The synthesisers mix the voices, for as long as the longest one.
+    let response = put(file_port).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(fs::read(&out).unwrap().len(), 44 + 4000 * 2);
+
+    // Rendering is allowed whatever the backend.
+    let response = client
+        .post(format!("http://127.0.0.1:{}/render", speaker_port))
+        .body(melody)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["duration_cs"], 50);
+    let events = body["events"].as_array().unwrap();
+    let second = events.iter().find(|e| e["voice"] == 1).unwrap();
+    assert_eq!(second["start_cs"], 0);
+    assert_eq!(second["source"], "e8");
+
+    for server in servers {
+        server.abort();
+    }
+}
+
+#[tokio::test]
+async fn test_repeat_extension() {
+    let temp_file = NamedTempFile::new().expect("Failed to create temp file");
+    let device_path = temp_file.path().to_string_lossy().to_string();
+    let port = find_available_port().await;
+    let config = Config {
+        max_expanded_duration: Duration::from_secs(2),
+        ..Config::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
+        let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], port))], backend, config).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = reqwest::Client::new();
+    let put = |query: &str, melody: &'static str| {
+        client
+            .put(format!("http://127.0.0.1:{}/play{}", port, query))
+            .body(melody)
+            .send()
+    };
+
+    // Without ?repeat=1 the brackets reach the kernel, which skips them.
+    let response = put("", "[cd]2").await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(fs::read_to_string(temp_file.path()).unwrap(), "[cd]2");
+
+    // With it, the expanded commands are written instead.
+    let response = put("?repeat=1", "t200 [cd]2").await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(fs::read_to_string(temp_file.path()).unwrap(), "t200cdcd");
+
+    // 4.5 seconds once expanded, past --max-expanded-duration.
+    let response = put("?repeat=1", "[c]9").await.unwrap();
+    assert_eq!(response.status(), 400);
+    assert!(response.text().await.unwrap().contains("more than 2 seconds"));
+
+    let response = client
+        .post(format!("http://127.0.0.1:{}/render?repeat=1", port))
+        .body("[c]2")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["duration_cs"], 100);
+    assert_eq!(body["events"][2]["source"], "c");
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_volume_commands() {
+    let temp_file = NamedTempFile::new().expect("Failed to create temp file");
+    let device_path = temp_file.path().to_string_lossy().to_string();
+    let port = find_available_port().await;
+    let server_handle = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
+        let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], port))], backend, Config::default()).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = reqwest::Client::new();
+    let melody = "t200 v10 c (d";
+
+    // The kernel is sent the melody without its volume commands.
+    let response = client
+        .put(format!("http://127.0.0.1:{}/play", port))
+        .body(melody)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(fs::read_to_string(temp_file.path()).unwrap(), "t200 c d");
+
+    let response = client
+        .post(format!("http://127.0.0.1:{}/render", port))
+        .body(melody)
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    let volumes: Vec<u64> = body["events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|e| e["volume"].as_u64())
+        .collect();
+    assert_eq!(volumes, vec![10, 9]);
+    assert!(body["diagnostics"].as_array().unwrap().is_empty());
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_request_sound_parameters() {
+    let temp_file = NamedTempFile::new().expect("Failed to create temp file");
+    let device_path = temp_file.path().to_string_lossy().to_string();
+    let port = find_available_port().await;
+    let server_handle = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
+        let config = Config {
+            max_volume: 0.5,
+            ..Config::default()
+        };
+        let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], port))], backend, config).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = reqwest::Client::new();
+    let render = |query: &str| {
+        client
+            .post(format!("http://127.0.0.1:{}/render.wav?rate=8000&{}", port, query))
+            .body("t120 c4")
+            .send()
+    };
+
+    // The overrides change the synthesised sound.
+    let response = render("waveform=square&volume=0.5").await.unwrap();
+    assert_eq!(response.status(), 200);
+    let wav = response.bytes().await.unwrap();
+    let samples: Vec<i16> = wav[44..].chunks(2).map(|c| i16::from_le_bytes([c[0], c[1]])).collect();
+    assert_eq!(samples.len(), 4000);
+    assert_eq!(samples[0], i16::MAX / 2 + 1);
+    let response = render("waveform=square&volume=0.5&envelope=100,0,1,0").await.unwrap();
+    let wav = response.bytes().await.unwrap();
+    assert_eq!(i16::from_le_bytes([wav[44], wav[45]]), 0);
+    for waveform in ["pulse-12", "pulse-25", "noise", "periodic-noise", "fm"] {
+        let response = render(&format!("waveform={}", waveform)).await.unwrap();
+        assert_eq!(response.status(), 200, "{}", waveform);
+    }
+
+    // Bad values and volumes above --max-volume are refused.
+    for query in ["volume=0.6", "volume=-1", "waveform=kazoo", "envelope=1,2,3", "waveform=wavetable"] {
+        let response = render(query).await.unwrap();
+        assert_eq!(response.status(), 400, "{}", query);
+    }
+    let response = client
+        .put(format!("http://127.0.0.1:{}/play?volume=1", port))
+        .body("c")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    // A beeper has no sound to change; the melody plays as it is.
+    let response = client
+        .put(format!("http://127.0.0.1:{}/play?waveform=sine&volume=0.4", port))
+        .body("t200 c")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(fs::read_to_string(temp_file.path()).unwrap(), "t200 c");
+
+    server_handle.abort();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_tunings() {
+    use spkrd::file_backend::{FileBackend, FileConfig, FileFormat};
+    use spkrd::synth::Waveform;
+    use spkrd::tuning::Tuning;
+    use std::sync::Arc;
+
+    let port = find_available_port().await;
+    let cfg = FileConfig {
+        path: None,
+        format: FileFormat::Wav,
+        sample_rate: 8000,
+        waveform: Waveform::Sine,
+        volume: 0.25,
+        envelope: None,
+        wavetable: None,
+        speaker: Default::default(),
+        tuning: Tuning::load("pythagorean", 415.0).unwrap(),
+        timing: Default::default(),
+        pace: false,
+    };
+    let server = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::File(Arc::new(FileBackend::new(cfg)));
+        let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], port))], backend, Config::default()).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // /render shows the pitches the backend plays: A at 415 Hz, and the E
+    // above it a pure fifth higher.
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("http://127.0.0.1:{}/render", port))
+        .body("o2 a >e")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    let tones: Vec<(u64, u64)> = body["events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|e| e["type"] == "tone")
+        .map(|e| (e["freq_hz"].as_u64().unwrap(), e["freq_mhz"].as_u64().unwrap()))
+        .collect();
+    assert_eq!(tones, vec![(415, 415_000), (623, 622_500)]);
+
+    let response = reqwest::get(format!("http://127.0.0.1:{}/status", port)).await.unwrap();
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["backend"]["config"]["tuning"]["name"], "pythagorean");
+    assert_eq!(body["backend"]["config"]["tuning"]["reference_hz"], 415.0);
+    server.abort();
+
+    // `spkrd render` takes the same flags; a Scala file retunes the melody
+    // and a reference pitch out of range is refused.
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let input = dir.path().join("tune.mml");
+    let scale = dir.path().join("quarter.scl");
+    fs::write(&input, "t120 o2 c4 d4").unwrap();
+    let mut scl = "! quarter.scl\nQuarter tones\n24\n!\n".to_string();
+    for k in 1..=24 {
+        scl.push_str(&format!(" {}.0\n", k * 50));
+    }
+    fs::write(&scale, scl).unwrap();
+    let render_with = |flags: &[&str]| {
+        let out = dir.path().join("tune.wav");
+        let status = std::process::Command::new(env!("CARGO_BIN_EXE_spkrd"))
+            .args(["render", "--rate", "8000", "--waveform", "sine", "--out"])
+            .arg(&out)
+            .args(flags)
+            .arg(&input)
+            .status()
+            .unwrap();
+        status.success().then(|| fs::read(&out).unwrap())
+    };
+    let spkr = render_with(&[]).unwrap();
+    assert_eq!(render_with(&["--tuning", "spkr", "--reference-pitch", "440"]).unwrap(), spkr);
+    let quarter = render_with(&["--tuning", scale.to_str().unwrap()]).unwrap();
+    assert_eq!(quarter.len(), spkr.len());
+    assert_ne!(quarter, spkr);
+    assert_eq!(render_with(&["--reference-pitch", "100"]), None);
+    assert_eq!(render_with(&["--tuning", "meantone"]), None);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_extended_range() {
+    use spkrd::file_backend::{FileBackend, FileConfig, FileFormat};
+    use spkrd::synth::Waveform;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    let temp_file = NamedTempFile::new().expect("Failed to create temp file");
+    let device_path = temp_file.path().to_string_lossy().to_string();
+    let kernel_port = find_available_port().await;
+    let null_port = find_available_port().await;
+    let mut servers = vec![tokio::spawn(async move {
+        let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
+        let addrs = vec![SocketAddr::from(([127, 0, 0, 1], kernel_port))];
+        let _ = spkrd::server::run(addrs, backend, Config::default()).await;
+    })];
+    let cfg = FileConfig {
+        path: None,
+        format: FileFormat::Wav,
+        sample_rate: 8000,
+        waveform: Waveform::Square,
+        volume: 0.25,
+        envelope: None,
+        wavetable: None,
+        speaker: Default::default(),
+        tuning: Default::default(),
+        timing: Default::default(),
+        pace: false,
+    };
+    servers.push(tokio::spawn(async move {
+        let backend = spkrd::server::Backend::File(Arc::new(FileBackend::new(cfg)));
+        let config = Config {
+            mml_extended: true,
+            mml_strict: true,
+            ..Config::default()
+        };
+        let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], null_port))], backend, config).await;
+    }));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // The kernel's interpreter knows nothing of the extended range, so the
+    // /dev/speaker writer refuses it; it can still be rendered.
+    let client = reqwest::Client::new();
+    let response = client
+        .put(format!("http://127.0.0.1:{}/play?extended=1", kernel_port))
+        .body("o0 c")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    assert!(response.text().await.unwrap().contains("extended range"));
+    assert_eq!(fs::read_to_string(temp_file.path()).unwrap(), "");
+
+    let response = client
+        .post(format!("http://127.0.0.1:{}/render?extended=1", kernel_port))
+        .body("n69 o4 k12 a")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["events"][0]["freq_hz"], 440);
+    assert_eq!(body["events"][2]["freq_hz"], 880);
+
+    // --mml-extended reads every melody so, strict checks included.
+    for (melody, status) in [("o0 c o9 b", 200), ("o10 c", 400)] {
+        let response = client
+            .put(format!("http://127.0.0.1:{}/play", null_port))
+            .body(melody)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), status, "{}", melody);
+    }
+
+    let render = |flags: &[&str]| {
+        let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_spkrd"))
+            .args(["render", "--rate", "8000", "--out", "-"])
+            .args(flags)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(b"o2 c").unwrap();
+        child.wait_with_output().unwrap().stdout
+    };
+    assert_ne!(render(&["--extended"]), render(&[]));
+
+    for server in servers {
+        server.abort();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_precise_timing() {
+    use spkrd::file_backend::{FileBackend, FileConfig, FileFormat};
+    use spkrd::mml::Timing;
+    use spkrd::synth::Waveform;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    let port = find_available_port().await;
+    let cfg = FileConfig {
+        path: None,
+        format: FileFormat::Wav,
+        sample_rate: 8000,
+        waveform: Waveform::Square,
+        volume: 0.25,
+        envelope: None,
+        wavetable: None,
+        speaker: Default::default(),
+        tuning: Default::default(),
+        timing: Timing::Precise,
+        pace: false,
+    };
+    let server = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::File(Arc::new(FileBackend::new(cfg)));
+        let _ = spkrd::server::run(vec![SocketAddr::from(([127, 0, 0, 1], port))], backend, Config::default()).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // /render times the melody as the backend plays it: 64 sixty-fourths
+    // at T255 take a whole note, 240 s / 255, where spkr.c's centiseconds
+    // make them 64 cs.
+    let sixty_fourths = format!("t255 l64 {}", "c".repeat(64));
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("http://127.0.0.1:{}/render", port))
+        .body(sixty_fourths.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["duration_us"], 941_176);
+    assert_eq!(body["duration_cs"], 94);
+    let last = body["events"].as_array().unwrap().last().unwrap().clone();
+    assert_eq!(last["start_us"].as_u64().unwrap() + last["micros"].as_u64().unwrap(), 941_176);
+
+    let response = reqwest::get(format!("http://127.0.0.1:{}/status", port)).await.unwrap();
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["backend"]["config"]["timing"], "precise");
+    server.abort();
+
+    // `spkrd render --precise-timing` synthesises the same lengths.
+    let render = |flags: &[&str]| {
+        let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_spkrd"))
+            .args(["render", "--rate", "8000", "--out", "-"])
+            .args(flags)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(sixty_fourths.as_bytes()).unwrap();
+        child.wait_with_output().unwrap().stdout
+    };
+    assert_eq!(render(&[]).len(), 44 + 2 * 5120);
+    assert_eq!(render(&["--precise-timing"]).len(), 44 + 2 * 7529);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_transpose_and_tempo() {
+    let temp_file = NamedTempFile::new().expect("Failed to create temp file");
+    let device_path = temp_file.path().to_string_lossy().to_string();
+    let port = find_available_port().await;
+    let server = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
+        let addrs = vec![SocketAddr::from(([127, 0, 0, 1], port))];
+        let _ = spkrd::server::run(addrs, backend, Config::default()).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // The kernel is handed the melody rewritten: up a minor third, with
+    // T120 scaled to T180. The `+` arrives as a space and still counts.
+    let client = reqwest::Client::new();
+    let response = client
+        .put(format!("http://127.0.0.1:{}/play?transpose=+3&tempo=1.5", port))
+        .body("o2 c e")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(fs::read_to_string(temp_file.path()).unwrap(), "t180o2d#g");
+
+    // /render shows the same, from the parsed melody.
+    let response = client
+        .post(format!("http://127.0.0.1:{}/render?transpose=-12&tempo=0.5", port))
+        .body("o2 a")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["events"][0]["freq_hz"], 220);
+    assert_eq!(body["duration_cs"], 100);
+
+    // T120 four times as fast is more than the kernel can play.
+    for query in ["transpose=up", "transpose=49", "tempo=fast", "tempo=8", "tempo=-1", "tempo=4"] {
+        let response = client
+            .put(format!("http://127.0.0.1:{}/play?{}", port, query))
+            .body("c")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400, "{}", query);
+    }
+    assert_eq!(fs::read_to_string(temp_file.path()).unwrap(), "t180o2d#g");
+
+    server.abort();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_tune_library() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("success.mml"), "t120 l4 c d e").unwrap();
+    fs::write(dir.path().join("build-failed.mml"), "t240 o1 c").unwrap();
+    let tunes = spkrd::tunes::Tunes::load(dir.path().to_str().unwrap(), 1000, Default::default(), Default::default()).unwrap();
+
+    let temp_file = NamedTempFile::new().expect("Failed to create temp file");
+    let device_path = temp_file.path().to_string_lossy().to_string();
+    let port = find_available_port().await;
+    let server = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
+        let addrs = vec![SocketAddr::from(([127, 0, 0, 1], port))];
+        let config = Config {
+            tunes: Some(std::sync::Arc::new(tunes)),
+            ..Config::default()
+        };
+        let _ = spkrd::server::run(addrs, backend, config).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = reqwest::Client::new();
+    let list = || async {
+        let response = client
+            .get(format!("http://127.0.0.1:{}/tunes", port))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        serde_json::from_str::<serde_json::Value>(&response.text().await.unwrap()).unwrap()
+    };
+    let body = list().await;
+    let tunes = body["tunes"].as_array().unwrap();
+    assert_eq!(tunes.len(), 2);
+    assert_eq!(tunes[0]["name"], "build-failed");
+    assert_eq!(tunes[0]["duration_ms"], 250);
+    assert_eq!(tunes[1]["name"], "success");
+    assert_eq!(tunes[1]["bytes"], 13);
+    assert_eq!(tunes[1]["duration_ms"], 1500);
+
+    // A tune plays as its text would, query parameters included.
+    let response = client
+        .put(format!("http://127.0.0.1:{}/play/success", port))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(fs::read_to_string(temp_file.path()).unwrap(), "t120 l4 c d e");
+    let response = client
+        .put(format!("http://127.0.0.1:{}/play/build-failed?transpose=12", port))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    // The device file is not truncated, so the shorter write overlays
+    // the first.
+    assert!(fs::read_to_string(temp_file.path()).unwrap().starts_with("t240o2c"));
+
+    let response = client
+        .put(format!("http://127.0.0.1:{}/play/ding", port))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+    assert_eq!(response.text().await.unwrap(), "No tune named ding");
+
+    // A tune added to the directory is picked up by the next poll.
+    fs::write(dir.path().join("ding.mml"), "o4 g").unwrap();
+    let mut found = false;
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if list().await["tunes"].as_array().unwrap().len() == 3 {
+            found = true;
+            break;
+        }
+    }
+    assert!(found, "ding.mml was not loaded");
+    let response = client
+        .put(format!("http://127.0.0.1:{}/play/ding", port))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(fs::read_to_string(temp_file.path()).unwrap().starts_with("o4 g"));
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn test_tunes_without_library() {
+    let temp_file = NamedTempFile::new().expect("Failed to create temp file");
+    let device_path = temp_file.path().to_string_lossy().to_string();
+    let port = find_available_port().await;
+    let server = tokio::spawn(async move {
+        let backend = spkrd::server::Backend::FreebsdSpeaker { device_path };
+        let addrs = vec![SocketAddr::from(([127, 0, 0, 1], port))];
+        let _ = spkrd::server::run(addrs, backend, Config::default()).await;
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get(format!("http://127.0.0.1:{}/tunes", port))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+    let response = client
+        .put(format!("http://127.0.0.1:{}/play/success", port))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+
+    server.abort();
+}
+
 // Helper function to find an available port
 async fn find_available_port() -> u16 {
     use tokio::net::TcpListener;